@@ -1 +1,218 @@
+use std::path::Path;
 
+use log::{error, info};
+
+use crate::{
+    config::{BackendType, Storage, load_config},
+    storage::{
+        backup::{create_backup, restore_backup},
+        create_backend,
+        lock::UserLock,
+        migration::migrate,
+        scrub::{ScrubReport, lock_dir, report_path, scrub},
+    },
+    user::UserHandle,
+};
+
+/// Run the command given on the command line, returning the exit code,
+/// or `None` if the arguments do not name a command and the server should start.
+pub fn run(args: &[String]) -> Option<i32> {
+    match args.get(1).map(String::as_str) {
+        Some("migrate") => Some(migrate_command(&args[2..])),
+        Some("scrub") => Some(scrub_command(&args[2..])),
+        Some("backup") => Some(backup_command(&args[2..])),
+        Some("restore") => Some(restore_command(&args[2..])),
+        Some("lockouts") => Some(lockouts_command(&args[2..])),
+        _ => None,
+    }
+}
+
+/// Get the value following a `--name` option.
+fn get_option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+/// `migrate --from <backend> --to <backend> [--config <file>]`
+fn migrate_command(args: &[String]) -> i32 {
+    let config = load_config(get_option(args, "--config").map(Path::new));
+    let from_name = get_option(args, "--from").unwrap_or_default();
+    let to_name = get_option(args, "--to").unwrap_or_default();
+    let (from, to) = match (BackendType::parse(from_name), BackendType::parse(to_name)) {
+        (Some(from), Some(to)) if from != to => (from, to),
+        _ => {
+            error!(
+                "Usage: synxit-server migrate --from <file|sqlite|s3> --to <file|sqlite|s3> [--config <file>]"
+            );
+            return 2;
+        }
+    };
+
+    info!("Migrating from {} to {} backend", from_name, to_name);
+    let source = create_backend(&Storage {
+        backend: from,
+        ..config.storage.clone()
+    });
+    let target = create_backend(&Storage {
+        backend: to,
+        ..config.storage.clone()
+    });
+    let state_path = format!(
+        "{}/migration-{}-{}.json",
+        config.storage.data_dir, from_name, to_name
+    );
+    let report = migrate(source.as_ref(), target.as_ref(), &state_path);
+    if report.failures.is_empty() {
+        info!("{}", report);
+        0
+    } else {
+        error!("{}", report);
+        1
+    }
+}
+
+/// `scrub [--quarantine] [--report] [--config <file>]`
+fn scrub_command(args: &[String]) -> i32 {
+    let config = load_config(get_option(args, "--config").map(Path::new));
+    let path = report_path(&config.storage);
+    if args.iter().any(|arg| arg == "--report") {
+        return match ScrubReport::load(&path) {
+            Some(report) => {
+                info!("{}", report);
+                0
+            }
+            None => {
+                error!("No scrub report found at {}", path);
+                1
+            }
+        };
+    }
+
+    info!("Scrubbing stored data");
+    let backend = create_backend(&config.storage);
+    let quarantine = args.iter().any(|arg| arg == "--quarantine");
+    let report = scrub(backend.as_ref(), &lock_dir(&config.storage), quarantine);
+    if !report.save(&path) {
+        error!("Could not save scrub report to {}", path);
+    }
+    if report.findings.is_empty() {
+        info!("{}", report);
+        0
+    } else {
+        error!("{}", report);
+        1
+    }
+}
+
+/// `backup --output <file> [--config <file>]`
+fn backup_command(args: &[String]) -> i32 {
+    let config = load_config(get_option(args, "--config").map(Path::new));
+    let Some(output) = get_option(args, "--output") else {
+        error!("Usage: synxit-server backup --output <file> [--config <file>]");
+        return 2;
+    };
+
+    info!("Backing up to {}", output);
+    let backend = create_backend(&config.storage);
+    match create_backup(
+        backend.as_ref(),
+        &lock_dir(&config.storage),
+        &config,
+        output,
+    ) {
+        Ok(summary) => {
+            info!("Backup complete\n{}", summary);
+            0
+        }
+        Err(err) => {
+            error!("Backup failed: {}", err);
+            1
+        }
+    }
+}
+
+/// `restore --input <file> [--config <file>]`
+fn restore_command(args: &[String]) -> i32 {
+    let config = load_config(get_option(args, "--config").map(Path::new));
+    let Some(input) = get_option(args, "--input") else {
+        error!("Usage: synxit-server restore --input <file> [--config <file>]");
+        return 2;
+    };
+
+    info!("Restoring from {}", input);
+    match restore_backup(input, &config.storage) {
+        Ok(summary) => {
+            info!("Restore complete\n{}", summary);
+            0
+        }
+        Err(err) => {
+            error!("Restore failed: {}", err);
+            1
+        }
+    }
+}
+
+/// `lockouts [--unlock <username>] [--config <file>]`
+/// A running server notices the unlock on the next request for the user.
+fn lockouts_command(args: &[String]) -> i32 {
+    let config = load_config(get_option(args, "--config").map(Path::new));
+    let backend = create_backend(&config.storage);
+    if let Some(username) = get_option(args, "--unlock") {
+        let unlocked = UserHandle::from_string("@".to_string() + username + ":localhost").and_then(
+            |userhandle| {
+                let _lock = UserLock::acquire_in(&lock_dir(&config.storage), username)?;
+                let mut user = backend.load_user(&userhandle)?;
+                Ok(user.unlock() && backend.save_user(&user))
+            },
+        );
+        return match unlocked {
+            Ok(true) => {
+                info!("Unlocked user {}", username);
+                0
+            }
+            Ok(false) => {
+                error!("User {} is not locked out", username);
+                1
+            }
+            Err(err) => {
+                error!("Could not unlock user {}: {}", username, err);
+                1
+            }
+        };
+    }
+
+    let users = match backend.list_users() {
+        Ok(users) => users,
+        Err(err) => {
+            error!("Could not list users: {}", err);
+            return 1;
+        }
+    };
+    let mut report = String::from("Failed logins since the last successful one:");
+    for username in users {
+        let Ok(user) = UserHandle::from_string("@".to_string() + &username + ":localhost")
+            .and_then(|userhandle| backend.load_user(&userhandle))
+        else {
+            continue;
+        };
+        let lockout = &user.auth.lockout;
+        if lockout.failures_since_login > 0 {
+            report += &format!(
+                "\n  {}: {} failed, {} lockouts, last at {}{}",
+                username,
+                lockout.failures_since_login,
+                lockout.lockouts_since_login,
+                lockout.throttle.last_failure,
+                if lockout.throttle.is_locked() {
+                    format!(", locked until {}", lockout.throttle.locked_until)
+                } else {
+                    String::new()
+                }
+            );
+        }
+    }
+    info!("{}", report);
+    0
+}
@@ -1,6 +1,6 @@
-use log::{error, info, warn, LevelFilter};
+use log::{LevelFilter, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::{path::Path, process::exit, sync::OnceLock};
+use std::{collections::BTreeMap, path::Path, process::exit, sync::OnceLock};
 use toml::Table;
 
 use crate::{
@@ -19,6 +19,7 @@ pub struct Config {
     pub auth: Auth,
     pub tiers: Vec<Tier>,
     pub federation: Federation,
+    pub rate_limit: RateLimit,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -47,19 +48,63 @@ pub struct Network {
     pub fqdns: Vec<Server>,
 }
 
+/// Number of blob versions kept for tiers that do not configure it.
+pub const DEFAULT_TIER_VERSIONS: u64 = 3;
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Tier {
     pub id: String,
     pub name: String,
     pub description: String,
     pub quota: u64,
+    /// Number of previous versions kept for every blob.
+    pub versions: u64,
+    /// Requests of each user of the tier, replacing `rate_limit.user`.
+    pub rate_limit: Option<Limit>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Storage {
+    pub backend: BackendType,
+    pub cache: bool,
+    pub deduplicate: bool,
+    /// Seconds deleted blobs stay in the trash, 0 deletes them immediately.
+    pub trash_retention: u64,
+    /// Seconds between integrity scrubs of all blobs, 0 disables the background scrubber.
+    pub scrub_interval: u64,
+    /// Move corrupt blobs found by the background scrubber into quarantine.
+    pub scrub_quarantine: bool,
     pub data_dir: String,
     pub temp_dir: String,
     pub log_dir: String,
+    pub sqlite: Sqlite,
+    pub s3: S3,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Sqlite {
+    pub path: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct S3 {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub prefix: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum BackendType {
+    #[default]
+    #[serde(rename = "file")]
+    File,
+    #[serde(rename = "sqlite")]
+    Sqlite,
+    #[serde(rename = "s3")]
+    S3,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -67,6 +112,47 @@ pub struct Auth {
     pub session_timeout: u64,
     pub auth_session_timeout: u64,
     pub registration_enabled: bool,
+    /// Failed logins of a user before it is locked out, 0 never locks users out.
+    pub max_failed_attempts: u32,
+    /// Failed attempts within one auth session before it is discarded, 0 for no limit.
+    pub max_session_attempts: u32,
+    /// Failed logins from one IP address before it is locked out, 0 never locks IPs out.
+    pub max_ip_attempts: u32,
+    /// Seconds to wait after the first failed attempt, doubling with every further one.
+    pub backoff_base: u64,
+    /// Longest wait between attempts in seconds.
+    pub backoff_max: u64,
+    /// Seconds a lockout lasts. Failures older than this are forgotten.
+    pub lockout_duration: u64,
+}
+
+/// A token bucket allowing bursts of `requests`, refilled completely over `per` seconds.
+/// No requests are limited if `requests` is 0.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub requests: u64,
+    pub per: u64,
+}
+
+impl Limit {
+    pub const fn new(requests: u64, per: u64) -> Self {
+        Limit { requests, per }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RateLimit {
+    pub enabled: bool,
+    /// Requests per client IP to all routes.
+    pub ip: Limit,
+    /// Requests per signed in user to all routes, unless the user's tier sets its own limit.
+    pub user: Limit,
+    /// Federation requests per client IP, which is the remote server for requests from
+    /// other servers.
+    pub federation: Limit,
+    /// Requests per client IP to a route such as `registration`, or to one action of a
+    /// route such as `auth.prepare`.
+    pub routes: BTreeMap<String, Limit>,
 }
 
 impl Default for Auth {
@@ -75,6 +161,28 @@ impl Default for Auth {
             session_timeout: 60 * 60 * 24 * 7,
             auth_session_timeout: 60,
             registration_enabled: true,
+            max_failed_attempts: 10,
+            max_session_attempts: 5,
+            max_ip_attempts: 50,
+            backoff_base: 1,
+            backoff_max: 60,
+            lockout_duration: 60 * 15,
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            enabled: true,
+            ip: Limit::new(600, 60),
+            user: Limit::new(600, 60),
+            federation: Limit::new(300, 60),
+            routes: BTreeMap::from([
+                ("registration".to_string(), Limit::new(5, 60 * 60)),
+                ("auth.prepare".to_string(), Limit::new(30, 60)),
+                ("account_import".to_string(), Limit::new(2, 60 * 60)),
+            ]),
         }
     }
 }
@@ -92,9 +200,38 @@ impl Default for Network {
 impl Default for Storage {
     fn default() -> Self {
         Storage {
+            backend: BackendType::File,
+            cache: true,
+            deduplicate: false,
+            trash_retention: 60 * 60 * 24 * 30,
+            scrub_interval: 60 * 60 * 24 * 7,
+            scrub_quarantine: false,
             data_dir: "/var/lib/synxit".to_string(),
             temp_dir: "/tmp/synxit".to_string(),
             log_dir: "/var/log/synxit".to_string(),
+            sqlite: Sqlite::default(),
+            s3: S3::default(),
+        }
+    }
+}
+
+impl Default for Sqlite {
+    fn default() -> Self {
+        Sqlite {
+            path: "/var/lib/synxit/synxit.db".to_string(),
+        }
+    }
+}
+
+impl Default for S3 {
+    fn default() -> Self {
+        S3 {
+            endpoint: "http://127.0.0.1:9000".to_string(),
+            bucket: "synxit".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            prefix: "users/".to_string(),
         }
     }
 }
@@ -162,6 +299,18 @@ impl Storage {
     }
 }
 
+impl BackendType {
+    /// Parse a backend type from its configuration name.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "file" => Some(BackendType::File),
+            "sqlite" => Some(BackendType::Sqlite),
+            "s3" => Some(BackendType::S3),
+            _ => None,
+        }
+    }
+}
+
 impl Config {
     /// Retrieve a tier by its ID.
     pub fn get_tier(&self, id: &str) -> Option<&Tier> {
@@ -180,17 +329,15 @@ pub fn load_config(config_file: Option<&Path>) -> Config {
         parse_auth_config(&mut config, &config_file);
         parse_tiers_config(&mut config, &config_file);
         parse_federation_config(&mut config, &config_file);
+        parse_rate_limit_config(&mut config, &config_file);
     }
 
     if logger::init_logger(&config.storage.log_dir, LevelFilter::Debug).is_err() {
         exit(1);
     }
 
-    if config_file.is_some() {
-        info!(
-            "Loading configuration from: {}",
-            config_file.unwrap().display()
-        );
+    if let Some(config_file) = config_file {
+        info!("Loading configuration from: {}", config_file.display());
     } else {
         warn!("No configuration file provided, using default settings");
     }
@@ -230,6 +377,27 @@ fn parse_network_config(config: &mut Config, table: &Table) {
 /// Parse the storage configuration.
 fn parse_storage_config(config: &mut Config, table: &Table) {
     if let Some(storage) = table.get("storage").and_then(|v| v.as_table()) {
+        if let Some(backend) = storage.get("backend").and_then(|v| v.as_str()) {
+            match BackendType::parse(backend) {
+                Some(backend) => config.storage.backend = backend,
+                None => warn!("Unknown storage backend {}, using default", backend),
+            }
+        }
+        if let Some(cache) = storage.get("cache").and_then(|v| v.as_bool()) {
+            config.storage.cache = cache;
+        }
+        if let Some(deduplicate) = storage.get("deduplicate").and_then(|v| v.as_bool()) {
+            config.storage.deduplicate = deduplicate;
+        }
+        if let Some(trash_retention) = storage.get("trash_retention").and_then(|v| v.as_integer()) {
+            config.storage.trash_retention = trash_retention.max(0) as u64;
+        }
+        if let Some(scrub_interval) = storage.get("scrub_interval").and_then(|v| v.as_integer()) {
+            config.storage.scrub_interval = scrub_interval.max(0) as u64;
+        }
+        if let Some(scrub_quarantine) = storage.get("scrub_quarantine").and_then(|v| v.as_bool()) {
+            config.storage.scrub_quarantine = scrub_quarantine;
+        }
         if let Some(data_dir) = storage.get("data_dir").and_then(|v| v.as_str()) {
             config.storage.data_dir = data_dir.to_string();
         }
@@ -239,6 +407,26 @@ fn parse_storage_config(config: &mut Config, table: &Table) {
         if let Some(log_dir) = storage.get("log_dir").and_then(|v| v.as_str()) {
             config.storage.log_dir = log_dir.to_string();
         }
+        if let Some(sqlite) = storage.get("sqlite").and_then(|v| v.as_table())
+            && let Some(path) = sqlite.get("path").and_then(|v| v.as_str())
+        {
+            config.storage.sqlite.path = path.to_string();
+        }
+        if let Some(s3) = storage.get("s3").and_then(|v| v.as_table()) {
+            let fields = [
+                ("endpoint", &mut config.storage.s3.endpoint),
+                ("bucket", &mut config.storage.s3.bucket),
+                ("region", &mut config.storage.s3.region),
+                ("access_key", &mut config.storage.s3.access_key),
+                ("secret_key", &mut config.storage.s3.secret_key),
+                ("prefix", &mut config.storage.s3.prefix),
+            ];
+            for (key, field) in fields {
+                if let Some(value) = s3.get(key).and_then(|v| v.as_str()) {
+                    *field = value.to_string();
+                }
+            }
+        }
     }
 }
 
@@ -259,6 +447,27 @@ fn parse_auth_config(config: &mut Config, table: &Table) {
         {
             config.auth.registration_enabled = registration_enabled;
         }
+        for (name, value) in [
+            ("max_failed_attempts", &mut config.auth.max_failed_attempts),
+            (
+                "max_session_attempts",
+                &mut config.auth.max_session_attempts,
+            ),
+            ("max_ip_attempts", &mut config.auth.max_ip_attempts),
+        ] {
+            if let Some(attempts) = auth.get(name).and_then(|v| v.as_integer()) {
+                *value = attempts.clamp(0, u32::MAX as i64) as u32;
+            }
+        }
+        for (name, value) in [
+            ("backoff_base", &mut config.auth.backoff_base),
+            ("backoff_max", &mut config.auth.backoff_max),
+            ("lockout_duration", &mut config.auth.lockout_duration),
+        ] {
+            if let Some(seconds) = auth.get(name).and_then(|v| v.as_integer()) {
+                *value = seconds.max(0) as u64;
+            }
+        }
     }
 }
 
@@ -267,20 +476,25 @@ fn parse_tiers_config(config: &mut Config, table: &Table) {
     if let Some(tiers) = table.get("tiers").and_then(|v| v.as_array()) {
         config.tiers.clear();
         for tier in tiers {
-            if let Some(tier_table) = tier.as_table() {
-                if let (Some(id), Some(name), Some(description), Some(quota)) = (
+            if let Some(tier_table) = tier.as_table()
+                && let (Some(id), Some(name), Some(description), Some(quota)) = (
                     tier_table.get("id").and_then(|v| v.as_str()),
                     tier_table.get("name").and_then(|v| v.as_str()),
                     tier_table.get("description").and_then(|v| v.as_str()),
                     tier_table.get("quota").and_then(|v| v.as_integer()),
-                ) {
-                    config.tiers.push(Tier {
-                        id: id.to_string(),
-                        name: name.to_string(),
-                        description: description.to_string(),
-                        quota: quota as u64,
-                    });
-                }
+                )
+            {
+                config.tiers.push(Tier {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    description: description.to_string(),
+                    quota: quota as u64,
+                    versions: tier_table
+                        .get("versions")
+                        .and_then(|v| v.as_integer())
+                        .map_or(DEFAULT_TIER_VERSIONS, |versions| versions.max(0) as u64),
+                    rate_limit: tier_table.get("rate_limit").and_then(parse_limit),
+                });
             }
         }
     }
@@ -322,6 +536,46 @@ fn parse_federation_config(config: &mut Config, table: &Table) {
     }
 }
 
+/// Parse a limit given as `{ requests = 60, per = 60 }`.
+fn parse_limit(value: &toml::Value) -> Option<Limit> {
+    let limit = value.as_table()?;
+    match (
+        limit.get("requests").and_then(|v| v.as_integer()),
+        limit.get("per").and_then(|v| v.as_integer()),
+    ) {
+        (Some(requests), Some(per)) => Some(Limit::new(requests.max(0) as u64, per.max(1) as u64)),
+        _ => {
+            warn!("Ignoring rate limit without requests and per: {}", value);
+            None
+        }
+    }
+}
+
+/// Parse the rate limit configuration.
+fn parse_rate_limit_config(config: &mut Config, table: &Table) {
+    if let Some(rate_limit) = table.get("rate_limit").and_then(|v| v.as_table()) {
+        if let Some(enabled) = rate_limit.get("enabled").and_then(|v| v.as_bool()) {
+            config.rate_limit.enabled = enabled;
+        }
+        for (name, limit) in [
+            ("ip", &mut config.rate_limit.ip),
+            ("user", &mut config.rate_limit.user),
+            ("federation", &mut config.rate_limit.federation),
+        ] {
+            if let Some(parsed) = rate_limit.get(name).and_then(parse_limit) {
+                *limit = parsed;
+            }
+        }
+        if let Some(routes) = rate_limit.get("routes").and_then(|v| v.as_table()) {
+            for (route, limit) in routes {
+                if let Some(limit) = parse_limit(limit) {
+                    config.rate_limit.routes.insert(route.to_string(), limit);
+                }
+            }
+        }
+    }
+}
+
 /// Get the current configuration, returning defaults if not set.
 pub fn get_config() -> Config {
     let default_config = Config::default();
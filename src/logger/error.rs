@@ -8,12 +8,31 @@ pub const ERROR_REMOTE_ERROR: &str = "REMOTE_ERROR";
 pub const ERROR_INVALID_JSON: &str = "INVALID_JSON";
 pub const ERROR_QUOTA_EXCEEDED: &str = "QUOTA_EXCEEDED";
 pub const ERROR_BLOB_HASH_NOT_MATCH: &str = "BLOB_HASH_NOT_MATCH";
+pub const ERROR_BLOB_HASH_REQUIRED: &str = "BLOB_HASH_REQUIRED";
+pub const ERROR_BLOB_WRITE_FAILED: &str = "BLOB_WRITE_FAILED";
+pub const ERROR_BLOB_ALREADY_EXISTS: &str = "BLOB_ALREADY_EXISTS";
+pub const ERROR_BLOB_NOT_IN_TRASH: &str = "BLOB_NOT_IN_TRASH";
+pub const ERROR_VERSION_NOT_FOUND: &str = "VERSION_NOT_FOUND";
+pub const ERROR_UPLOAD_NOT_FOUND: &str = "UPLOAD_NOT_FOUND";
+pub const ERROR_UPLOAD_INCOMPLETE: &str = "UPLOAD_INCOMPLETE";
+pub const ERROR_TOO_MANY_UPLOADS: &str = "TOO_MANY_UPLOADS";
+pub const ERROR_INVALID_CHUNK: &str = "INVALID_CHUNK";
+pub const ERROR_TAGS_TOO_LARGE: &str = "TAGS_TOO_LARGE";
+pub const ERROR_REVISION_MISMATCH: &str = "REVISION_MISMATCH";
 pub const ERROR_SHARE_NOT_FOUND: &str = "SHARE_NOT_FOUND";
 pub const ERROR_INVALID_CREDENTIALS: &str = "INVALID_CREDENTIALS";
 pub const ERROR_UNAUTHORIZED: &str = "Unauthorized";
 pub const ERROR_REGISTRATION_DISABLED: &str = "REGISTRATION_DISABLED";
+pub const ERROR_USER_ALREADY_EXISTS: &str = "USER_ALREADY_EXISTS";
+pub const ERROR_INVALID_ARCHIVE: &str = "INVALID_ARCHIVE";
+pub const ERROR_TOO_MANY_ATTEMPTS: &str = "TOO_MANY_ATTEMPTS";
+pub const ERROR_RATE_LIMITED: &str = "RATE_LIMITED";
+pub const ERROR_INVALID_MFA_CODE: &str = "Invalid MFA code";
+pub const ERROR_INVALID_WEBAUTHN_ASSERTION: &str = "Invalid WebAuthn assertion";
+pub const ERROR_INVALID_RECOVERY_CODE: &str = "Invalid MFA recovery code";
 
 /// Custom error type for logger-related errors.
+#[derive(Debug)]
 pub struct Error {
     message: String,
 }
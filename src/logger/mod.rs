@@ -95,10 +95,9 @@ impl Logger {
             .create(true)
             .append(true)
             .open(file_path)
+            && let Err(e) = writeln!(file, "{}", plain_message)
         {
-            if let Err(e) = writeln!(file, "{}", plain_message) {
-                eprintln!("Failed to write to log file: {}", e);
-            }
+            eprintln!("Failed to write to log file: {}", e);
         }
     }
 }
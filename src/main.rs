@@ -10,12 +10,17 @@ mod web;
 #[cfg(test)]
 mod tests;
 
-use std::path::Path;
+use std::{path::Path, process::exit};
 
 use config::load_config;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use logger::display_copyright;
-use user::User;
+use storage::{
+    backend,
+    file::start_blob_relayout,
+    scrub::{lock_dir, start_scrubber},
+};
+use user::{User, schema::upgrade_all, trash::start_trash_sweeper};
 use web::start_server;
 
 #[actix_web::main]
@@ -23,6 +28,10 @@ async fn main() {
     display_copyright();
 
     let args = std::env::args().collect::<Vec<String>>();
+    if let Some(code) = cli::run(&args) {
+        exit(code);
+    }
+
     let config = if args.len() >= 2 {
         load_config(Some(Path::new(&args[1])))
     } else {
@@ -30,11 +39,18 @@ async fn main() {
     };
 
     info!("Starting synxit server...");
+    info!("Upgrading user data...");
+    let report = upgrade_all(backend(), &lock_dir(&config.storage));
+    if report.failures.is_empty() {
+        info!("{}", report);
+    } else {
+        error!("{}", report);
+    }
     info!("Loading users...");
     for mut user in User::all() {
         user.delete_all_auth_sessions();
         if !config.tiers.iter().any(|tier| tier.id == user.tier) {
-            warn!("User {} has an invalid tier", user.userhandle.to_string());
+            warn!("User {} has an invalid tier", user.userhandle);
         }
         if false {
             user.delete_all_sessions();
@@ -44,6 +60,9 @@ async fn main() {
     debug!("{:#?}", &config);
 
     info!("Users loaded");
+    start_trash_sweeper();
+    start_scrubber();
+    start_blob_relayout();
     info!(
         "Endpoint: http://{}{}/",
         config.network.host,
@@ -1,8 +1,23 @@
+use num_bigint::BigUint;
+use sha2::{Digest, Sha256};
 use totp_rs::{Algorithm, Secret, TOTP};
 
 use crate::utils::u128_to_32_char_hex_string;
 
-/// Verify a challenge-response pair using SHA-256 for password login
+/// Modulus of the 2048-bit SRP group from RFC 5054, used with generator 2.
+const SRP_N: &str = "\
+    AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050A37329CBB4A099ED8193E075\
+    7767A13DD52312AB4B03310DCD7F48A9DA04FD50E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE8\
+    2918A9962F0B93B855F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773BCA97B43A\
+    23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748544523B524B0D57D5EA77A2775D2ECFA\
+    032CFBDBF52FB3786160279004E57AE6AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8\
+    E9DBFBB694B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73";
+const SRP_G: u32 = 2;
+/// Length of N in bytes, the length numbers are padded to before hashing.
+const SRP_LENGTH: usize = 256;
+
+/// Verify a challenge-response pair using SHA-256 for password login. Only accounts that
+/// were not moved to SRP yet still log in this way.
 pub fn verify_challenge_response(challenge: u128, response: &str, password_hash: String) -> bool {
     response
         == sha256::digest(format!(
@@ -22,3 +37,134 @@ pub fn verify_totp_code(secret: String, code: &str) -> bool {
         Err(_) => false,
     }
 }
+
+fn srp_group() -> (BigUint, BigUint) {
+    (
+        BigUint::parse_bytes(SRP_N.as_bytes(), 16).unwrap_or_default(),
+        BigUint::from(SRP_G),
+    )
+}
+
+fn srp_pad(number: &BigUint) -> Vec<u8> {
+    let bytes = number.to_bytes_be();
+    let mut padded = vec![0; SRP_LENGTH.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+fn srp_hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+fn srp_number(parts: &[&[u8]]) -> BigUint {
+    BigUint::from_bytes_be(&srp_hash(parts))
+}
+
+fn srp_parse(hex: &str) -> Option<BigUint> {
+    BigUint::parse_bytes(hex.as_bytes(), 16)
+}
+
+/// k = H(N | PAD(g))
+fn srp_multiplier(n: &BigUint, g: &BigUint) -> BigUint {
+    srp_number(&[&srp_pad(n), &srp_pad(g)])
+}
+
+/// x = H(salt | H(username | ":" | password)), where the password is the hash the client
+/// derives from the user's password.
+fn srp_private_key(username: &str, salt: &str, password: &str) -> BigUint {
+    let identity = srp_hash(&[username.as_bytes(), b":", password.as_bytes()]);
+    srp_number(&[salt.as_bytes(), &identity])
+}
+
+/// Computes the SRP-6a verifier v = g^x stored in place of the password hash. It does not
+/// allow logging in without the password.
+pub fn srp_verifier(username: &str, salt: &str, password: &str) -> String {
+    let (n, g) = srp_group();
+    g.modpow(&srp_private_key(username, salt, password), &n)
+        .to_str_radix(16)
+}
+
+/// Returns a new secret server ephemeral b.
+pub fn srp_server_secret() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Computes the public server ephemeral B = k * v + g^b.
+pub fn srp_server_public(verifier: &str, secret: &str) -> Option<String> {
+    let (n, g) = srp_group();
+    let public =
+        (srp_multiplier(&n, &g) * srp_parse(verifier)? + g.modpow(&srp_parse(secret)?, &n)) % &n;
+    Some(public.to_str_radix(16))
+}
+
+/// Checks the client's public ephemeral A and its proof M1 = H(PAD(A) | PAD(B) | PAD(S)).
+/// Returns the server's proof M2 = H(PAD(A) | M1 | PAD(S)) if the client knew the password.
+pub fn srp_verify_client(
+    verifier: &str,
+    secret: &str,
+    client_public: &str,
+    client_proof: &str,
+) -> Option<String> {
+    let (n, _) = srp_group();
+    let zero = BigUint::from(0u32);
+    let a = srp_parse(client_public)?;
+    if &a % &n == zero {
+        return None;
+    }
+    let v = srp_parse(verifier)?;
+    let b = srp_parse(secret)?;
+    let server_public = srp_parse(&srp_server_public(verifier, secret)?)?;
+    let u = srp_number(&[&srp_pad(&a), &srp_pad(&server_public)]);
+    if u == zero {
+        return None;
+    }
+    let shared = (&a * v.modpow(&u, &n)).modpow(&b, &n);
+    let proof = srp_hash(&[&srp_pad(&a), &srp_pad(&server_public), &srp_pad(&shared)]);
+    let client_proof = hex::decode(client_proof).ok()?;
+    // Compared in constant time
+    let difference = proof
+        .iter()
+        .zip(&client_proof)
+        .fold(0, |difference, (x, y)| difference | (x ^ y));
+    if difference != 0 || proof.len() != client_proof.len() {
+        return None;
+    }
+    Some(hex::encode(srp_hash(&[
+        &srp_pad(&a),
+        &proof,
+        &srp_pad(&shared),
+    ])))
+}
+
+/// Client side of the SRP-6a exchange, returning A, M1 and the expected M2.
+#[cfg(test)]
+pub fn srp_client(
+    username: &str,
+    salt: &str,
+    password: &str,
+    server_public: &str,
+) -> (String, String, String) {
+    let (n, g) = srp_group();
+    let a = BigUint::from_bytes_be(&rand::random::<[u8; 32]>());
+    let client_public = g.modpow(&a, &n);
+    let server_public = srp_parse(server_public).unwrap();
+    let u = srp_number(&[&srp_pad(&client_public), &srp_pad(&server_public)]);
+    let x = srp_private_key(username, salt, password);
+    let base = (&server_public + &n - (srp_multiplier(&n, &g) * g.modpow(&x, &n)) % &n) % &n;
+    let shared = base.modpow(&(a + u * x), &n);
+    let proof = srp_hash(&[
+        &srp_pad(&client_public),
+        &srp_pad(&server_public),
+        &srp_pad(&shared),
+    ]);
+    let server_proof = srp_hash(&[&srp_pad(&client_public), &proof, &srp_pad(&shared)]);
+    (
+        client_public.to_str_radix(16),
+        hex::encode(proof),
+        hex::encode(server_proof),
+    )
+}
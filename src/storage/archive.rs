@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+
+use super::stream::HashingReader;
+use crate::{
+    logger::error::{ERROR_QUOTA_EXCEEDED, Error},
+    utils::current_time,
+};
+
+const MANIFEST: &str = "manifest.json";
+const FORMAT: u32 = 1;
+
+/// Kind of archive written by `create_backup`.
+pub const KIND_BACKUP: &str = "backup";
+/// Kind of archive written by `User::export_account`.
+pub const KIND_ACCOUNT: &str = "account";
+
+fn default_kind() -> String {
+    KIND_BACKUP.to_string()
+}
+
+/// Lists the SHA-256 of every file in an archive.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Manifest {
+    pub format: u32,
+    /// Archives written before this field existed are backups.
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    pub created_at: u64,
+    pub server_version: String,
+    pub files: BTreeMap<String, String>,
+}
+
+pub fn archive_error(err: impl Display) -> Error {
+    Error::new(format!("Archive error: {}", err).as_str())
+}
+
+/// Writes a gzipped tar archive next to its final path, ending with a manifest of hashes.
+/// The archive only appears at its final path once `finish` succeeded.
+pub struct ArchiveWriter {
+    builder: tar::Builder<GzEncoder<File>>,
+    manifest: Manifest,
+    path: String,
+    temp_path: String,
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &str, kind: &str) -> Result<Self, Error> {
+        let temp_path = path.to_string() + ".tmp";
+        let file = File::create(&temp_path).map_err(archive_error)?;
+        Ok(ArchiveWriter {
+            builder: tar::Builder::new(GzEncoder::new(file, Compression::default())),
+            manifest: Manifest {
+                format: FORMAT,
+                kind: kind.to_string(),
+                created_at: current_time(),
+                server_version: env!("CARGO_PKG_VERSION").to_string(),
+                files: BTreeMap::new(),
+            },
+            path: path.to_string(),
+            temp_path,
+        })
+    }
+
+    fn append(&mut self, path: &str, size: u64, content: impl Read) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o600);
+        header.set_mtime(self.manifest.created_at);
+        self.builder.append_data(&mut header, path, content)
+    }
+
+    pub fn add(&mut self, path: &str, content: &[u8]) -> Result<(), Error> {
+        self.add_reader(path, content.len() as u64, content)
+    }
+
+    /// Adds a file of `size` bytes read from `content`, hashing it while it is written.
+    pub fn add_reader(&mut self, path: &str, size: u64, content: impl Read) -> Result<(), Error> {
+        let mut reader = HashingReader::new(content.take(size));
+        self.append(path, size, &mut reader)
+            .map_err(archive_error)?;
+        self.manifest
+            .files
+            .insert(path.to_string(), reader.finish());
+        Ok(())
+    }
+
+    /// Writes the manifest and moves the archive to its final path.
+    pub fn finish(mut self) -> Result<(), Error> {
+        let result = serde_json::to_vec_pretty(&self.manifest)
+            .map_err(io::Error::from)
+            .and_then(|manifest| self.append(MANIFEST, manifest.len() as u64, &manifest[..]))
+            .and_then(|_| self.builder.into_inner())
+            .and_then(|encoder| encoder.finish())
+            .and_then(|file| file.sync_all())
+            .and_then(|_| fs::rename(&self.temp_path, &self.path));
+        if result.is_err() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+        result.map_err(archive_error)
+    }
+
+    /// Removes the partially written archive.
+    pub fn abort(self) {
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+/// Passes every entry of an archive of the given kind to `handler` with its size and a reader
+/// of its content, then checks the entries against the manifest. Entries are hashed while the
+/// handler reads them, and whatever it leaves unread is skipped. The handler sees entries
+/// before they are verified, so callers that must not act on a damaged archive read it twice.
+/// Reading stops with `ERROR_QUOTA_EXCEEDED` before the entries would unpack to more than
+/// `limit` bytes.
+pub fn read_archive(
+    path: &str,
+    kind: &str,
+    limit: u64,
+    mut handler: impl FnMut(&str, u64, &mut dyn Read) -> Result<(), Error>,
+) -> Result<Manifest, Error> {
+    let file = File::open(path).map_err(archive_error)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut hashes = BTreeMap::new();
+    let mut manifest: Option<Manifest> = None;
+    let mut total = 0u64;
+    for entry in archive.entries().map_err(archive_error)? {
+        let mut entry = entry.map_err(archive_error)?;
+        let size = entry.header().size().map_err(archive_error)?;
+        total = total.saturating_add(size);
+        if total > limit {
+            return Err(Error::new(ERROR_QUOTA_EXCEEDED));
+        }
+        let entry_path = entry
+            .path()
+            .map_err(archive_error)?
+            .to_str()
+            .ok_or_else(|| archive_error("entry path is not UTF-8"))?
+            .to_string();
+        if entry_path == MANIFEST {
+            manifest = Some(serde_json::from_reader(&mut entry).map_err(archive_error)?);
+            continue;
+        }
+        if hashes.contains_key(&entry_path) {
+            return Err(archive_error(format!("duplicate entry {}", entry_path)));
+        }
+        let mut reader = HashingReader::new(&mut entry);
+        handler(&entry_path, size, &mut reader)?;
+        io::copy(&mut reader, &mut io::sink()).map_err(archive_error)?;
+        hashes.insert(entry_path, reader.finish());
+    }
+    let manifest = manifest.ok_or_else(|| archive_error("manifest missing"))?;
+    if manifest.format != FORMAT {
+        Err(archive_error(format!(
+            "unsupported format {}",
+            manifest.format
+        )))
+    } else if manifest.kind != kind {
+        Err(archive_error(format!(
+            "expected {} archive, found {}",
+            kind, manifest.kind
+        )))
+    } else if hashes != manifest.files {
+        Err(archive_error("content does not match the manifest"))
+    } else {
+        Ok(manifest)
+    }
+}
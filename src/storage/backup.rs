@@ -0,0 +1,205 @@
+use std::fmt::Display;
+use std::fs;
+use std::io::Read;
+
+use log::info;
+
+use super::{
+    StorageBackend,
+    archive::{ArchiveWriter, KIND_BACKUP, archive_error, read_archive},
+    create_backend,
+    file::{FileBackend, dir_exists, remove_dir, remove_file},
+    lock::UserLock,
+    migration::migrate,
+    stream::{BlobReader, copy_to_file},
+};
+use crate::{
+    config::{BackendType, Config, Storage},
+    logger::error::Error,
+    user::{
+        User, UserHandle,
+        blob::{BlobID, Share},
+    },
+    utils::current_time,
+};
+
+const CONFIG: &str = "config.toml";
+
+/// Counts of what a backup or restore contained.
+#[derive(Debug, Default)]
+pub struct ArchiveSummary {
+    pub users: usize,
+    pub documents: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+impl Display for ArchiveSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Users: {}", self.users)?;
+        writeln!(f, "Documents: {}", self.documents)?;
+        writeln!(f, "Blobs: {}", self.blobs)?;
+        write!(f, "Bytes: {}", self.bytes)
+    }
+}
+
+/// Writes a backup of the config and every user with its shares, documents and blobs to a
+/// gzipped tar archive at `path`, ending with a manifest of hashes. Each user is copied while
+/// holding its lock, so the copy of every user is consistent even while the server runs.
+pub fn create_backup(
+    backend: &dyn StorageBackend,
+    lock_dir: &str,
+    config: &Config,
+    path: &str,
+) -> Result<ArchiveSummary, Error> {
+    let mut archive = ArchiveWriter::create(path, KIND_BACKUP)?;
+    match write_backup(backend, lock_dir, config, &mut archive) {
+        Ok(summary) => archive.finish().map(|_| summary),
+        Err(err) => {
+            archive.abort();
+            Err(err)
+        }
+    }
+}
+
+fn write_backup(
+    backend: &dyn StorageBackend,
+    lock_dir: &str,
+    config: &Config,
+    archive: &mut ArchiveWriter,
+) -> Result<ArchiveSummary, Error> {
+    let mut summary = ArchiveSummary::default();
+    let config = toml::to_string(config).map_err(archive_error)?;
+    archive.add(CONFIG, config.as_bytes())?;
+
+    for user in backend.list_users()? {
+        let userhandle = UserHandle::from_string("@".to_string() + &user + ":localhost")?;
+        let _lock = UserLock::acquire_in(lock_dir, &user)?;
+        let prefix = format!("users/{}/", user);
+        let data = backend.load_user(&userhandle)?.to_string()?;
+        archive.add(&(prefix.to_string() + "data.json"), data.as_bytes())?;
+        let shares =
+            serde_json::to_string(&backend.load_shares(&userhandle)).map_err(archive_error)?;
+        archive.add(&(prefix.to_string() + "shares.json"), shares.as_bytes())?;
+        for name in backend.list_documents(&userhandle) {
+            let content = backend
+                .read_document(&userhandle, &name)
+                .ok_or_else(|| Error::new(format!("Could not read document {}", name).as_str()))?;
+            archive.add(
+                &(prefix.to_string() + "documents/" + &name),
+                content.as_bytes(),
+            )?;
+            summary.documents += 1;
+        }
+        for id in backend.list_blobs(&userhandle)? {
+            let content = BlobReader::new(backend, &userhandle, id)?;
+            let size = content.size();
+            archive.add_reader(
+                &(prefix.to_string() + "blobs/" + &String::from(id)),
+                size,
+                content,
+            )?;
+            summary.blobs += 1;
+            summary.bytes += size;
+        }
+        summary.users += 1;
+        info!("Backed up {}", userhandle);
+    }
+    Ok(summary)
+}
+
+/// Restores a single archive entry into the staging backend. Blobs are unpacked to
+/// `temp_path` first and moved into place from there.
+fn restore_entry(
+    staging: &FileBackend,
+    temp_path: &str,
+    path: &str,
+    content: &mut dyn Read,
+    summary: &mut ArchiveSummary,
+) -> Result<(), Error> {
+    let parts: Vec<&str> = path.split('/').collect();
+    let invalid = || Error::new(format!("Unexpected archive entry {}", path).as_str());
+    let (user, rest) = match parts.as_slice() {
+        [CONFIG] => return Ok(()),
+        ["users", user, rest @ ..] => (*user, rest),
+        _ => return Err(invalid()),
+    };
+    let userhandle = UserHandle::from_string("@".to_string() + user + ":localhost")?;
+    let mut text = || {
+        let mut text = String::new();
+        content.read_to_string(&mut text).map_err(|_| invalid())?;
+        Ok::<_, Error>(text)
+    };
+    let stored = match rest {
+        ["data.json"] => {
+            let mut user = User::from_json(&text()?)?;
+            user.userhandle = userhandle;
+            summary.users += 1;
+            staging.save_user(&user)
+        }
+        ["shares.json"] => {
+            let shares: Vec<Share> = serde_json::from_str(&text()?).map_err(archive_error)?;
+            staging.save_shares(&userhandle, &shares)
+        }
+        ["documents", name] if !name.is_empty() && !name.starts_with('.') => {
+            summary.documents += 1;
+            staging.write_document(&userhandle, name, &text()?)
+        }
+        ["blobs", id] if id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()) => {
+            summary.blobs += 1;
+            summary.bytes += copy_to_file(content, temp_path).map_err(archive_error)?;
+            staging.write_blob_from_file(&userhandle, BlobID::from(id.to_string()), temp_path)
+        }
+        _ => return Err(invalid()),
+    };
+    if stored {
+        Ok(())
+    } else {
+        Err(Error::new(format!("Could not restore {}", path).as_str()))
+    }
+}
+
+/// Restores a backup archive. The archive is unpacked into a staging directory next to the
+/// data directory and checked against its manifest before anything is replaced. With the
+/// file backend the staging directory then replaces the data directory, which is kept as
+/// `<data_dir>.before-restore-<time>`; other backends get the users copied into them.
+/// The server must not be running.
+pub fn restore_backup(path: &str, storage: &Storage) -> Result<ArchiveSummary, Error> {
+    let staging_dir = storage.data_dir.to_string() + ".restore";
+    if dir_exists(&staging_dir) && !remove_dir(&staging_dir) {
+        return Err(Error::new("Could not remove old staging directory"));
+    }
+    let staging = FileBackend::new(&staging_dir);
+    let temp_path = storage.data_dir.to_string() + ".restore.tmp";
+    let mut summary = ArchiveSummary::default();
+    let verified = read_archive(path, KIND_BACKUP, u64::MAX, |entry, _, content| {
+        restore_entry(&staging, &temp_path, entry, content, &mut summary)
+    });
+    remove_file(&temp_path);
+    if let Err(err) = verified {
+        remove_dir(&staging_dir);
+        return Err(err);
+    }
+
+    if storage.backend == BackendType::File {
+        let previous = format!("{}.before-restore-{}", storage.data_dir, current_time());
+        if dir_exists(&storage.data_dir) {
+            fs::rename(&storage.data_dir, &previous).map_err(archive_error)?;
+            info!("Previous data directory kept at {}", previous);
+        }
+        fs::rename(&staging_dir, &storage.data_dir).map_err(archive_error)?;
+    } else {
+        let target = create_backend(storage);
+        let report = migrate(
+            &staging,
+            target.as_ref(),
+            &(staging_dir.to_string() + "/restore-state.json"),
+        );
+        if !report.failures.is_empty() {
+            return Err(Error::new(report.to_string().as_str()));
+        }
+        remove_dir(&staging_dir);
+        info!("Users not contained in the backup were left in place");
+    }
+    Ok(summary)
+}
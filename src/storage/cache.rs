@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{
+    StorageBackend,
+    file::{create_dir, dir_exists, get_file_size, read_file_to_string},
+};
+use crate::{
+    logger::error::Error,
+    user::{
+        User, UserHandle,
+        blob::{BlobHash, BlobID, Share},
+    },
+    utils::{random_u128, u128_to_32_char_hex_string},
+};
+
+const SHARDS: usize = 16;
+
+/// Cached state of a single user, valid while the user is at the recorded generation.
+#[derive(Default)]
+struct Entry {
+    generation: Option<String>,
+    user: Option<User>,
+    used_space: Option<u64>,
+}
+
+/// Storage backend wrapper keeping user records and quota usage in memory.
+/// Writes go to the wrapped backend first and update the cache only on success.
+/// Every write also moves the user to a new generation, stored in a file shared by all
+/// processes using the data directory. Cached state is dropped once the generation on disk
+/// differs, so writes of CLI commands reach a running server. With caching disabled only
+/// the generations are kept up to date.
+/// Users are spread over several independently locked shards so requests for
+/// different users do not contend.
+pub struct CachedBackend {
+    inner: Box<dyn StorageBackend>,
+    shards: Vec<RwLock<HashMap<String, Entry>>>,
+    generation_dir: String,
+    enabled: bool,
+}
+
+impl CachedBackend {
+    pub fn new(inner: Box<dyn StorageBackend>, generation_dir: &str, enabled: bool) -> Self {
+        CachedBackend {
+            inner,
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            generation_dir: generation_dir.to_string(),
+            enabled,
+        }
+    }
+
+    fn generation_path(&self, username: &str) -> String {
+        format!("{}/{}.generation", self.generation_dir, username)
+    }
+
+    fn current_generation(&self, username: &str) -> Option<String> {
+        read_file_to_string(self.generation_path(username)).ok()
+    }
+
+    /// Moves a user to a new generation, returning it, or `None` if it could not be stored.
+    fn next_generation(&self, username: &str) -> Option<String> {
+        if !dir_exists(&self.generation_dir) && !create_dir(&self.generation_dir) {
+            return None;
+        }
+        let generation = u128_to_32_char_hex_string(random_u128());
+        fs::write(self.generation_path(username), &generation).ok()?;
+        Some(generation)
+    }
+
+    /// Returns a value from the cached state of a user, if it is still current.
+    fn cached<T>(&self, username: &str, get: impl FnOnce(&Entry) -> Option<T>) -> Option<T> {
+        if !self.enabled {
+            return None;
+        }
+        let generation = self.current_generation(username);
+        self.read(username)
+            .get(username)
+            .filter(|entry| entry.generation == generation)
+            .and_then(get)
+    }
+
+    /// Stores a value in the cached state of a user, read from the wrapped backend at
+    /// `generation`. A stale entry is replaced first.
+    fn fill(&self, username: &str, generation: Option<String>, set: impl FnOnce(&mut Entry)) {
+        if !self.enabled {
+            return;
+        }
+        let mut shard = self.write(username);
+        let entry = shard.entry(username.to_string()).or_default();
+        if entry.generation != generation {
+            *entry = Entry {
+                generation,
+                ..Default::default()
+            };
+        }
+        set(entry);
+    }
+
+    /// Runs a write on the wrapped backend and moves the user to a new generation.
+    /// The cached state is kept and updated only if no other process wrote since it was
+    /// cached, otherwise it is dropped and read again on the next access.
+    fn write_through(
+        &self,
+        userhandle: &UserHandle,
+        write: impl FnOnce() -> bool,
+        update: impl FnOnce(&mut Entry),
+    ) -> bool {
+        let username = userhandle.get_local_username();
+        let before = self.current_generation(&username);
+        let written = write();
+        let after = self.next_generation(&username);
+        if !self.enabled {
+            return written;
+        }
+        let mut shard = self.write(&username);
+        match after {
+            Some(after) if written => {
+                let entry = shard.entry(username).or_default();
+                if entry.generation != before {
+                    *entry = Entry::default();
+                }
+                entry.generation = Some(after);
+                update(entry);
+            }
+            _ => {
+                shard.remove(&username);
+            }
+        }
+        written
+    }
+
+    fn shard(&self, username: &str) -> &RwLock<HashMap<String, Entry>> {
+        let mut hasher = DefaultHasher::new();
+        username.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn read(&self, username: &str) -> RwLockReadGuard<'_, HashMap<String, Entry>> {
+        self.shard(username)
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self, username: &str) -> RwLockWriteGuard<'_, HashMap<String, Entry>> {
+        self.shard(username)
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs a write changing the stored size of a blob or document from `old_size` to
+    /// `new_size`, adjusting the tracked quota usage if it has been computed already.
+    fn write_sized(
+        &self,
+        userhandle: &UserHandle,
+        old_size: u64,
+        new_size: u64,
+        write: impl FnOnce() -> bool,
+    ) -> bool {
+        self.write_through(userhandle, write, |entry| {
+            if let Some(used_space) = entry.used_space.as_mut() {
+                *used_space = used_space.saturating_sub(old_size).saturating_add(new_size);
+            }
+        })
+    }
+}
+
+impl StorageBackend for CachedBackend {
+    fn list_users(&self) -> Result<Vec<String>, Error> {
+        self.inner.list_users()
+    }
+
+    fn user_exists(&self, userhandle: &UserHandle) -> bool {
+        let username = userhandle.get_local_username();
+        self.cached(&username, |entry| entry.user.as_ref().map(|_| ()))
+            .is_some()
+            || self.inner.user_exists(userhandle)
+    }
+
+    fn load_user(&self, userhandle: &UserHandle) -> Result<User, Error> {
+        let username = userhandle.get_local_username();
+        if let Some(mut user) = self.cached(&username, |entry| entry.user.clone()) {
+            user.userhandle = userhandle.to_owned();
+            return Ok(user);
+        }
+        // A write after the generation was read moves the user on, so the record loaded
+        // here is dropped again on the next access rather than kept in place of the newer one
+        let generation = self.current_generation(&username);
+        let user = self.inner.load_user(userhandle)?;
+        self.fill(&username, generation, |entry| {
+            entry.user = Some(user.clone())
+        });
+        Ok(user)
+    }
+
+    fn save_user(&self, user: &User) -> bool {
+        self.write_through(
+            &user.userhandle,
+            || self.inner.save_user(user),
+            |entry| entry.user = Some(user.clone()),
+        )
+    }
+
+    fn load_shares(&self, userhandle: &UserHandle) -> Vec<Share> {
+        self.inner.load_shares(userhandle)
+    }
+
+    fn save_shares(&self, userhandle: &UserHandle, shares: &[Share]) -> bool {
+        self.inner.save_shares(userhandle, shares)
+    }
+
+    fn schema_version(&self, userhandle: &UserHandle) -> Result<u32, Error> {
+        self.inner.schema_version(userhandle)
+    }
+
+    fn check_records(&self, userhandle: &UserHandle) -> Vec<String> {
+        self.inner.check_records(userhandle)
+    }
+
+    fn read_document(&self, userhandle: &UserHandle, name: &str) -> Option<String> {
+        self.inner.read_document(userhandle, name)
+    }
+
+    fn write_document(&self, userhandle: &UserHandle, name: &str, content: &str) -> bool {
+        let old_size = self
+            .inner
+            .read_document(userhandle, name)
+            .map_or(0, |document| document.len() as u64);
+        self.write_sized(userhandle, old_size, content.len() as u64, || {
+            self.inner.write_document(userhandle, name, content)
+        })
+    }
+
+    fn list_documents(&self, userhandle: &UserHandle) -> Vec<String> {
+        self.inner.list_documents(userhandle)
+    }
+
+    fn list_blobs(&self, userhandle: &UserHandle) -> Result<Vec<BlobID>, Error> {
+        self.inner.list_blobs(userhandle)
+    }
+
+    fn blob_exists(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        self.inner.blob_exists(userhandle, id)
+    }
+
+    fn blob_size(&self, userhandle: &UserHandle, id: BlobID) -> Option<u64> {
+        self.inner.blob_size(userhandle, id)
+    }
+
+    fn read_blob(&self, userhandle: &UserHandle, id: BlobID) -> Result<Vec<u8>, Error> {
+        self.inner.read_blob(userhandle, id)
+    }
+
+    fn read_blob_range(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        self.inner.read_blob_range(userhandle, id, offset, length)
+    }
+
+    fn blob_hash(&self, userhandle: &UserHandle, id: BlobID) -> Result<BlobHash, Error> {
+        self.inner.blob_hash(userhandle, id)
+    }
+
+    fn write_blob(&self, userhandle: &UserHandle, id: BlobID, content: Vec<u8>) -> bool {
+        let old_size = self.inner.blob_size(userhandle, id).unwrap_or(0);
+        let new_size = content.len() as u64;
+        self.write_sized(userhandle, old_size, new_size, || {
+            self.inner.write_blob(userhandle, id, content)
+        })
+    }
+
+    fn write_blob_from_file(&self, userhandle: &UserHandle, id: BlobID, path: &str) -> bool {
+        let old_size = self.inner.blob_size(userhandle, id).unwrap_or(0);
+        let new_size = get_file_size(path).unwrap_or(0);
+        self.write_sized(userhandle, old_size, new_size, || {
+            self.inner.write_blob_from_file(userhandle, id, path)
+        })
+    }
+
+    fn copy_blob(&self, userhandle: &UserHandle, from: BlobID, to: BlobID) -> bool {
+        let old_size = self.inner.blob_size(userhandle, to).unwrap_or(0);
+        let new_size = self.inner.blob_size(userhandle, from).unwrap_or(0);
+        self.write_sized(userhandle, old_size, new_size, || {
+            self.inner.copy_blob(userhandle, from, to)
+        })
+    }
+
+    fn delete_blob(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        let old_size = self.inner.blob_size(userhandle, id).unwrap_or(0);
+        self.write_sized(userhandle, old_size, 0, || {
+            self.inner.delete_blob(userhandle, id)
+        })
+    }
+
+    fn used_space(&self, userhandle: &UserHandle) -> u64 {
+        let username = userhandle.get_local_username();
+        if let Some(used_space) = self.cached(&username, |entry| entry.used_space) {
+            return used_space;
+        }
+        let generation = self.current_generation(&username);
+        let used_space = self.inner.used_space(userhandle);
+        self.fill(&username, generation, |entry| {
+            entry.used_space = Some(used_space)
+        });
+        used_space
+    }
+
+    fn required_space(&self, userhandle: &UserHandle, size: u64, hash: &BlobHash) -> u64 {
+        self.inner.required_space(userhandle, size, hash)
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::StorageBackend;
+use crate::{
+    logger::error::{ERROR_BLOB_NOT_FOUND, Error},
+    user::{
+        User, UserHandle,
+        blob::{BlobHash, BlobID, Share},
+    },
+};
+
+const INDEX_DOCUMENT: &str = "dedup.json";
+
+/// Stored content shared by one or more blob IDs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Object {
+    /// ID under which the content is stored in the wrapped backend.
+    id: BlobID,
+    size: u64,
+    refs: u64,
+}
+
+/// Per-user index mapping blob IDs to content hashes and content hashes to stored objects.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Index {
+    blobs: HashMap<String, String>,
+    objects: HashMap<String, Object>,
+}
+
+/// Storage backend wrapper storing every distinct content of a user only once.
+/// Blob IDs are pointers to objects keyed by the SHA-256 of their content, which
+/// are reference counted and deleted from the wrapped backend once unreferenced.
+/// Blobs written before deduplication was enabled are still served directly.
+pub struct DedupBackend {
+    inner: Box<dyn StorageBackend>,
+    index_lock: Mutex<()>,
+}
+
+impl DedupBackend {
+    pub fn new(inner: Box<dyn StorageBackend>) -> Self {
+        DedupBackend {
+            inner,
+            index_lock: Mutex::new(()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.index_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_index(&self, userhandle: &UserHandle) -> Index {
+        serde_json::from_str(
+            self.inner
+                .read_document(userhandle, INDEX_DOCUMENT)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn write_index(&self, userhandle: &UserHandle, index: &Index) -> bool {
+        self.inner.write_document(
+            userhandle,
+            INDEX_DOCUMENT,
+            serde_json::to_string(index)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+
+    /// Returns the object a blob ID points to, if it is deduplicated.
+    fn resolve(&self, userhandle: &UserHandle, id: BlobID) -> Option<Object> {
+        let index = self.read_index(userhandle);
+        index
+            .blobs
+            .get(&String::from(id))
+            .and_then(|hash| index.objects.get(hash))
+            .cloned()
+    }
+
+    /// Drops one reference to the content with the given hash, deleting the object at zero.
+    fn release(&self, userhandle: &UserHandle, index: &mut Index, hash: &str) {
+        let Some(object) = index.objects.get_mut(hash) else {
+            return;
+        };
+        object.refs = object.refs.saturating_sub(1);
+        if object.refs == 0 {
+            let id = object.id;
+            index.objects.remove(hash);
+            if !self.inner.delete_blob(userhandle, id) {
+                error!(
+                    "Error deleting unreferenced object {} of {}",
+                    String::from(id),
+                    userhandle
+                );
+            }
+        }
+    }
+}
+
+fn content_hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+impl StorageBackend for DedupBackend {
+    fn list_users(&self) -> Result<Vec<String>, Error> {
+        self.inner.list_users()
+    }
+
+    fn user_exists(&self, userhandle: &UserHandle) -> bool {
+        self.inner.user_exists(userhandle)
+    }
+
+    fn load_user(&self, userhandle: &UserHandle) -> Result<User, Error> {
+        self.inner.load_user(userhandle)
+    }
+
+    fn save_user(&self, user: &User) -> bool {
+        self.inner.save_user(user)
+    }
+
+    fn load_shares(&self, userhandle: &UserHandle) -> Vec<Share> {
+        self.inner.load_shares(userhandle)
+    }
+
+    fn save_shares(&self, userhandle: &UserHandle, shares: &[Share]) -> bool {
+        self.inner.save_shares(userhandle, shares)
+    }
+
+    fn schema_version(&self, userhandle: &UserHandle) -> Result<u32, Error> {
+        self.inner.schema_version(userhandle)
+    }
+
+    fn check_records(&self, userhandle: &UserHandle) -> Vec<String> {
+        self.inner.check_records(userhandle)
+    }
+
+    fn read_document(&self, userhandle: &UserHandle, name: &str) -> Option<String> {
+        self.inner.read_document(userhandle, name)
+    }
+
+    fn write_document(&self, userhandle: &UserHandle, name: &str, content: &str) -> bool {
+        self.inner.write_document(userhandle, name, content)
+    }
+
+    fn list_documents(&self, userhandle: &UserHandle) -> Vec<String> {
+        self.inner
+            .list_documents(userhandle)
+            .into_iter()
+            .filter(|name| name != INDEX_DOCUMENT)
+            .collect()
+    }
+
+    fn list_blobs(&self, userhandle: &UserHandle) -> Result<Vec<BlobID>, Error> {
+        let index = self.read_index(userhandle);
+        let objects: Vec<String> = index
+            .objects
+            .values()
+            .map(|object| object.id.into())
+            .collect();
+        let mut blobs: Vec<BlobID> = self
+            .inner
+            .list_blobs(userhandle)?
+            .into_iter()
+            .filter(|id| !objects.contains(&String::from(*id)))
+            .collect();
+        blobs.extend(index.blobs.into_keys().map(BlobID::from));
+        Ok(blobs)
+    }
+
+    fn blob_exists(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        self.resolve(userhandle, id).is_some() || self.inner.blob_exists(userhandle, id)
+    }
+
+    fn blob_size(&self, userhandle: &UserHandle, id: BlobID) -> Option<u64> {
+        match self.resolve(userhandle, id) {
+            Some(object) => Some(object.size),
+            None => self.inner.blob_size(userhandle, id),
+        }
+    }
+
+    fn read_blob(&self, userhandle: &UserHandle, id: BlobID) -> Result<Vec<u8>, Error> {
+        match self.resolve(userhandle, id) {
+            Some(object) => self.inner.read_blob(userhandle, object.id),
+            None => self.inner.read_blob(userhandle, id),
+        }
+    }
+
+    fn read_blob_range(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        match self.resolve(userhandle, id) {
+            Some(object) => self
+                .inner
+                .read_blob_range(userhandle, object.id, offset, length),
+            None => self.inner.read_blob_range(userhandle, id, offset, length),
+        }
+    }
+
+    fn blob_hash(&self, userhandle: &UserHandle, id: BlobID) -> Result<BlobHash, Error> {
+        match self.read_index(userhandle).blobs.get(&String::from(id)) {
+            Some(hash) => Ok(BlobHash::from(hash.to_string())),
+            None => self.inner.blob_hash(userhandle, id),
+        }
+    }
+
+    fn write_blob(&self, userhandle: &UserHandle, id: BlobID, content: Vec<u8>) -> bool {
+        let _guard = self.lock();
+        let mut index = self.read_index(userhandle);
+        let hash = content_hash(&content);
+        let key = String::from(id);
+        let previous = index.blobs.get(&key).cloned();
+        if previous.as_ref() == Some(&hash) {
+            return true;
+        }
+
+        match index.objects.get_mut(&hash) {
+            Some(object) => object.refs += 1,
+            None => {
+                let mut object_id = BlobID::random();
+                while self.inner.blob_exists(userhandle, object_id) {
+                    object_id = BlobID::random();
+                }
+                let size = content.len() as u64;
+                if !self.inner.write_blob(userhandle, object_id, content) {
+                    return false;
+                }
+                index.objects.insert(
+                    hash.to_string(),
+                    Object {
+                        id: object_id,
+                        size,
+                        refs: 1,
+                    },
+                );
+            }
+        }
+        index.blobs.insert(key, hash);
+        match previous {
+            Some(previous) => self.release(userhandle, &mut index, &previous),
+            // A blob stored before deduplication was enabled is replaced by the pointer
+            None if self.inner.blob_exists(userhandle, id) => {
+                self.inner.delete_blob(userhandle, id);
+            }
+            None => {}
+        }
+        self.write_index(userhandle, &index)
+    }
+
+    fn delete_blob(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        let _guard = self.lock();
+        let mut index = self.read_index(userhandle);
+        match index.blobs.remove(&String::from(id)) {
+            Some(hash) => {
+                self.release(userhandle, &mut index, &hash);
+                self.write_index(userhandle, &index)
+            }
+            None if self.inner.blob_exists(userhandle, id) => {
+                self.inner.delete_blob(userhandle, id)
+            }
+            None => {
+                error!("{}: {}", ERROR_BLOB_NOT_FOUND, String::from(id));
+                false
+            }
+        }
+    }
+
+    fn used_space(&self, userhandle: &UserHandle) -> u64 {
+        // The index is bookkeeping of this backend, not content of the user
+        let index = self
+            .inner
+            .read_document(userhandle, INDEX_DOCUMENT)
+            .map_or(0, |index| index.len() as u64);
+        self.inner.used_space(userhandle).saturating_sub(index)
+    }
+
+    fn required_space(&self, userhandle: &UserHandle, size: u64, hash: &BlobHash) -> u64 {
+        if self
+            .read_index(userhandle)
+            .objects
+            .contains_key(&String::from(hash.to_owned()))
+        {
+            0
+        } else {
+            size
+        }
+    }
+}
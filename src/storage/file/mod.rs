@@ -1,22 +1,134 @@
 use std::fs;
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::thread;
+
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+
+use super::{StorageBackend, lock::UserLock, scrub::lock_dir};
+use crate::{
+    config::get_config,
+    logger::error::{ERROR_BLOB_NOT_FOUND, Error},
+    user::{
+        User, UserHandle,
+        blob::{BlobHash, BlobID, Share},
+        schema::{SCHEMA_VERSION, stored_version},
+    },
+    utils::{random_u128, u128_to_32_char_hex_string},
+};
 
 pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     fs::read(path)
 }
 
+/// Reads up to `length` bytes of a file starting at `offset`.
+pub fn read_file_range<P: AsRef<Path>>(path: P, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut content = vec![];
+    file.take(length).read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Computes the hex encoded SHA-256 of a file without reading it into memory at once.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
 /// Reads the entire contents of a file into a string.
 pub fn read_file_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
     fs::read_to_string(path)
 }
 
+/// Writes bytes to a file atomically, returning true on success and false on failure.
 pub fn write_file<P: AsRef<Path>>(path: P, content: Vec<u8>) -> bool {
-    fs::write(path, content).is_ok()
+    write_file_atomic(path, &content, false).is_ok()
 }
-/// Writes a string to a file, returning true on success and false on failure.
+
+/// Writes a string to a file atomically, returning true on success and false on failure.
 pub fn write_file_from_string<P: AsRef<Path>>(path: P, content: &str) -> bool {
-    fs::write(path, content).is_ok()
+    write_file_atomic(path, content.as_bytes(), false).is_ok()
+}
+
+/// Writes a string to a file atomically and keeps the previous version as `<path>.bak`,
+/// returning true on success and false on failure.
+pub fn write_file_with_backup<P: AsRef<Path>>(path: P, content: &str) -> bool {
+    write_file_atomic(path, content.as_bytes(), true).is_ok()
+}
+
+/// Writes the content to a temporary sibling file, syncs it to disk and renames it over the
+/// target, so a crash leaves either the old or the new version but never a truncated file.
+fn write_file_atomic<P: AsRef<Path>>(path: P, content: &[u8], backup: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?
+        .to_string_lossy();
+    let temp_path = dir.join(format!(
+        ".{}.{}.tmp",
+        file_name,
+        u128_to_32_char_hex_string(random_u128())
+    ));
+
+    let result = replace_file(dir, path, &temp_path, content, backup);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn replace_file(
+    dir: &Path,
+    path: &Path,
+    temp_path: &Path,
+    content: &[u8],
+    backup: bool,
+) -> io::Result<()> {
+    let mut file = fs::File::create(temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    if backup && path.is_file() {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        let _ = fs::remove_file(&backup_path);
+        if fs::hard_link(path, &backup_path).is_err() {
+            fs::copy(path, &backup_path)?;
+        }
+    }
+    fs::rename(temp_path, path)?;
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Moves a file to a new path, replacing any existing file, and syncs the target directory.
+/// Falls back to an atomic copy when both paths are on different file systems.
+pub fn move_file<P: AsRef<Path>>(from: P, to: P) -> bool {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let moved = fs::rename(from, to).is_ok()
+        || (read_file(from).is_ok_and(|content| write_file_atomic(to, &content, false).is_ok())
+            && remove_file(from));
+    if moved && let Some(dir) = to.parent() {
+        let _ = fs::File::open(dir).and_then(|dir| dir.sync_all());
+    }
+    moved
+}
+
+/// Checks if a directory entry is named like a blob, as opposed to temporary files and
+/// the directories of the sharded layout.
+fn is_blob_name(name: &str) -> bool {
+    name.len() == 32 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Checks if a directory entry is a temporary file left by an atomic write.
+pub fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
 }
 
 /// Creates a directory and all necessary parent directories, returning true on success and false on failure.
@@ -59,9 +171,10 @@ pub fn read_dir<P: AsRef<Path>>(path: P, recursive: bool) -> io::Result<Vec<Stri
     Ok(files)
 }
 
-/// Calculates the total size of a folder, including all its files and subdirectories.
+/// Calculates the total size of the files in a folder and its subdirectories.
+/// Directories themselves are not counted, so the blob layout does not take up quota.
 pub fn get_folder_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
-    let mut size = get_file_size(&path)?;
+    let mut size = 0;
     for entry in fs::read_dir(path)? {
         let entry = entry?;
         let path = entry.path();
@@ -78,3 +191,414 @@ pub fn get_folder_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
 pub fn get_file_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
     Ok(path.as_ref().metadata()?.len())
 }
+
+/// Storage backend keeping every user in its own directory below `<data_dir>/users/`.
+pub struct FileBackend {
+    data_dir: String,
+}
+
+impl FileBackend {
+    pub fn new(data_dir: &str) -> Self {
+        FileBackend {
+            data_dir: data_dir.to_string(),
+        }
+    }
+
+    fn resolve_user_path(&self, userhandle: &UserHandle, path: &str) -> String {
+        self.data_dir.to_string()
+            + "/users/"
+            + userhandle.get_local_username().as_str()
+            + "/"
+            + path
+    }
+
+    /// Path of a blob in the sharded layout, `blobs/<first two>/<next two>/<id>`, which keeps
+    /// directories small for users with many blobs.
+    fn resolve_blob_path(&self, userhandle: &UserHandle, id: BlobID) -> String {
+        let string: String = id.into();
+        self.resolve_user_path(
+            userhandle,
+            &format!("blobs/{}/{}/{}", &string[0..2], &string[2..4], string),
+        )
+    }
+
+    /// Path of a blob in the flat layout used before blobs were sharded.
+    fn resolve_legacy_blob_path(&self, userhandle: &UserHandle, id: BlobID) -> String {
+        let string: String = id.into();
+        self.resolve_user_path(userhandle, "blobs/") + string.as_str()
+    }
+
+    /// Runs a read on the blob in the sharded layout, falling back to the flat layout.
+    /// The sharded path is tried again last, in case the blob was moved in between.
+    fn with_blob_path<T>(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        read: impl Fn(String) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let path = self.resolve_blob_path(userhandle, id);
+        read(path.to_string())
+            .or_else(|_| read(self.resolve_legacy_blob_path(userhandle, id)))
+            .or_else(|_| read(path))
+    }
+
+    /// Creates the directory of a blob in the sharded layout and returns the blob's path.
+    fn prepare_blob_path(&self, userhandle: &UserHandle, id: BlobID) -> Option<String> {
+        let path = self.resolve_blob_path(userhandle, id);
+        let dir = Path::new(&path).parent()?;
+        (dir.is_dir() || create_dir(dir)).then_some(path)
+    }
+
+    /// Removes the flat layout copy of a blob that was just written in the sharded layout.
+    fn remove_legacy_blob(&self, userhandle: &UserHandle, id: BlobID) {
+        let legacy = self.resolve_legacy_blob_path(userhandle, id);
+        if file_exists(&legacy) {
+            remove_file(legacy);
+        }
+    }
+
+    /// Moves a user's blobs from the flat layout into the sharded layout, returning how many
+    /// were moved. The caller holds the user's lock.
+    pub fn relayout_blobs(&self, userhandle: &UserHandle) -> Result<usize, Error> {
+        let dir = self.resolve_user_path(userhandle, "blobs/");
+        if !dir_exists(&dir) {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        for name in read_dir(&dir, false).map_err(|e| Error::new(e.to_string().as_str()))? {
+            if !is_blob_name(&name) || !file_exists(dir.to_string() + &name) {
+                continue;
+            }
+            let id = BlobID::from(name);
+            let Some(path) = self.prepare_blob_path(userhandle, id) else {
+                return Err(Error::new("Could not create blob directory"));
+            };
+            // A blob written since the flat copy was made is newer
+            if file_exists(&path) {
+                self.remove_legacy_blob(userhandle, id);
+                continue;
+            }
+            fs::rename(self.resolve_legacy_blob_path(userhandle, id), &path)
+                .map_err(|e| Error::new(e.to_string().as_str()))?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Loads the previous version of a user record kept by `write_file_with_backup`.
+    fn load_user_backup(&self, userhandle: &UserHandle) -> Result<User, Error> {
+        let data = read_file_to_string(self.resolve_user_path(userhandle, "data.json.bak"))
+            .map_err(|_| Error::new("No backup of user data"))?;
+        let user = User::from_json(data.as_str())?;
+        warn!("Loaded user {} from backup", userhandle);
+        Ok(user)
+    }
+
+    fn create_user_dir(&self, userhandle: &UserHandle, path: &str) -> bool {
+        let dir = self.resolve_user_path(userhandle, path);
+        if dir_exists(&dir) {
+            return true;
+        }
+        if create_dir(&dir) {
+            info!("User directory created {}", dir);
+            true
+        } else {
+            error!("Error creating user directory {}", dir);
+            false
+        }
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn list_users(&self) -> Result<Vec<String>, Error> {
+        read_dir(self.data_dir.to_string() + "/users/", false)
+            .map_err(|e| Error::new(e.to_string().as_str()))
+    }
+
+    fn user_exists(&self, userhandle: &UserHandle) -> bool {
+        file_exists(self.resolve_user_path(userhandle, "data.json"))
+    }
+
+    fn load_user(&self, userhandle: &UserHandle) -> Result<User, Error> {
+        match read_file_to_string(self.resolve_user_path(userhandle, "data.json")) {
+            Ok(data) => match User::from_json(data.as_str()).or_else(|err| {
+                warn!("Error parsing user data: {}", err);
+                // An older backup must not stand in for data written by a newer server
+                if self.schema_version(userhandle).unwrap_or(0) > SCHEMA_VERSION {
+                    return Err(err);
+                }
+                self.load_user_backup(userhandle)
+            }) {
+                Ok(mut user) => {
+                    user.userhandle = userhandle.to_owned();
+                    Ok(user)
+                }
+                Err(err) => {
+                    warn!("Error parsing user data: {}", err);
+                    Err(Error::new(
+                        format!("Could not parse user data: {}", err).as_str(),
+                    ))
+                }
+            },
+            Err(_) => {
+                warn!("Error loading user data");
+                Err(Error::new("Could not load user data"))
+            }
+        }
+    }
+
+    fn save_user(&self, user: &User) -> bool {
+        if !self.create_user_dir(&user.userhandle, "") {
+            return false;
+        }
+        match user.to_string() {
+            Ok(string) => {
+                if write_file_with_backup(
+                    self.resolve_user_path(&user.userhandle, "data.json"),
+                    &string,
+                ) {
+                    true
+                } else {
+                    error!("Error saving user data");
+                    false
+                }
+            }
+            Err(err) => {
+                error!("Error serializing user data: {}", err);
+                false
+            }
+        }
+    }
+
+    fn load_shares(&self, userhandle: &UserHandle) -> Vec<Share> {
+        serde_json::from_str(
+            read_file_to_string(self.resolve_user_path(userhandle, "shares.json"))
+                .unwrap_or("[]".to_string())
+                .as_str(),
+        )
+        .unwrap_or(vec![])
+    }
+
+    fn save_shares(&self, userhandle: &UserHandle, shares: &[Share]) -> bool {
+        self.create_user_dir(userhandle, "")
+            && write_file_from_string(
+                self.resolve_user_path(userhandle, "shares.json"),
+                serde_json::to_string(shares)
+                    .unwrap_or("[]".to_string())
+                    .as_str(),
+            )
+    }
+
+    fn schema_version(&self, userhandle: &UserHandle) -> Result<u32, Error> {
+        let data = read_file_to_string(self.resolve_user_path(userhandle, "data.json"))
+            .map_err(|e| Error::new(e.to_string().as_str()))?;
+        serde_json::from_str(data.as_str())
+            .map(|user| stored_version(&user))
+            .map_err(|e| Error::new(format!("Error parsing user data: {}", e).as_str()))
+    }
+
+    fn check_records(&self, userhandle: &UserHandle) -> Vec<String> {
+        let mut problems = vec![];
+        // Checked directly, as loading the user silently falls back to the backup
+        match read_file_to_string(self.resolve_user_path(userhandle, "data.json")) {
+            Ok(data) => {
+                if let Err(err) = User::from_json(data.as_str()) {
+                    problems.push(format!("data.json: {}", err));
+                }
+            }
+            Err(err) => problems.push(format!("data.json: {}", err)),
+        }
+        let shares = self.resolve_user_path(userhandle, "shares.json");
+        if file_exists(&shares) {
+            match read_file_to_string(&shares) {
+                Ok(data) => {
+                    if let Err(err) = serde_json::from_str::<Vec<Share>>(data.as_str()) {
+                        problems.push(format!("shares.json: {}", err));
+                    }
+                }
+                Err(err) => problems.push(format!("shares.json: {}", err)),
+            }
+        }
+        problems
+    }
+
+    fn read_document(&self, userhandle: &UserHandle, name: &str) -> Option<String> {
+        read_file_to_string(self.resolve_user_path(userhandle, "documents/") + name).ok()
+    }
+
+    fn write_document(&self, userhandle: &UserHandle, name: &str, content: &str) -> bool {
+        self.create_user_dir(userhandle, "documents/")
+            && write_file_from_string(
+                self.resolve_user_path(userhandle, "documents/") + name,
+                content,
+            )
+    }
+
+    fn list_documents(&self, userhandle: &UserHandle) -> Vec<String> {
+        read_dir(self.resolve_user_path(userhandle, "documents/"), false)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| !is_temp_file(name))
+            .collect()
+    }
+
+    fn list_blobs(&self, userhandle: &UserHandle) -> Result<Vec<BlobID>, Error> {
+        let dir = self.resolve_user_path(userhandle, "blobs/");
+        if !dir_exists(&dir) {
+            return Ok(vec![]);
+        }
+        // Blobs not moved into the sharded layout yet are listed as well
+        read_dir(dir, true)
+            .map(|names| {
+                let mut ids: Vec<BlobID> = names
+                    .into_iter()
+                    .filter(|name| is_blob_name(name))
+                    .map(BlobID::from)
+                    .collect();
+                ids.sort();
+                ids.dedup();
+                ids
+            })
+            .map_err(|e| Error::new(e.to_string().as_str()))
+    }
+
+    fn blob_exists(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        self.with_blob_path(userhandle, id, |path| {
+            if file_exists(path) {
+                Ok(())
+            } else {
+                Err(io::ErrorKind::NotFound.into())
+            }
+        })
+        .is_ok()
+    }
+
+    fn blob_size(&self, userhandle: &UserHandle, id: BlobID) -> Option<u64> {
+        self.with_blob_path(userhandle, id, get_file_size).ok()
+    }
+
+    fn read_blob(&self, userhandle: &UserHandle, id: BlobID) -> Result<Vec<u8>, Error> {
+        self.with_blob_path(userhandle, id, read_file)
+            .map_err(|_| Error::new(ERROR_BLOB_NOT_FOUND))
+    }
+
+    fn read_blob_range(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        self.with_blob_path(userhandle, id, |path| read_file_range(path, offset, length))
+            .map_err(|_| Error::new(ERROR_BLOB_NOT_FOUND))
+    }
+
+    fn blob_hash(&self, userhandle: &UserHandle, id: BlobID) -> Result<BlobHash, Error> {
+        self.with_blob_path(userhandle, id, hash_file)
+            .map(BlobHash::from)
+            .map_err(|_| Error::new(ERROR_BLOB_NOT_FOUND))
+    }
+
+    fn write_blob(&self, userhandle: &UserHandle, id: BlobID, content: Vec<u8>) -> bool {
+        let written = self.create_user_dir(userhandle, "blobs/")
+            && self
+                .prepare_blob_path(userhandle, id)
+                .is_some_and(|path| write_file(path, content));
+        if written {
+            self.remove_legacy_blob(userhandle, id);
+        }
+        written
+    }
+
+    fn write_blob_from_file(&self, userhandle: &UserHandle, id: BlobID, path: &str) -> bool {
+        let written = self.create_user_dir(userhandle, "blobs/")
+            && self
+                .prepare_blob_path(userhandle, id)
+                .is_some_and(|target| move_file(path, target.as_str()));
+        if written {
+            self.remove_legacy_blob(userhandle, id);
+        }
+        written
+    }
+
+    fn copy_blob(&self, userhandle: &UserHandle, from: BlobID, to: BlobID) -> bool {
+        let Some(target) = self.prepare_blob_path(userhandle, to) else {
+            return false;
+        };
+        // Blobs are only ever replaced by renaming, so a hard link is a safe copy
+        let temp_path = self.resolve_user_path(
+            userhandle,
+            &format!(
+                "blobs/.copy.{}.tmp",
+                u128_to_32_char_hex_string(random_u128())
+            ),
+        );
+        let copied = if self
+            .with_blob_path(userhandle, from, |path| fs::hard_link(path, &temp_path))
+            .is_ok()
+        {
+            move_file(temp_path, target)
+        } else {
+            match self.with_blob_path(userhandle, from, read_file) {
+                Ok(content) => write_file(target, content),
+                Err(_) => false,
+            }
+        };
+        if copied {
+            self.remove_legacy_blob(userhandle, to);
+        }
+        copied
+    }
+
+    fn delete_blob(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        let sharded = remove_file(self.resolve_blob_path(userhandle, id));
+        remove_file(self.resolve_legacy_blob_path(userhandle, id)) || sharded
+    }
+
+    fn used_space(&self, userhandle: &UserHandle) -> u64 {
+        ["blobs/", "documents/"]
+            .iter()
+            .map(|dir| get_folder_size(self.resolve_user_path(userhandle, dir)).unwrap_or_default())
+            .sum()
+    }
+}
+
+/// Marks a data directory whose blobs were all moved into the sharded layout.
+const LAYOUT_MARKER: &str = "/.sharded-blobs";
+
+/// Moves the blobs of every user into the sharded layout in a background thread, one user at
+/// a time while holding its lock. Blobs stay readable in either layout meanwhile. Once every
+/// user was moved, a marker in the data directory keeps later starts from scanning again.
+pub fn start_blob_relayout() {
+    let storage = get_config().storage;
+    if file_exists(storage.data_dir.to_string() + LAYOUT_MARKER) {
+        return;
+    }
+    thread::spawn(move || {
+        let files = FileBackend::new(&storage.data_dir);
+        // Without a users directory nothing is stored yet
+        let Ok(users) = files.list_users() else {
+            return;
+        };
+        let mut complete = true;
+        for user in users {
+            let moved = UserHandle::from_string("@".to_string() + &user + ":localhost").and_then(
+                |userhandle| {
+                    let _lock = UserLock::acquire_in(&lock_dir(&storage), &user)?;
+                    files.relayout_blobs(&userhandle)
+                },
+            );
+            match moved {
+                Ok(0) => {}
+                Ok(moved) => info!("Moved {} blobs of {} into the sharded layout", moved, user),
+                Err(err) => {
+                    warn!("Could not move blobs of {}: {}", user, err);
+                    complete = false;
+                }
+            }
+        }
+        if complete && !write_file_from_string(storage.data_dir.to_string() + LAYOUT_MARKER, "") {
+            warn!("Could not mark the blob layout as sharded");
+        }
+    });
+}
@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+
+use fs4::fs_std::FileExt;
+
+use super::file::{create_dir, dir_exists};
+use crate::{config::get_config, logger::error::Error, user::UserHandle};
+
+thread_local! {
+    /// Locks held by the current thread with their nesting depth, making `UserLock` reentrant.
+    static HELD_LOCKS: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new());
+}
+
+/// Advisory lock on a user, serializing read-modify-write cycles of its data across
+/// threads and processes. The lock is released when the value is dropped.
+pub struct UserLock {
+    path: String,
+    file: Option<File>,
+}
+
+impl UserLock {
+    /// Blocks until the lock of the given user is acquired.
+    pub fn acquire(userhandle: &UserHandle) -> Result<UserLock, Error> {
+        Self::acquire_in(
+            &(get_config().storage.data_dir + "/locks"),
+            &userhandle.get_local_username(),
+        )
+    }
+
+    /// Blocks until the lock file `<dir>/<name>.lock` is acquired.
+    pub fn acquire_in(dir: &str, name: &str) -> Result<UserLock, Error> {
+        let path = format!("{}/{}.lock", dir, name);
+        let depth = HELD_LOCKS.with(|held| held.borrow().get(&path).copied().unwrap_or(0));
+        if depth > 0 {
+            HELD_LOCKS.with(|held| held.borrow_mut().insert(path.to_string(), depth + 1));
+            return Ok(UserLock { path, file: None });
+        }
+
+        if !dir_exists(dir) && !create_dir(dir) {
+            return Err(Error::new("Could not create lock directory"));
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::new(format!("Could not open lock file: {}", e).as_str()))?;
+        FileExt::lock_exclusive(&file)
+            .map_err(|e| Error::new(format!("Could not lock user: {}", e).as_str()))?;
+        HELD_LOCKS.with(|held| held.borrow_mut().insert(path.to_string(), 1));
+        Ok(UserLock {
+            path,
+            file: Some(file),
+        })
+    }
+}
+
+impl Drop for UserLock {
+    fn drop(&mut self) {
+        HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            match held.get(&self.path).copied() {
+                Some(depth) if depth > 1 => {
+                    held.insert(self.path.to_string(), depth - 1);
+                }
+                _ => {
+                    held.remove(&self.path);
+                }
+            }
+        });
+        if let Some(file) = &self.file {
+            let _ = FileExt::unlock(file);
+        }
+    }
+}
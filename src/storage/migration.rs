@@ -0,0 +1,212 @@
+use std::fmt::Display;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    StorageBackend,
+    file::{read_file_to_string, remove_file, write_file_from_string},
+    stream::{BlobReader, HashingReader, copy_to_file},
+};
+use crate::{
+    logger::error::Error,
+    user::{
+        UserHandle,
+        blob::{BlobHash, BlobID},
+    },
+};
+
+/// Progress of a migration, persisted after every user so an interrupted run can resume.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct MigrationState {
+    pub completed_users: Vec<String>,
+}
+
+/// Summary of a migration run.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated_users: usize,
+    pub resumed_users: usize,
+    pub copied_blobs: usize,
+    pub skipped_blobs: usize,
+    pub failures: Vec<String>,
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Migration report")?;
+        writeln!(f, "Users migrated: {}", self.migrated_users)?;
+        writeln!(f, "Users already migrated: {}", self.resumed_users)?;
+        writeln!(f, "Blobs copied: {}", self.copied_blobs)?;
+        writeln!(f, "Blobs already present: {}", self.skipped_blobs)?;
+        write!(f, "Failures: {}", self.failures.len())?;
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl MigrationState {
+    fn load(path: &str) -> Self {
+        serde_json::from_str(
+            read_file_to_string(path)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn save(&self, path: &str) -> bool {
+        write_file_from_string(
+            path,
+            serde_json::to_string(self)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+}
+
+/// Copy every user with its data, shares, documents and blobs from one backend to another.
+/// Blobs are streamed through a temporary file next to the state file and verified by
+/// their hash. Users completed in an earlier run, as recorded in the state file, are skipped.
+pub fn migrate(
+    from: &dyn StorageBackend,
+    to: &dyn StorageBackend,
+    state_path: &str,
+) -> MigrationReport {
+    let mut report = MigrationReport::default();
+    let mut state = MigrationState::load(state_path);
+    let temp_path = state_path.to_string() + ".blob.tmp";
+
+    let users = match from.list_users() {
+        Ok(users) => users,
+        Err(err) => {
+            report
+                .failures
+                .push(format!("Could not list users: {}", err));
+            return report;
+        }
+    };
+
+    for user in users {
+        if state.completed_users.contains(&user) {
+            report.resumed_users += 1;
+            continue;
+        }
+        let userhandle = match UserHandle::from_string("@".to_string() + &user + ":localhost") {
+            Ok(userhandle) => userhandle,
+            Err(err) => {
+                report.failures.push(format!("{}: {}", user, err));
+                continue;
+            }
+        };
+        info!("Migrating user {}", userhandle);
+        let failures = report.failures.len();
+        migrate_user(from, to, &userhandle, &temp_path, &mut report);
+        if report.failures.len() == failures {
+            report.migrated_users += 1;
+            state.completed_users.push(user);
+            if !state.save(state_path) {
+                warn!("Could not save migration state to {}", state_path);
+            }
+        }
+    }
+    remove_file(&temp_path);
+    report
+}
+
+/// Streams a blob from one backend to another through the file at `temp_path`, returning
+/// the hash of the content read.
+fn copy_blob(
+    from: &dyn StorageBackend,
+    to: &dyn StorageBackend,
+    userhandle: &UserHandle,
+    id: BlobID,
+    temp_path: &str,
+) -> Result<BlobHash, Error> {
+    let mut reader = HashingReader::new(BlobReader::new(from, userhandle, id)?);
+    copy_to_file(&mut reader, temp_path).map_err(|err| Error::new(err.to_string().as_str()))?;
+    if to.write_blob_from_file(userhandle, id, temp_path) {
+        Ok(BlobHash::from(reader.finish()))
+    } else {
+        Err(Error::new("could not write"))
+    }
+}
+
+fn migrate_user(
+    from: &dyn StorageBackend,
+    to: &dyn StorageBackend,
+    userhandle: &UserHandle,
+    temp_path: &str,
+    report: &mut MigrationReport,
+) {
+    let user = match from.load_user(userhandle) {
+        Ok(user) => user,
+        Err(err) => {
+            report.failures.push(format!("{}: {}", userhandle, err));
+            return;
+        }
+    };
+    if !to.save_user(&user) {
+        report
+            .failures
+            .push(format!("{}: could not save user data", userhandle));
+        return;
+    }
+    if !to.save_shares(userhandle, &from.load_shares(userhandle)) {
+        report
+            .failures
+            .push(format!("{}: could not save shares", userhandle));
+    }
+
+    for name in from.list_documents(userhandle) {
+        let copied = from
+            .read_document(userhandle, &name)
+            .is_some_and(|content| to.write_document(userhandle, &name, &content));
+        if !copied {
+            report
+                .failures
+                .push(format!("{}: could not copy document {}", userhandle, name));
+        }
+    }
+
+    let blobs = match from.list_blobs(userhandle) {
+        Ok(blobs) => blobs,
+        Err(err) => {
+            report
+                .failures
+                .push(format!("{}: could not list blobs: {}", userhandle, err));
+            return;
+        }
+    };
+    for id in blobs {
+        let blob: String = id.into();
+        if to.blob_exists(userhandle, id)
+            && let (Ok(existing), Ok(hash)) =
+                (to.blob_hash(userhandle, id), from.blob_hash(userhandle, id))
+            && existing == hash
+        {
+            report.skipped_blobs += 1;
+            continue;
+        }
+        let hash = match copy_blob(from, to, userhandle, id, temp_path) {
+            Ok(hash) => hash,
+            Err(err) => {
+                report
+                    .failures
+                    .push(format!("{}: blob {}: {}", userhandle, blob, err));
+                continue;
+            }
+        };
+        match to.blob_hash(userhandle, id) {
+            Ok(copy) if copy == hash => report.copied_blobs += 1,
+            Ok(_) => report
+                .failures
+                .push(format!("{}: blob {}: hash mismatch", userhandle, blob)),
+            Err(err) => report
+                .failures
+                .push(format!("{}: blob {}: {}", userhandle, blob, err)),
+        }
+    }
+}
@@ -1,3 +1,185 @@
-// TODO: Implement more storage backends (e.g., database, cloud storage)
-
+pub mod archive;
+pub mod backup;
+pub mod cache;
+pub mod dedup;
 pub mod file;
+pub mod lock;
+pub mod migration;
+pub mod s3;
+pub mod scrub;
+pub mod sqlite;
+pub mod stream;
+pub mod upload;
+
+use std::{io, process::exit, sync::OnceLock};
+
+use crate::{
+    config::{BackendType, Storage, get_config},
+    logger::error::Error,
+    user::{
+        User, UserHandle,
+        blob::{BlobHash, BlobID, Share},
+        schema::SCHEMA_VERSION,
+    },
+};
+use cache::CachedBackend;
+use dedup::DedupBackend;
+use file::FileBackend;
+use log::error;
+use s3::S3Backend;
+use scrub::lock_dir;
+use sqlite::SqliteBackend;
+use stream::{BlobReader, HashingReader};
+
+static BACKEND: OnceLock<Box<dyn StorageBackend>> = OnceLock::new();
+
+/// A place where user records, blobs and shares are persisted.
+pub trait StorageBackend: Send + Sync {
+    /// Returns the local usernames of all stored users.
+    fn list_users(&self) -> Result<Vec<String>, Error>;
+
+    /// Checks if a user record exists.
+    fn user_exists(&self, userhandle: &UserHandle) -> bool;
+
+    /// Loads a user record, setting its userhandle.
+    fn load_user(&self, userhandle: &UserHandle) -> Result<User, Error>;
+
+    /// Saves a user record, returning true on success and false on failure.
+    fn save_user(&self, user: &User) -> bool;
+
+    /// Loads all shares of a user, returning an empty list if there are none.
+    fn load_shares(&self, userhandle: &UserHandle) -> Vec<Share>;
+
+    /// Replaces all shares of a user, returning true on success and false on failure.
+    fn save_shares(&self, userhandle: &UserHandle, shares: &[Share]) -> bool;
+
+    /// Returns the schema version of the stored user record. Backends keeping users in
+    /// a structured form instead of serialized records always hold the current version.
+    fn schema_version(&self, userhandle: &UserHandle) -> Result<u32, Error> {
+        self.load_user(userhandle).map(|_| SCHEMA_VERSION)
+    }
+
+    /// Returns problems with the stored user record and shares of a user, such as
+    /// records that do not parse.
+    fn check_records(&self, userhandle: &UserHandle) -> Vec<String> {
+        match self.load_user(userhandle) {
+            Ok(_) => vec![],
+            Err(err) => vec![format!("user record: {}", err)],
+        }
+    }
+
+    /// Reads a named metadata document of a user, returning `None` if it does not exist.
+    fn read_document(&self, userhandle: &UserHandle, name: &str) -> Option<String>;
+
+    /// Writes a named metadata document of a user, returning true on success and false on failure.
+    fn write_document(&self, userhandle: &UserHandle, name: &str, content: &str) -> bool;
+
+    /// Returns the names of all metadata documents of a user.
+    fn list_documents(&self, userhandle: &UserHandle) -> Vec<String>;
+
+    /// Returns the IDs of all blobs of a user.
+    fn list_blobs(&self, userhandle: &UserHandle) -> Result<Vec<BlobID>, Error>;
+
+    /// Checks if a blob exists.
+    fn blob_exists(&self, userhandle: &UserHandle, id: BlobID) -> bool;
+
+    /// Returns the size of a blob in bytes, or `None` if it does not exist.
+    fn blob_size(&self, userhandle: &UserHandle, id: BlobID) -> Option<u64>;
+
+    /// Reads the content of a blob.
+    fn read_blob(&self, userhandle: &UserHandle, id: BlobID) -> Result<Vec<u8>, Error>;
+
+    /// Reads up to `length` bytes of a blob starting at `offset`.
+    fn read_blob_range(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let content = self.read_blob(userhandle, id)?;
+        let start = (offset as usize).min(content.len());
+        let end = start.saturating_add(length as usize).min(content.len());
+        Ok(content[start..end].to_vec())
+    }
+
+    /// Returns the hash of the content of a blob, reading it piece by piece.
+    fn blob_hash(&self, userhandle: &UserHandle, id: BlobID) -> Result<BlobHash, Error> {
+        let mut reader = HashingReader::new(BlobReader::new(self, userhandle, id)?);
+        io::copy(&mut reader, &mut io::sink())
+            .map_err(|err| Error::new(err.to_string().as_str()))?;
+        Ok(BlobHash::from(reader.finish()))
+    }
+
+    /// Writes the content of a blob, returning true on success and false on failure.
+    fn write_blob(&self, userhandle: &UserHandle, id: BlobID, content: Vec<u8>) -> bool;
+
+    /// Stores the content of a file as a blob, moving the file into place where the backend
+    /// allows it, returning true on success and false on failure.
+    fn write_blob_from_file(&self, userhandle: &UserHandle, id: BlobID, path: &str) -> bool {
+        match file::read_file(path) {
+            Ok(content) => self.write_blob(userhandle, id, content),
+            Err(err) => {
+                error!("Error reading file {}: {}", path, err);
+                false
+            }
+        }
+    }
+
+    /// Copies the content of a blob to another blob ID, returning true on success and false on failure.
+    fn copy_blob(&self, userhandle: &UserHandle, from: BlobID, to: BlobID) -> bool {
+        match self.read_blob(userhandle, from) {
+            Ok(content) => self.write_blob(userhandle, to, content),
+            Err(_) => false,
+        }
+    }
+
+    /// Deletes a blob, returning true on success and false on failure.
+    fn delete_blob(&self, userhandle: &UserHandle, id: BlobID) -> bool;
+
+    /// Returns the number of bytes the blobs and documents of a user occupy in this backend.
+    fn used_space(&self, userhandle: &UserHandle) -> u64;
+
+    /// Returns how many bytes of quota storing content of the given size and hash would take.
+    fn required_space(&self, _userhandle: &UserHandle, size: u64, _hash: &BlobHash) -> u64 {
+        size
+    }
+}
+
+/// Creates the storage backend selected in the given storage configuration,
+/// with content deduplication on top if it is enabled.
+pub fn create_backend(storage: &Storage) -> Box<dyn StorageBackend> {
+    build_backend(storage, false)
+}
+
+fn build_backend(storage: &Storage, cache: bool) -> Box<dyn StorageBackend> {
+    let mut backend: Box<dyn StorageBackend> = match storage.backend {
+        BackendType::File => Box::new(FileBackend::new(&storage.data_dir)),
+        BackendType::S3 => Box::new(S3Backend::new(&storage.data_dir, &storage.s3)),
+        BackendType::Sqlite => match SqliteBackend::new(&storage.sqlite.path, &storage.data_dir) {
+            Ok(backend) => Box::new(backend),
+            Err(err) => {
+                error!("Failed to open database {}: {}", storage.sqlite.path, err);
+                exit(10);
+            }
+        },
+    };
+    // The cache sits below deduplication so it only sees writes of distinct content.
+    // Disabled, it still records the generations the caches of other processes check.
+    backend = Box::new(CachedBackend::new(backend, &lock_dir(storage), cache));
+    if storage.deduplicate {
+        backend = Box::new(DedupBackend::new(backend));
+    }
+    backend
+}
+
+/// Get the storage backend selected in the configuration,
+/// wrapped in the in-memory user cache unless it is disabled.
+pub fn backend() -> &'static dyn StorageBackend {
+    BACKEND
+        .get_or_init(|| {
+            let storage = get_config().storage;
+            build_backend(&storage, storage.cache)
+        })
+        .as_ref()
+}
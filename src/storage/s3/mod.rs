@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use log::error;
+use reqwest::{
+    Method, StatusCode,
+    blocking::{Client, Response},
+};
+use sha2::{Digest, Sha256};
+
+use super::{StorageBackend, file::FileBackend};
+use crate::{
+    config::S3,
+    logger::error::{ERROR_BLOB_NOT_FOUND, Error},
+    storage::{
+        file::{create_dir, dir_exists, read_file_to_string, write_file_from_string},
+        lock::UserLock,
+    },
+    user::{
+        User, UserHandle,
+        blob::{BlobID, Share},
+    },
+};
+
+/// Storage backend keeping blobs in an S3-compatible bucket.
+/// User records and shares stay in the directory layout of the file backend,
+/// next to a `blobs.json` index holding the size of every stored blob.
+pub struct S3Backend {
+    metadata: FileBackend,
+    data_dir: String,
+    prefix: String,
+    client: S3Client,
+}
+
+/// Minimal S3 client using path-style requests signed with AWS Signature Version 4.
+/// Requests run on a separate thread, as the blocking client must not be driven from
+/// the async runtime of an actix worker.
+pub struct S3Client {
+    client: Client,
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Client {
+    pub fn new(config: &S3) -> Self {
+        S3Client {
+            client: off_runtime(|| {
+                Client::builder()
+                    .timeout(Duration::from_secs(60))
+                    .build()
+                    .expect("S3 client configuration is valid")
+            }),
+            endpoint: config.endpoint.trim_end_matches('/').to_string(),
+            bucket: config.bucket.to_string(),
+            region: config.region.to_string(),
+            access_key: config.access_key.to_string(),
+            secret_key: config.secret_key.to_string(),
+        }
+    }
+
+    /// Uploads an object, replacing any existing object with the same key.
+    pub fn put_object(&self, key: &str, content: &[u8]) -> Result<(), Error> {
+        self.request(Method::PUT, key, content).map(|_| ())
+    }
+
+    /// Downloads an object.
+    pub fn get_object(&self, key: &str) -> Result<Vec<u8>, Error> {
+        self.request(Method::GET, key, &[])
+    }
+
+    /// Downloads `length` bytes of an object starting at `offset`.
+    pub fn get_object_range(&self, key: &str, offset: u64, length: u64) -> Result<Vec<u8>, Error> {
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        self.request_with_headers(Method::GET, key, &[], &[("Range", &range)])
+    }
+
+    /// Deletes an object. Deleting a missing object is not an error.
+    pub fn delete_object(&self, key: &str) -> Result<(), Error> {
+        self.request(Method::DELETE, key, &[]).map(|_| ())
+    }
+
+    fn request(&self, method: Method, key: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+        self.request_with_headers(method, key, body, &[])
+    }
+
+    fn request_with_headers(
+        &self,
+        method: Method,
+        key: &str,
+        body: &[u8],
+        headers: &[(&str, &str)],
+    ) -> Result<Vec<u8>, Error> {
+        let path = format!("/{}/{}", uri_encode(&self.bucket), uri_encode(key));
+        let host = self
+            .endpoint
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .to_string();
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(body));
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                format!("AWS4{}", self.secret_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.access_key, scope, signature
+        );
+
+        let request = headers.iter().fold(
+            self.client
+                .request(method.clone(), self.endpoint.to_string() + &path)
+                .header("x-amz-date", &amz_date)
+                .header("x-amz-content-sha256", &payload_hash)
+                .header("Authorization", &authorization),
+            |request, (name, value)| request.header(*name, *value),
+        );
+        let body = body.to_vec();
+        let result = off_runtime(move || {
+            request
+                .body(body)
+                .send()
+                .and_then(Response::error_for_status)
+                .and_then(|response| response.bytes())
+        });
+        match result {
+            Ok(content) => Ok(content.to_vec()),
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => {
+                Err(Error::new(ERROR_BLOB_NOT_FOUND))
+            }
+            Err(err) => {
+                error!("S3 request {} {} failed: {}", method, path, err);
+                Err(Error::new(err.to_string().as_str()))
+            }
+        }
+    }
+}
+
+impl S3Backend {
+    pub fn new(data_dir: &str, config: &S3) -> Self {
+        S3Backend {
+            metadata: FileBackend::new(data_dir),
+            data_dir: data_dir.to_string(),
+            prefix: config.prefix.to_string(),
+            client: S3Client::new(config),
+        }
+    }
+
+    fn resolve_user_path(&self, userhandle: &UserHandle, path: &str) -> String {
+        self.data_dir.to_string()
+            + "/users/"
+            + userhandle.get_local_username().as_str()
+            + "/"
+            + path
+    }
+
+    fn resolve_object_key(&self, userhandle: &UserHandle, id: BlobID) -> String {
+        let string: String = id.into();
+        self.prefix.to_string() + userhandle.get_local_username().as_str() + "/" + string.as_str()
+    }
+
+    /// Reads the blob size index of a user, mapping blob IDs to sizes in bytes.
+    fn read_index(&self, userhandle: &UserHandle) -> HashMap<String, u64> {
+        serde_json::from_str(
+            read_file_to_string(self.resolve_user_path(userhandle, "blobs.json"))
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn write_index(&self, userhandle: &UserHandle, index: &HashMap<String, u64>) -> bool {
+        let dir = self.resolve_user_path(userhandle, "");
+        if !dir_exists(&dir) && !create_dir(&dir) {
+            error!("Error creating user directory {}", dir);
+            return false;
+        }
+        write_file_from_string(
+            self.resolve_user_path(userhandle, "blobs.json"),
+            serde_json::to_string(index)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+}
+
+impl StorageBackend for S3Backend {
+    fn list_users(&self) -> Result<Vec<String>, Error> {
+        self.metadata.list_users()
+    }
+
+    fn user_exists(&self, userhandle: &UserHandle) -> bool {
+        self.metadata.user_exists(userhandle)
+    }
+
+    fn load_user(&self, userhandle: &UserHandle) -> Result<User, Error> {
+        self.metadata.load_user(userhandle)
+    }
+
+    fn save_user(&self, user: &User) -> bool {
+        self.metadata.save_user(user)
+    }
+
+    fn load_shares(&self, userhandle: &UserHandle) -> Vec<Share> {
+        self.metadata.load_shares(userhandle)
+    }
+
+    fn save_shares(&self, userhandle: &UserHandle, shares: &[Share]) -> bool {
+        self.metadata.save_shares(userhandle, shares)
+    }
+
+    fn schema_version(&self, userhandle: &UserHandle) -> Result<u32, Error> {
+        self.metadata.schema_version(userhandle)
+    }
+
+    fn check_records(&self, userhandle: &UserHandle) -> Vec<String> {
+        self.metadata.check_records(userhandle)
+    }
+
+    fn read_document(&self, userhandle: &UserHandle, name: &str) -> Option<String> {
+        self.metadata.read_document(userhandle, name)
+    }
+
+    fn write_document(&self, userhandle: &UserHandle, name: &str, content: &str) -> bool {
+        self.metadata.write_document(userhandle, name, content)
+    }
+
+    fn list_documents(&self, userhandle: &UserHandle) -> Vec<String> {
+        self.metadata.list_documents(userhandle)
+    }
+
+    fn list_blobs(&self, userhandle: &UserHandle) -> Result<Vec<BlobID>, Error> {
+        Ok(self
+            .read_index(userhandle)
+            .into_keys()
+            .map(BlobID::from)
+            .collect())
+    }
+
+    fn blob_exists(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        self.read_index(userhandle).contains_key(&String::from(id))
+    }
+
+    fn blob_size(&self, userhandle: &UserHandle, id: BlobID) -> Option<u64> {
+        self.read_index(userhandle).get(&String::from(id)).copied()
+    }
+
+    fn read_blob(&self, userhandle: &UserHandle, id: BlobID) -> Result<Vec<u8>, Error> {
+        self.client
+            .get_object(&self.resolve_object_key(userhandle, id))
+    }
+
+    fn read_blob_range(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let size = self
+            .blob_size(userhandle, id)
+            .ok_or_else(|| Error::new(ERROR_BLOB_NOT_FOUND))?;
+        let length = length.min(size.saturating_sub(offset));
+        self.client
+            .get_object_range(&self.resolve_object_key(userhandle, id), offset, length)
+    }
+
+    fn write_blob(&self, userhandle: &UserHandle, id: BlobID, content: Vec<u8>) -> bool {
+        let size = content.len() as u64;
+        if self
+            .client
+            .put_object(&self.resolve_object_key(userhandle, id), &content)
+            .is_err()
+        {
+            return false;
+        }
+        let Ok(_lock) = UserLock::acquire(userhandle) else {
+            return false;
+        };
+        let mut index = self.read_index(userhandle);
+        index.insert(id.into(), size);
+        self.write_index(userhandle, &index)
+    }
+
+    fn delete_blob(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        if self
+            .client
+            .delete_object(&self.resolve_object_key(userhandle, id))
+            .is_err()
+        {
+            return false;
+        }
+        let Ok(_lock) = UserLock::acquire(userhandle) else {
+            return false;
+        };
+        let mut index = self.read_index(userhandle);
+        index.remove(&String::from(id));
+        self.write_index(userhandle, &index)
+    }
+
+    fn used_space(&self, userhandle: &UserHandle) -> u64 {
+        self.read_index(userhandle).values().sum::<u64>() + self.metadata.used_space(userhandle)
+    }
+}
+
+/// Runs `f` on a scoped thread outside any async runtime and waits for its result.
+fn off_runtime<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    thread::scope(|scope| scope.spawn(f).join().expect("S3 request thread panicked"))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes an object key as required for the canonical URI, keeping `/` as separator.
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
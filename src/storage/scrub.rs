@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    StorageBackend, backend,
+    file::{read_file_to_string, write_file_from_string},
+    lock::UserLock,
+};
+use crate::{
+    config::{Storage, get_config},
+    user::{
+        UserHandle,
+        blob::{BlobHash, BlobID},
+        metadata::{BlobMetadata, METADATA_DOCUMENT},
+        trash::{TRASH_DOCUMENT, TrashEntry},
+        versions::{VERSIONS_DOCUMENT, Versions},
+    },
+    utils::current_time,
+};
+
+pub const QUARANTINE_DOCUMENT: &str = "quarantine.json";
+
+/// A corrupt blob moved out of the user's view, stored under a blob ID of its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuarantineEntry {
+    pub id: BlobID,
+    pub stored: BlobID,
+    pub reason: String,
+    pub quarantined_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// The user record or shares cannot be read.
+    Record,
+    /// A blob's content does not match its recorded hash.
+    HashMismatch,
+    /// A blob cannot be read.
+    UnreadableBlob,
+    /// A share lists a blob that does not exist.
+    MissingSharedBlob,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Finding {
+    pub user: String,
+    pub problem: Problem,
+    pub blob: Option<String>,
+    pub detail: String,
+    pub quarantined: bool,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:?}", self.user, self.problem)?;
+        if let Some(blob) = &self.blob {
+            write!(f, " blob {}", blob)?;
+        }
+        write!(f, ": {}", self.detail)?;
+        if self.quarantined {
+            write!(f, " (quarantined)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Summary of a scrub run.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ScrubReport {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub users: usize,
+    pub verified_blobs: usize,
+    /// Blobs that could be read but have no recorded hash to compare against.
+    pub unverified_blobs: usize,
+    /// Blobs stored before metadata was kept that got their metadata recorded.
+    #[serde(default)]
+    pub recorded_blobs: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl Display for ScrubReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Scrub report")?;
+        writeln!(f, "Users: {}", self.users)?;
+        writeln!(f, "Blobs verified: {}", self.verified_blobs)?;
+        writeln!(f, "Blobs without recorded hash: {}", self.unverified_blobs)?;
+        writeln!(
+            f,
+            "Blobs with newly recorded metadata: {}",
+            self.recorded_blobs
+        )?;
+        write!(f, "Findings: {}", self.findings.len())?;
+        for finding in &self.findings {
+            write!(f, "\n  {}", finding)?;
+        }
+        Ok(())
+    }
+}
+
+impl ScrubReport {
+    /// Loads the report of the last scrub.
+    pub fn load(path: &str) -> Option<Self> {
+        read_file_to_string(path)
+            .ok()
+            .and_then(|data| serde_json::from_str(data.as_str()).ok())
+    }
+
+    pub fn save(&self, path: &str) -> bool {
+        write_file_from_string(
+            path,
+            serde_json::to_string_pretty(self)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+
+    fn add(
+        &mut self,
+        userhandle: &UserHandle,
+        problem: Problem,
+        blob: Option<BlobID>,
+        detail: &str,
+    ) {
+        let finding = Finding {
+            user: userhandle.to_string(),
+            problem,
+            blob: blob.map(String::from),
+            detail: detail.to_string(),
+            quarantined: false,
+        };
+        warn!("Scrub: {}", finding);
+        self.findings.push(finding);
+    }
+}
+
+/// Path of the report written by the last scrub.
+pub fn report_path(storage: &Storage) -> String {
+    storage.log_dir.to_string() + "/scrub-report.json"
+}
+
+/// Directory of the user locks, as used by `UserLock::acquire`.
+pub fn lock_dir(storage: &Storage) -> String {
+    storage.data_dir.to_string() + "/locks"
+}
+
+fn read_json<T: for<'de> Deserialize<'de> + Default>(
+    backend: &dyn StorageBackend,
+    userhandle: &UserHandle,
+    name: &str,
+) -> T {
+    backend
+        .read_document(userhandle, name)
+        .and_then(|data| serde_json::from_str(data.as_str()).ok())
+        .unwrap_or_default()
+}
+
+/// Returns the quarantined blobs of a user.
+pub fn quarantined(backend: &dyn StorageBackend, userhandle: &UserHandle) -> Vec<QuarantineEntry> {
+    read_json(backend, userhandle, QUARANTINE_DOCUMENT)
+}
+
+/// Returns the recorded hash of every blob and blob version of a user.
+fn expected_hashes(
+    backend: &dyn StorageBackend,
+    userhandle: &UserHandle,
+) -> HashMap<BlobID, BlobHash> {
+    let metadata: HashMap<String, BlobMetadata> = read_json(backend, userhandle, METADATA_DOCUMENT);
+    let versions: Versions = read_json(backend, userhandle, VERSIONS_DOCUMENT);
+    metadata
+        .into_iter()
+        .map(|(id, metadata)| (BlobID::from(id), metadata.hash))
+        .chain(
+            versions
+                .into_values()
+                .flatten()
+                .map(|version| (version.id, version.hash)),
+        )
+        .collect()
+}
+
+/// Checks the records, shares and blobs of every user. Blobs whose content does not match
+/// their recorded hash are moved into quarantine if `quarantine` is set.
+pub fn scrub(backend: &dyn StorageBackend, lock_dir: &str, quarantine: bool) -> ScrubReport {
+    let mut report = ScrubReport {
+        started_at: current_time(),
+        ..Default::default()
+    };
+    let users = match backend.list_users() {
+        Ok(users) => users,
+        Err(err) => {
+            error!("Scrub: could not list users: {}", err);
+            return report;
+        }
+    };
+    for user in users {
+        match UserHandle::from_string("@".to_string() + &user + ":localhost") {
+            Ok(userhandle) => {
+                report.users += 1;
+                scrub_user(backend, lock_dir, &userhandle, quarantine, &mut report);
+            }
+            Err(err) => error!("Scrub: {}: {}", user, err),
+        }
+    }
+    report.finished_at = current_time();
+    report
+}
+
+fn scrub_user(
+    backend: &dyn StorageBackend,
+    lock_dir: &str,
+    userhandle: &UserHandle,
+    quarantine: bool,
+    report: &mut ScrubReport,
+) {
+    for problem in backend.check_records(userhandle) {
+        report.add(userhandle, Problem::Record, None, &problem);
+    }
+
+    let blobs = match backend.list_blobs(userhandle) {
+        Ok(blobs) => blobs,
+        Err(err) => {
+            report.add(
+                userhandle,
+                Problem::Record,
+                None,
+                &format!("could not list blobs: {}", err),
+            );
+            return;
+        }
+    };
+    let skipped: HashSet<BlobID> = quarantined(backend, userhandle)
+        .into_iter()
+        .map(|entry| entry.stored)
+        .collect();
+    let expected = expected_hashes(backend, userhandle);
+    for id in blobs.into_iter().filter(|id| !skipped.contains(id)) {
+        let (size, hash) = match backend.read_blob(userhandle, id) {
+            Ok(content) => (content.len() as u64, BlobHash::hash(content)),
+            Err(err) => {
+                report.add(
+                    userhandle,
+                    Problem::UnreadableBlob,
+                    Some(id),
+                    &err.to_string(),
+                );
+                continue;
+            }
+        };
+        match expected.get(&id) {
+            None if record_metadata(backend, lock_dir, userhandle, id, size, &hash) => {
+                report.recorded_blobs += 1
+            }
+            None => report.unverified_blobs += 1,
+            Some(expected) if *expected == hash => report.verified_blobs += 1,
+            Some(expected) => {
+                let detail = format!(
+                    "expected hash {}, found {}",
+                    String::from(expected.to_owned()),
+                    String::from(hash)
+                );
+                report.add(userhandle, Problem::HashMismatch, Some(id), &detail);
+                if quarantine
+                    && quarantine_blob(backend, lock_dir, userhandle, id, &detail)
+                    && let Some(finding) = report.findings.last_mut()
+                {
+                    finding.quarantined = true;
+                }
+            }
+        }
+    }
+
+    for share in backend.load_shares(userhandle) {
+        for id in share.blobs {
+            if !backend.blob_exists(userhandle, id) {
+                let detail = format!("listed in share {}", String::from(share.id));
+                report.add(userhandle, Problem::MissingSharedBlob, Some(id), &detail);
+            }
+        }
+    }
+}
+
+/// Records the metadata of a blob stored before metadata was kept. Trashed blobs are left
+/// alone, they get it once restored.
+fn record_metadata(
+    backend: &dyn StorageBackend,
+    lock_dir: &str,
+    userhandle: &UserHandle,
+    id: BlobID,
+    size: u64,
+    hash: &BlobHash,
+) -> bool {
+    let Ok(_lock) = UserLock::acquire_in(lock_dir, &userhandle.get_local_username()) else {
+        return false;
+    };
+    let trash: Vec<TrashEntry> = read_json(backend, userhandle, TRASH_DOCUMENT);
+    if trash.iter().any(|entry| entry.stored == id) {
+        return false;
+    }
+    // The blob may have been written or deleted since it was read, which records or drops
+    // its metadata
+    let mut metadata: HashMap<String, BlobMetadata> =
+        read_json(backend, userhandle, METADATA_DOCUMENT);
+    if metadata.contains_key(&String::from(id)) || !backend.blob_exists(userhandle, id) {
+        return false;
+    }
+    let now = current_time();
+    metadata.insert(
+        id.into(),
+        BlobMetadata {
+            size,
+            hash: hash.to_owned(),
+            created_at: now,
+            updated_at: now,
+            tags: None,
+        },
+    );
+    backend.write_document(
+        userhandle,
+        METADATA_DOCUMENT,
+        serde_json::to_string(&metadata)
+            .unwrap_or("{}".to_string())
+            .as_str(),
+    )
+}
+
+/// Moves a blob still not matching its recorded hash into quarantine, dropping its metadata.
+fn quarantine_blob(
+    backend: &dyn StorageBackend,
+    lock_dir: &str,
+    userhandle: &UserHandle,
+    id: BlobID,
+    reason: &str,
+) -> bool {
+    let Ok(_lock) = UserLock::acquire_in(lock_dir, &userhandle.get_local_username()) else {
+        return false;
+    };
+    // The blob may have been replaced since it was checked
+    let still_corrupt = match (
+        backend.read_blob(userhandle, id),
+        expected_hashes(backend, userhandle).get(&id),
+    ) {
+        (Ok(content), Some(expected)) => BlobHash::hash(content) != *expected,
+        _ => false,
+    };
+    if !still_corrupt {
+        return false;
+    }
+
+    let mut stored = BlobID::random();
+    while backend.blob_exists(userhandle, stored) {
+        stored = BlobID::random();
+    }
+    if !backend.copy_blob(userhandle, id, stored) {
+        return false;
+    }
+    let mut entries = quarantined(backend, userhandle);
+    entries.push(QuarantineEntry {
+        id,
+        stored,
+        reason: reason.to_string(),
+        quarantined_at: current_time(),
+    });
+    if !backend.write_document(
+        userhandle,
+        QUARANTINE_DOCUMENT,
+        serde_json::to_string(&entries)
+            .unwrap_or("[]".to_string())
+            .as_str(),
+    ) {
+        backend.delete_blob(userhandle, stored);
+        return false;
+    }
+    backend.delete_blob(userhandle, id);
+
+    let mut metadata: HashMap<String, BlobMetadata> =
+        read_json(backend, userhandle, METADATA_DOCUMENT);
+    if metadata.remove(&String::from(id)).is_some() {
+        backend.write_document(
+            userhandle,
+            METADATA_DOCUMENT,
+            serde_json::to_string(&metadata)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        );
+    }
+    true
+}
+
+/// Starts a background thread scrubbing all users at the configured interval and writing
+/// the report to the log directory.
+pub fn start_scrubber() {
+    let storage = get_config().storage;
+    if storage.scrub_interval == 0 {
+        return;
+    }
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(storage.scrub_interval));
+            info!("Scrubbing stored data...");
+            let report = scrub(backend(), &lock_dir(&storage), storage.scrub_quarantine);
+            if report.findings.is_empty() {
+                info!("{}", report);
+            } else {
+                warn!("{}", report);
+            }
+            if !report.save(&report_path(&storage)) {
+                warn!("Could not save scrub report");
+            }
+        }
+    });
+}
@@ -0,0 +1,603 @@
+use std::sync::{Mutex, MutexGuard};
+
+use log::error;
+use rusqlite::{Connection, OptionalExtension, Transaction, params, types::Type};
+use serde::de::DeserializeOwned;
+
+use super::{StorageBackend, file::FileBackend};
+use crate::{
+    logger::error::Error,
+    user::{
+        Auth, AuthSession, EncryptedData, MFA, MFAMethod, Session, User, UserHandle,
+        blob::{BlobHash, BlobID, Share},
+        schema::SCHEMA_VERSION,
+    },
+    utils::{char_hex_string_to_u128, u128_to_32_char_hex_string},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    salt TEXT NOT NULL,
+    foreign_keyring TEXT NOT NULL,
+    tier TEXT NOT NULL,
+    mfa_enabled INTEGER NOT NULL,
+    mfa_min_methods INTEGER NOT NULL,
+    recovery_codes TEXT NOT NULL,
+    master_key TEXT NOT NULL,
+    keyring TEXT NOT NULL,
+    blob_map TEXT NOT NULL,
+    verifier TEXT NOT NULL DEFAULT '',
+    lockout TEXT NOT NULL DEFAULT '',
+    srp_identity TEXT NOT NULL DEFAULT ''
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used INTEGER NOT NULL,
+    root INTEGER NOT NULL,
+    PRIMARY KEY (username, id)
+);
+CREATE TABLE IF NOT EXISTS auth_sessions (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    challenge TEXT NOT NULL,
+    completed_mfa BLOB NOT NULL,
+    password_correct INTEGER NOT NULL,
+    srp_secret TEXT NOT NULL DEFAULT '',
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (username, id)
+);
+CREATE TABLE IF NOT EXISTS mfa_methods (
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    enabled INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    type TEXT NOT NULL,
+    PRIMARY KEY (username, id)
+);
+CREATE TABLE IF NOT EXISTS shares (
+    username TEXT NOT NULL,
+    id TEXT NOT NULL,
+    write INTEGER NOT NULL,
+    secret TEXT NOT NULL,
+    PRIMARY KEY (username, id)
+);
+CREATE TABLE IF NOT EXISTS share_blobs (
+    username TEXT NOT NULL,
+    share_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    blob_id TEXT NOT NULL,
+    PRIMARY KEY (username, share_id, position),
+    FOREIGN KEY (username, share_id) REFERENCES shares(username, id) ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS documents (
+    username TEXT NOT NULL,
+    name TEXT NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (username, name)
+);
+";
+
+/// Columns added after their table was first created, with their definitions. Databases
+/// created before get them on open.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("users", "verifier", "TEXT NOT NULL DEFAULT ''"),
+    ("auth_sessions", "srp_secret", "TEXT NOT NULL DEFAULT ''"),
+    ("users", "lockout", "TEXT NOT NULL DEFAULT ''"),
+    (
+        "auth_sessions",
+        "failed_attempts",
+        "INTEGER NOT NULL DEFAULT 0",
+    ),
+    ("users", "srp_identity", "TEXT NOT NULL DEFAULT ''"),
+];
+
+fn add_missing_columns(connection: &Connection) -> rusqlite::Result<()> {
+    for (table, column, definition) in ADDED_COLUMNS {
+        let exists = connection
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                table
+            ))?
+            .exists(params![column])?;
+        if !exists {
+            connection.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses a JSON column, failing the read for content that is not valid. Rows written before
+/// the column was added hold an empty string and read as the default.
+fn parse_json<T: DeserializeOwned + Default>(column: usize, value: &str) -> rusqlite::Result<T> {
+    if value.is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_str(value)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(e)))
+}
+
+/// Storage backend keeping user records, sessions and shares in an SQLite database.
+/// Blobs stay in the directory layout of the file backend. Every load and save of a user is
+/// one transaction; a load, change and save of the same user is only safe from concurrent
+/// requests while the user's lock is held, as `User::with_locked` does.
+pub struct SqliteBackend {
+    connection: Mutex<Connection>,
+    blobs: FileBackend,
+}
+
+impl SqliteBackend {
+    /// Opens (or creates) the database at the given path and initializes the schema.
+    pub fn new(path: &str, data_dir: &str) -> Result<Self, Error> {
+        let connection = Connection::open(path).map_err(to_error)?;
+        connection
+            .execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")
+            .map_err(to_error)?;
+        connection
+            .busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(to_error)?;
+        connection.execute_batch(SCHEMA).map_err(to_error)?;
+        add_missing_columns(&connection).map_err(to_error)?;
+        Ok(SqliteBackend {
+            connection: Mutex::new(connection),
+            blobs: FileBackend::new(data_dir),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_user(&self, username: &str) -> rusqlite::Result<Option<User>> {
+        let mut guard = self.connection();
+        // Read from one snapshot, so a save by another process is never seen half done
+        let connection = guard.transaction()?;
+        let Some(mut user) = connection
+            .query_row(
+                "SELECT hash, salt, foreign_keyring, tier, mfa_enabled, mfa_min_methods,
+                    recovery_codes, master_key, keyring, blob_map, verifier, lockout, srp_identity
+                 FROM users WHERE username = ?1",
+                params![username],
+                |row| {
+                    let recovery_codes: String = row.get(6)?;
+                    let lockout: String = row.get(11)?;
+                    Ok(User {
+                        userhandle: UserHandle::default(),
+                        schema_version: SCHEMA_VERSION,
+                        sessions: vec![],
+                        auth: Auth {
+                            hash: row.get(0)?,
+                            salt: row.get(1)?,
+                            verifier: row.get(10)?,
+                            srp_identity: row.get(12)?,
+                            auth_sessions: vec![],
+                            mfa: MFA {
+                                enabled: row.get(4)?,
+                                methods: vec![],
+                                recovery_codes: parse_json(6, &recovery_codes)?,
+                                min_methods: row.get(5)?,
+                            },
+                            encrypted: EncryptedData {
+                                master_key: row.get(7)?,
+                                keyring: row.get(8)?,
+                                blob_map: row.get(9)?,
+                            },
+                            lockout: parse_json(11, &lockout)?,
+                        },
+                        foreign_keyring: row.get(2)?,
+                        tier: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        user.sessions = connection
+            .prepare("SELECT id, created_at, last_used, root FROM sessions WHERE username = ?1")?
+            .query_map(params![username], |row| {
+                Ok(Session {
+                    id: row.get::<_, String>(0)?.into(),
+                    created_at: row.get::<_, i64>(1)? as u64,
+                    last_used: row.get::<_, i64>(2)? as u64,
+                    root: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        user.auth.auth_sessions = connection
+            .prepare(
+                "SELECT id, expires_at, challenge, completed_mfa, password_correct, srp_secret,
+                    failed_attempts
+                 FROM auth_sessions WHERE username = ?1",
+            )?
+            .query_map(params![username], |row| {
+                Ok(AuthSession {
+                    id: row.get::<_, String>(0)?.into(),
+                    expires_at: row.get::<_, i64>(1)? as u64,
+                    challenge: char_hex_string_to_u128(row.get(2)?),
+                    completed_mfa: row.get(3)?,
+                    password_correct: row.get(4)?,
+                    srp_secret: row.get(5)?,
+                    failed_attempts: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        user.auth.mfa.methods = connection
+            .prepare(
+                "SELECT id, name, enabled, data, type FROM mfa_methods
+                 WHERE username = ?1 ORDER BY rowid",
+            )?
+            .query_map(params![username], |row| {
+                let r#type: String = row.get(4)?;
+                Ok(MFAMethod {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    enabled: row.get(2)?,
+                    data: row.get(3)?,
+                    r#type: serde_json::from_value(serde_json::Value::String(r#type)).map_err(
+                        |e| rusqlite::Error::FromSqlConversionFailure(4, Type::Text, Box::new(e)),
+                    )?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(user))
+    }
+
+    fn write_user(transaction: &Transaction, username: &str, user: &User) -> rusqlite::Result<()> {
+        transaction.execute(
+            "INSERT INTO users (username, hash, salt, foreign_keyring, tier, mfa_enabled,
+                mfa_min_methods, recovery_codes, master_key, keyring, blob_map, verifier, lockout,
+                srp_identity)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+             ON CONFLICT(username) DO UPDATE SET
+                hash = excluded.hash,
+                salt = excluded.salt,
+                foreign_keyring = excluded.foreign_keyring,
+                tier = excluded.tier,
+                mfa_enabled = excluded.mfa_enabled,
+                mfa_min_methods = excluded.mfa_min_methods,
+                recovery_codes = excluded.recovery_codes,
+                master_key = excluded.master_key,
+                keyring = excluded.keyring,
+                blob_map = excluded.blob_map,
+                verifier = excluded.verifier,
+                lockout = excluded.lockout,
+                srp_identity = excluded.srp_identity",
+            params![
+                username,
+                user.auth.hash,
+                user.auth.salt,
+                user.foreign_keyring,
+                user.tier,
+                user.auth.mfa.enabled,
+                user.auth.mfa.min_methods,
+                serde_json::to_string(&user.auth.mfa.recovery_codes).unwrap_or_default(),
+                user.auth.encrypted.master_key,
+                user.auth.encrypted.keyring,
+                user.auth.encrypted.blob_map,
+                user.auth.verifier,
+                serde_json::to_string(&user.auth.lockout).unwrap_or_default(),
+                user.auth.srp_identity,
+            ],
+        )?;
+
+        transaction.execute(
+            "DELETE FROM sessions WHERE username = ?1",
+            params![username],
+        )?;
+        for session in &user.sessions {
+            transaction.execute(
+                "INSERT INTO sessions (username, id, created_at, last_used, root)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    username,
+                    String::from(session.id),
+                    session.created_at as i64,
+                    session.last_used as i64,
+                    session.root,
+                ],
+            )?;
+        }
+
+        transaction.execute(
+            "DELETE FROM auth_sessions WHERE username = ?1",
+            params![username],
+        )?;
+        for auth_session in &user.auth.auth_sessions {
+            transaction.execute(
+                "INSERT INTO auth_sessions (username, id, expires_at, challenge, completed_mfa,
+                    password_correct, srp_secret, failed_attempts)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    username,
+                    String::from(auth_session.id),
+                    auth_session.expires_at as i64,
+                    u128_to_32_char_hex_string(auth_session.challenge),
+                    auth_session.completed_mfa,
+                    auth_session.password_correct,
+                    auth_session.srp_secret,
+                    auth_session.failed_attempts,
+                ],
+            )?;
+        }
+
+        transaction.execute(
+            "DELETE FROM mfa_methods WHERE username = ?1",
+            params![username],
+        )?;
+        for method in &user.auth.mfa.methods {
+            transaction.execute(
+                "INSERT INTO mfa_methods (username, id, name, enabled, data, type)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    username,
+                    method.id,
+                    method.name,
+                    method.enabled,
+                    method.data,
+                    serde_json::to_value(&method.r#type)
+                        .ok()
+                        .and_then(|v| v.as_str().map(String::from))
+                        .unwrap_or_default(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn read_shares(&self, username: &str) -> rusqlite::Result<Vec<Share>> {
+        let connection = self.connection();
+        let mut shares: Vec<Share> = connection
+            .prepare("SELECT id, write, secret FROM shares WHERE username = ?1 ORDER BY rowid")?
+            .query_map(params![username], |row| {
+                Ok(Share {
+                    id: row.get::<_, String>(0)?.into(),
+                    blobs: vec![],
+                    write: row.get(1)?,
+                    secret: row.get::<_, String>(2)?.into(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut statement = connection.prepare(
+            "SELECT blob_id FROM share_blobs
+             WHERE username = ?1 AND share_id = ?2 ORDER BY position",
+        )?;
+        for share in &mut shares {
+            share.blobs = statement
+                .query_map(params![username, String::from(share.id)], |row| {
+                    Ok(BlobID::from(row.get::<_, String>(0)?))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+        }
+        Ok(shares)
+    }
+
+    fn write_shares(&self, username: &str, shares: &[Share]) -> rusqlite::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM shares WHERE username = ?1", params![username])?;
+        for share in shares {
+            let share_id = String::from(share.id);
+            transaction.execute(
+                "INSERT INTO shares (username, id, write, secret) VALUES (?1, ?2, ?3, ?4)",
+                params![username, share_id, share.write, String::from(share.secret)],
+            )?;
+            for (position, blob) in share.blobs.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO share_blobs (username, share_id, position, blob_id)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![username, share_id, position as i64, String::from(*blob)],
+                )?;
+            }
+        }
+        transaction.commit()
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn list_users(&self) -> Result<Vec<String>, Error> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT username FROM users ORDER BY username")
+            .map_err(to_error)?;
+        statement
+            .query_map([], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(to_error)
+    }
+
+    fn user_exists(&self, userhandle: &UserHandle) -> bool {
+        self.connection()
+            .query_row(
+                "SELECT 1 FROM users WHERE username = ?1",
+                params![userhandle.get_local_username()],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|row| row.is_some())
+            .unwrap_or(false)
+    }
+
+    fn load_user(&self, userhandle: &UserHandle) -> Result<User, Error> {
+        match self.read_user(&userhandle.get_local_username()) {
+            Ok(Some(mut user)) => {
+                user.userhandle = userhandle.to_owned();
+                Ok(user)
+            }
+            Ok(None) => Err(Error::new("Could not load user data")),
+            Err(err) => {
+                error!("Error reading user data: {}", err);
+                Err(Error::new("Could not parse user data"))
+            }
+        }
+    }
+
+    fn save_user(&self, user: &User) -> bool {
+        let username = user.userhandle.get_local_username();
+        let mut connection = self.connection();
+        let result = connection.transaction().and_then(|transaction| {
+            Self::write_user(&transaction, &username, user)?;
+            transaction.commit()
+        });
+        match result {
+            Ok(_) => true,
+            Err(err) => {
+                error!("Error saving user data: {}", err);
+                false
+            }
+        }
+    }
+
+    fn load_shares(&self, userhandle: &UserHandle) -> Vec<Share> {
+        self.read_shares(&userhandle.get_local_username())
+            .unwrap_or_else(|err| {
+                error!("Error reading shares: {}", err);
+                vec![]
+            })
+    }
+
+    fn save_shares(&self, userhandle: &UserHandle, shares: &[Share]) -> bool {
+        match self.write_shares(&userhandle.get_local_username(), shares) {
+            Ok(_) => true,
+            Err(err) => {
+                error!("Error saving shares: {}", err);
+                false
+            }
+        }
+    }
+
+    fn check_records(&self, userhandle: &UserHandle) -> Vec<String> {
+        let mut problems = vec![];
+        if let Err(err) = self.load_user(userhandle) {
+            problems.push(format!("user record: {}", err));
+        }
+        if let Err(err) = self.read_shares(&userhandle.get_local_username()) {
+            problems.push(format!("shares: {}", err));
+        }
+        problems
+    }
+
+    fn read_document(&self, userhandle: &UserHandle, name: &str) -> Option<String> {
+        self.connection()
+            .query_row(
+                "SELECT content FROM documents WHERE username = ?1 AND name = ?2",
+                params![userhandle.get_local_username(), name],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap_or_else(|err| {
+                error!("Error reading document {}: {}", name, err);
+                None
+            })
+    }
+
+    fn write_document(&self, userhandle: &UserHandle, name: &str, content: &str) -> bool {
+        match self.connection().execute(
+            "INSERT INTO documents (username, name, content) VALUES (?1, ?2, ?3)
+             ON CONFLICT(username, name) DO UPDATE SET content = excluded.content",
+            params![userhandle.get_local_username(), name, content],
+        ) {
+            Ok(_) => true,
+            Err(err) => {
+                error!("Error saving document {}: {}", name, err);
+                false
+            }
+        }
+    }
+
+    fn list_documents(&self, userhandle: &UserHandle) -> Vec<String> {
+        let connection = self.connection();
+        let names = connection
+            .prepare("SELECT name FROM documents WHERE username = ?1 ORDER BY name")
+            .and_then(|mut statement| {
+                statement
+                    .query_map(params![userhandle.get_local_username()], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()
+            });
+        names.unwrap_or_else(|err| {
+            error!("Error listing documents: {}", err);
+            vec![]
+        })
+    }
+
+    fn list_blobs(&self, userhandle: &UserHandle) -> Result<Vec<BlobID>, Error> {
+        self.blobs.list_blobs(userhandle)
+    }
+
+    fn blob_exists(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        self.blobs.blob_exists(userhandle, id)
+    }
+
+    fn blob_size(&self, userhandle: &UserHandle, id: BlobID) -> Option<u64> {
+        self.blobs.blob_size(userhandle, id)
+    }
+
+    fn read_blob(&self, userhandle: &UserHandle, id: BlobID) -> Result<Vec<u8>, Error> {
+        self.blobs.read_blob(userhandle, id)
+    }
+
+    fn read_blob_range(
+        &self,
+        userhandle: &UserHandle,
+        id: BlobID,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Error> {
+        self.blobs.read_blob_range(userhandle, id, offset, length)
+    }
+
+    fn blob_hash(&self, userhandle: &UserHandle, id: BlobID) -> Result<BlobHash, Error> {
+        self.blobs.blob_hash(userhandle, id)
+    }
+
+    fn write_blob(&self, userhandle: &UserHandle, id: BlobID, content: Vec<u8>) -> bool {
+        self.blobs.write_blob(userhandle, id, content)
+    }
+
+    fn write_blob_from_file(&self, userhandle: &UserHandle, id: BlobID, path: &str) -> bool {
+        self.blobs.write_blob_from_file(userhandle, id, path)
+    }
+
+    fn copy_blob(&self, userhandle: &UserHandle, from: BlobID, to: BlobID) -> bool {
+        self.blobs.copy_blob(userhandle, from, to)
+    }
+
+    fn delete_blob(&self, userhandle: &UserHandle, id: BlobID) -> bool {
+        self.blobs.delete_blob(userhandle, id)
+    }
+
+    fn used_space(&self, userhandle: &UserHandle) -> u64 {
+        let documents: i64 = self
+            .connection()
+            .query_row(
+                "SELECT COALESCE(SUM(LENGTH(CAST(content AS BLOB))), 0) FROM documents
+                 WHERE username = ?1",
+                params![userhandle.get_local_username()],
+                |row| row.get(0),
+            )
+            .unwrap_or_else(|err| {
+                error!("Error summing documents of {}: {}", userhandle, err);
+                0
+            });
+        self.blobs.used_space(userhandle) + documents as u64
+    }
+}
+
+fn to_error(err: rusqlite::Error) -> Error {
+    Error::new(err.to_string().as_str())
+}
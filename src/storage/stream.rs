@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+use sha2::{Digest, Sha256};
+
+use super::StorageBackend;
+use crate::{
+    logger::error::{ERROR_BLOB_NOT_FOUND, Error},
+    user::{UserHandle, blob::BlobID},
+};
+
+/// Size of the pieces a `BlobReader` reads from the backend.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Reads a blob piece by piece through `read_blob_range`, so it never has to fit into memory.
+/// Reading fails if the blob turns out shorter than it was when the reader was created.
+pub struct BlobReader<'a, B: StorageBackend + ?Sized> {
+    backend: &'a B,
+    userhandle: &'a UserHandle,
+    id: BlobID,
+    size: u64,
+    offset: u64,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl<'a, B: StorageBackend + ?Sized> BlobReader<'a, B> {
+    pub fn new(backend: &'a B, userhandle: &'a UserHandle, id: BlobID) -> Result<Self, Error> {
+        let size = backend
+            .blob_size(userhandle, id)
+            .ok_or_else(|| Error::new(ERROR_BLOB_NOT_FOUND))?;
+        Ok(BlobReader {
+            backend,
+            userhandle,
+            id,
+            size,
+            offset: 0,
+            chunk: vec![],
+            position: 0,
+        })
+    }
+
+    /// Number of bytes the reader yields.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<B: StorageBackend + ?Sized> Read for BlobReader<'_, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            if self.offset >= self.size {
+                return Ok(0);
+            }
+            let length = CHUNK_SIZE.min(self.size - self.offset);
+            self.chunk = self
+                .backend
+                .read_blob_range(self.userhandle, self.id, self.offset, length)
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            if self.chunk.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.offset += self.chunk.len() as u64;
+            self.position = 0;
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Passes reads through, computing the SHA-256 of everything read.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the hex encoded hash of what was read.
+    pub fn finish(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Writes everything a reader yields to a new file at `path`, returning the number of bytes.
+pub fn copy_to_file(reader: &mut dyn Read, path: &str) -> io::Result<u64> {
+    let mut file = File::create(path)?;
+    let copied = io::copy(reader, &mut file)?;
+    file.sync_all()?;
+    Ok(copied)
+}
@@ -0,0 +1,231 @@
+use std::fs;
+use std::io::{self, Write};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::file::{
+    create_dir, dir_exists, file_exists, get_file_size, read_dir, read_file, read_file_to_string,
+    remove_dir, write_file, write_file_from_string,
+};
+use crate::{
+    logger::error::{
+        ERROR_BLOB_HASH_NOT_MATCH, ERROR_INVALID_CHUNK, ERROR_TOO_MANY_UPLOADS,
+        ERROR_UPLOAD_INCOMPLETE, ERROR_UPLOAD_NOT_FOUND, Error,
+    },
+    user::{
+        UserHandle,
+        blob::{BlobHash, BlobID},
+    },
+    utils::{current_time, random_u128, u128_to_32_char_hex_string},
+};
+
+/// Largest chunk a client may upload at once.
+pub const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Uploads not touched for this many seconds are removed.
+const UPLOAD_TIMEOUT: u64 = 60 * 60 * 24;
+
+/// Most uploads a user may have open at once.
+pub const MAX_UPLOADS: usize = 8;
+
+/// A blob upload split into numbered chunks, declared up front by its size and hash.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadSession {
+    pub id: String,
+    pub size: u64,
+    pub hash: BlobHash,
+    pub chunk_size: u64,
+    /// Blob replaced on commit, with the hash it must still have. A new blob is created if unset.
+    pub blob: Option<(BlobID, BlobHash)>,
+    pub updated_at: u64,
+}
+
+impl UploadSession {
+    /// Number of chunks the upload consists of.
+    pub fn chunks(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size)
+    }
+
+    /// Expected size of the chunk with the given index.
+    fn chunk_length(&self, index: u64) -> u64 {
+        if index + 1 == self.chunks() {
+            self.size - index * self.chunk_size
+        } else {
+            self.chunk_size
+        }
+    }
+}
+
+/// Upload sessions kept below `<temp_dir>/uploads/<username>/<upload id>/`,
+/// holding a `session.json` and one file per received chunk.
+pub struct UploadStore {
+    dir: String,
+}
+
+impl UploadStore {
+    pub fn new(temp_dir: &str) -> Self {
+        UploadStore {
+            dir: temp_dir.to_string() + "/uploads",
+        }
+    }
+
+    fn resolve_user_dir(&self, userhandle: &UserHandle) -> String {
+        self.dir.to_string() + "/" + userhandle.get_local_username().as_str()
+    }
+
+    fn resolve_upload_path(&self, userhandle: &UserHandle, id: &str, path: &str) -> String {
+        self.resolve_user_dir(userhandle) + "/" + id + "/" + path
+    }
+
+    fn save(&self, userhandle: &UserHandle, session: &UploadSession) -> bool {
+        write_file_from_string(
+            self.resolve_upload_path(userhandle, &session.id, "session.json"),
+            serde_json::to_string(session)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+
+    /// Loads an upload session of a user.
+    pub fn load(&self, userhandle: &UserHandle, id: &str) -> Result<UploadSession, Error> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(ERROR_UPLOAD_NOT_FOUND));
+        }
+        read_file_to_string(self.resolve_upload_path(userhandle, id, "session.json"))
+            .ok()
+            .and_then(|data| serde_json::from_str(data.as_str()).ok())
+            .ok_or_else(|| Error::new(ERROR_UPLOAD_NOT_FOUND))
+    }
+
+    /// Starts a new upload session.
+    pub fn begin(
+        &self,
+        userhandle: &UserHandle,
+        size: u64,
+        hash: BlobHash,
+        chunk_size: u64,
+        blob: Option<(BlobID, BlobHash)>,
+    ) -> Result<UploadSession, Error> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(Error::new(ERROR_INVALID_CHUNK));
+        }
+        if self.list(userhandle).len() >= MAX_UPLOADS {
+            return Err(Error::new(ERROR_TOO_MANY_UPLOADS));
+        }
+        let session = UploadSession {
+            id: u128_to_32_char_hex_string(random_u128()),
+            size,
+            hash,
+            chunk_size,
+            blob,
+            updated_at: current_time(),
+        };
+        let dir = self.resolve_upload_path(userhandle, &session.id, "");
+        if !create_dir(&dir) || !self.save(userhandle, &session) {
+            return Err(Error::new("Could not create upload session"));
+        }
+        Ok(session)
+    }
+
+    /// Stores a chunk, replacing an earlier upload of the same chunk.
+    pub fn write_chunk(
+        &self,
+        userhandle: &UserHandle,
+        id: &str,
+        index: u64,
+        content: Vec<u8>,
+    ) -> Result<UploadSession, Error> {
+        let mut session = self.load(userhandle, id)?;
+        if index >= session.chunks() || content.len() as u64 != session.chunk_length(index) {
+            return Err(Error::new(ERROR_INVALID_CHUNK));
+        }
+        if !write_file(
+            self.resolve_upload_path(userhandle, id, &index.to_string()),
+            content,
+        ) {
+            return Err(Error::new("Could not store chunk"));
+        }
+        session.updated_at = current_time();
+        self.save(userhandle, &session);
+        Ok(session)
+    }
+
+    /// Returns the indices of all chunks received so far.
+    pub fn received_chunks(&self, userhandle: &UserHandle, session: &UploadSession) -> Vec<u64> {
+        (0..session.chunks())
+            .filter(|index| {
+                let path = self.resolve_upload_path(userhandle, &session.id, &index.to_string());
+                file_exists(&path)
+                    && get_file_size(&path).is_ok_and(|size| size == session.chunk_length(*index))
+            })
+            .collect()
+    }
+
+    /// Joins all chunks into a single file inside the upload directory and checks it against
+    /// the declared size and hash, returning the path of the file.
+    pub fn assemble(
+        &self,
+        userhandle: &UserHandle,
+        session: &UploadSession,
+    ) -> Result<String, Error> {
+        if self.received_chunks(userhandle, session).len() as u64 != session.chunks() {
+            return Err(Error::new(ERROR_UPLOAD_INCOMPLETE));
+        }
+        let path = self.resolve_upload_path(userhandle, &session.id, "blob");
+        let hash = self
+            .join_chunks(userhandle, session, &path)
+            .map_err(|e| Error::new(format!("Could not assemble upload: {}", e).as_str()))?;
+        if BlobHash::from(hash) != session.hash {
+            return Err(Error::new(ERROR_BLOB_HASH_NOT_MATCH));
+        }
+        Ok(path)
+    }
+
+    fn join_chunks(
+        &self,
+        userhandle: &UserHandle,
+        session: &UploadSession,
+        path: &str,
+    ) -> io::Result<String> {
+        let mut file = fs::File::create(path)?;
+        let mut hasher = Sha256::new();
+        for index in 0..session.chunks() {
+            let chunk =
+                read_file(self.resolve_upload_path(userhandle, &session.id, &index.to_string()))?;
+            hasher.update(&chunk);
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()?;
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Removes an upload session with all its chunks.
+    pub fn remove(&self, userhandle: &UserHandle, id: &str) -> bool {
+        remove_dir(self.resolve_upload_path(userhandle, id, ""))
+    }
+
+    /// Returns the open upload sessions of a user, removing those that have not been touched
+    /// for a day.
+    pub fn list(&self, userhandle: &UserHandle) -> Vec<UploadSession> {
+        let dir = self.resolve_user_dir(userhandle);
+        if !dir_exists(&dir) {
+            return vec![];
+        }
+        let mut sessions = vec![];
+        for id in read_dir(&dir, false).unwrap_or_default() {
+            match self.load(userhandle, &id) {
+                Ok(session) if session.updated_at + UPLOAD_TIMEOUT >= current_time() => {
+                    sessions.push(session)
+                }
+                _ => {
+                    if !self.remove(userhandle, &id) {
+                        warn!("Could not remove expired upload {} of {}", id, userhandle);
+                    }
+                }
+            }
+        }
+        sessions
+    }
+}
@@ -1,10 +1,58 @@
 use crate::config::load_config;
 use crate::{
-    config::Config,
-    security::verify_challenge_response,
+    config::{Auth, Config, Limit, S3, Tier},
+    logger::error::{
+        ERROR_INVALID_CREDENTIALS, ERROR_QUOTA_EXCEEDED, ERROR_RATE_LIMITED,
+        ERROR_REVISION_MISMATCH, ERROR_TOO_MANY_ATTEMPTS, ERROR_UNAUTHORIZED,
+    },
+    security::{srp_client, srp_verifier, verify_challenge_response},
+    storage::{
+        StorageBackend,
+        archive::{ArchiveWriter, KIND_ACCOUNT, KIND_BACKUP, read_archive},
+        backend,
+        backup::{create_backup, restore_backup},
+        cache::CachedBackend,
+        dedup::DedupBackend,
+        file::FileBackend,
+        lock::UserLock,
+        migration::migrate,
+        s3::S3Backend,
+        scrub::{Problem, quarantined, scrub},
+        sqlite::SqliteBackend,
+        stream::{BlobReader, HashingReader},
+        upload::{MAX_UPLOADS, UploadStore},
+    },
+    user::{
+        MFAMethodType, User, UserHandle,
+        blob::{BlobHash, BlobID, IfMatch, Share, ShareID, ShareSecret, base64_encode},
+        changes::{CHANGES_DOCUMENT, ChangeKind, MAX_CHANGES_LIMIT},
+        lockout::{Lockout, Throttle},
+        metadata::MAX_TAGS_LENGTH,
+        schema::{SCHEMA_VERSION, upgrade_all},
+        trash::TRASH_DOCUMENT,
+        webauthn::{WebAuthnAssertion, WebAuthnData},
+    },
     utils::{random_u128, u128_to_32_char_hex_string},
+    web::{
+        Request, Response,
+        auth::handle_auth,
+        blob::{etag_matches, handle_blob, handle_raw_write, parse_if_match, parse_range},
+        rate_limit::{TokenBucket, limit_action, rate_limit},
+        registration::handle_registration,
+    },
 };
+use actix_web::{
+    App, HttpRequest, HttpResponse,
+    http::StatusCode,
+    middleware::from_fn,
+    test::{TestRequest, call_service, init_service},
+    web,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Read;
 use std::path::Path;
+use std::sync::OnceLock;
 
 fn root_dir() -> String {
     let dir = std::env::temp_dir().join("synxit_test_storage");
@@ -13,6 +61,24 @@ fn root_dir() -> String {
 
 fn setup_config() -> (Config, String) {
     let mut config = Config::default();
+    config.tiers.push(Tier {
+        id: "small".to_string(),
+        name: "Small".to_string(),
+        description: "Tier with a tiny quota".to_string(),
+        quota: 100_000,
+        versions: 2,
+        rate_limit: None,
+    });
+    config.tiers.push(Tier {
+        id: "throttled".to_string(),
+        name: "Throttled".to_string(),
+        description: "Tier allowing a single request per hour".to_string(),
+        quota: 100_000,
+        versions: 2,
+        rate_limit: Some(Limit::new(1, 3600)),
+    });
+    // A failed login holds off the next one long enough for a test to see it
+    config.auth.backoff_base = 60;
     let root_dir = root_dir();
     config.storage.data_dir = root_dir.to_string() + "/data";
     config.storage.log_dir = root_dir.to_string() + "/logs";
@@ -26,6 +92,74 @@ fn setup_config() -> (Config, String) {
     )
 }
 
+/// Loads the test configuration once for the whole test run, so tests can use the global
+/// backend, which then stores below `root_dir`.
+fn test_config() -> &'static Config {
+    static TEST_CONFIG: OnceLock<Config> = OnceLock::new();
+    TEST_CONFIG.get_or_init(|| {
+        delete_test_storage();
+        setup_config().0
+    })
+}
+
+/// Creates and saves a user in the global backend of the test configuration.
+fn test_user(username: &str, tier: &str) -> User {
+    test_config();
+    let mut user = User::new(
+        UserHandle::from_string(format!("@{}:localhost", username)).unwrap(),
+        "",
+        "",
+    );
+    user.tier = tier.to_string();
+    assert!(user.save());
+    user
+}
+
+/// Creates a test user with a session and returns it with the fields authenticating requests.
+fn test_session(username: &str) -> (User, serde_json::Value) {
+    let mut user = test_user(username, "");
+    let session = user.create_session();
+    assert!(user.save());
+    let auth = json!({
+        "userhandle": user.userhandle.to_string(),
+        "session": String::from(session),
+    });
+    (user, auth)
+}
+
+/// Runs an action through a request handler and returns the JSON it responds with.
+fn call(
+    handler: fn(Request) -> Response,
+    action: &str,
+    auth: &serde_json::Value,
+    data: serde_json::Value,
+) -> serde_json::Value {
+    let mut data = data;
+    if let (Some(data), Some(auth)) = (data.as_object_mut(), auth.as_object()) {
+        data.extend(auth.to_owned());
+    }
+    let request = Request::parse(json!({ "action": action, "data": data }).to_string());
+    serde_json::from_str(&handler(request).to_string()).unwrap()
+}
+
+/// Prepares an auth session for the user and returns the fields proving `password` over SRP,
+/// with the server proof expected in return.
+fn srp_proof(auth: &serde_json::Value, password: &str) -> (serde_json::Value, String) {
+    let prepared = call(handle_auth, "prepare", auth, json!({}))["data"].to_owned();
+    let (client_public, proof, server_proof) = srp_client(
+        prepared["srp_identity"].as_str().unwrap(),
+        prepared["salt"].as_str().unwrap(),
+        password,
+        prepared["srp_b"].as_str().unwrap(),
+    );
+    let fields = json!({
+        "auth_session": prepared["auth_session"],
+        "srp_a": client_public,
+        "srp_m1": proof,
+    });
+    (fields, server_proof)
+}
+
 fn delete_test_storage() {
     let root_dir = root_dir();
     if std::path::Path::new(&root_dir).exists() {
@@ -57,12 +191,1888 @@ fn check_login_challenge_works() {
     ));
 }
 
+#[test]
+fn srp_login_replaces_legacy_hash() {
+    let mut user = User::new(
+        UserHandle::from_string("@srp:localhost".to_string()).unwrap(),
+        "hash",
+        "salt",
+    );
+    // Logging in with the legacy scheme converts the hash into a verifier
+    let auth_session = user.create_auth_session();
+    let challenge = user.get_auth_session_by_id(auth_session).unwrap().challenge;
+    let response = sha256::digest(u128_to_32_char_hex_string(challenge) + "hash");
+    assert!(user.check_password_for_auth_session(auth_session, &response));
+    assert_eq!(user.auth.hash, "");
+    assert!(!user.auth.verifier.is_empty());
+    let auth_session = user.create_auth_session();
+    assert!(!user.check_password_for_auth_session(auth_session, &response));
+
+    let auth_session = user.create_auth_session();
+    let server_public = user.srp_server_public(auth_session).unwrap();
+    let (client_public, proof, _) = srp_client("srp", "salt", "wrong", &server_public);
+    assert!(
+        user.check_srp_for_auth_session(auth_session, &client_public, &proof)
+            .is_none()
+    );
+    // The server ephemeral is gone after one attempt
+    let (client_public, proof, server_proof) = srp_client("srp", "salt", "hash", &server_public);
+    assert!(
+        user.check_srp_for_auth_session(auth_session, &client_public, &proof)
+            .is_none()
+    );
+
+    let auth_session = user.create_auth_session();
+    let server_public = user.srp_server_public(auth_session).unwrap();
+    let (client_public, proof, server_proof_expected) =
+        srp_client("srp", "salt", "hash", &server_public);
+    assert_ne!(server_proof, server_proof_expected);
+    assert_eq!(
+        user.check_srp_for_auth_session(auth_session, &client_public, &proof),
+        Some(server_proof_expected)
+    );
+    assert!(
+        user.get_auth_session_by_id(auth_session)
+            .unwrap()
+            .password_correct
+    );
+}
+
+#[test]
+fn failed_logins_back_off_and_lock_out() {
+    let config = Auth::default();
+    let now = crate::utils::current_time();
+    let mut throttle = Throttle::default();
+    assert_eq!(throttle.blocked_until(&config), None);
+    for _ in 0..3 {
+        assert!(!throttle.record_failure(&config, 4));
+    }
+    assert_eq!(
+        throttle.blocked_until(&config),
+        Some(throttle.last_failure + 4)
+    );
+    assert!(throttle.record_failure(&config, 4));
+    assert!(throttle.is_locked());
+    assert!(throttle.blocked_until(&config).unwrap() >= now + config.lockout_duration);
+    // Failures older than a lockout are forgotten
+    let mut throttle = Throttle {
+        failed_attempts: 3,
+        last_failure: now - config.lockout_duration - 1,
+        locked_until: 0,
+    };
+    assert_eq!(throttle.blocked_until(&config), None);
+    assert!(!throttle.record_failure(&config, 4));
+    assert_eq!(throttle.failed_attempts, 1);
+
+    let mut user = User::new(
+        UserHandle::from_string("@lockout:localhost".to_string()).unwrap(),
+        "",
+        "",
+    );
+    let auth_session = user.create_auth_session();
+    for _ in 0..config.max_session_attempts {
+        assert!(user.get_auth_session_by_id(auth_session).is_ok());
+        user.record_failed_login(auth_session);
+    }
+    assert!(user.get_auth_session_by_id(auth_session).is_err());
+    assert!(user.login_blocked_until().is_some());
+    for _ in config.max_session_attempts..config.max_failed_attempts {
+        user.record_failed_login(auth_session);
+    }
+    assert!(user.auth.lockout.throttle.is_locked());
+
+    let lockout = user.take_lockout();
+    assert_eq!(lockout.failures_since_login, config.max_failed_attempts);
+    assert_eq!(lockout.lockouts_since_login, 1);
+    assert_eq!(user.login_blocked_until(), None);
+    user.record_failed_login(auth_session);
+    assert!(!user.unlock());
+}
+
+#[test]
+fn token_bucket_refills_over_period() {
+    let limit = Limit::new(3, 60);
+    let now = std::time::Instant::now();
+    let mut bucket = TokenBucket::new(limit, now);
+    for _ in 0..3 {
+        assert_eq!(bucket.take(limit, now), Ok(()));
+    }
+    assert_eq!(bucket.take(limit, now), Err(20));
+    let later = now + std::time::Duration::from_secs(15);
+    assert_eq!(bucket.take(limit, later), Err(5));
+    let later = now + std::time::Duration::from_secs(20);
+    assert_eq!(bucket.take(limit, later), Ok(()));
+    // Idle buckets never hold more than the limit
+    let later = now + std::time::Duration::from_secs(60 * 60);
+    for _ in 0..3 {
+        assert_eq!(bucket.take(limit, later), Ok(()));
+    }
+    assert!(bucket.take(limit, later).is_err());
+    // No limit for 0 requests
+    let unlimited = Limit::new(0, 60);
+    let mut bucket = TokenBucket::new(unlimited, now);
+    for _ in 0..10 {
+        assert_eq!(bucket.take(unlimited, now), Ok(()));
+    }
+}
+
+#[actix_web::test]
+async fn rate_limited_requests_get_429_with_retry_after() {
+    test_config();
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(rate_limit))
+            .route("/synxit/account/import", web::post().to(HttpResponse::Ok)),
+    )
+    .await;
+    let import = |ip: &str| {
+        TestRequest::post()
+            .uri("/synxit/account/import")
+            .peer_addr(format!("{}:4000", ip).parse().unwrap())
+            .to_request()
+    };
+
+    // The default configuration allows two account imports per hour and client
+    for _ in 0..2 {
+        let response = call_service(&app, import("192.0.2.25")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = call_service(&app, import("192.0.2.25")).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response
+        .headers()
+        .get("Retry-After")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 1800);
+    // Other clients have buckets of their own
+    let response = call_service(&app, import("192.0.2.26")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn actions_are_rate_limited_per_client() {
+    test_config();
+    let prepare = |ip: &str| {
+        let http = TestRequest::default()
+            .peer_addr(format!("{}:4000", ip).parse().unwrap())
+            .to_http_request();
+        Request::parse(json!({ "action": "prepare", "data": {} }).to_string()).with_ip(&http)
+    };
+
+    // The default configuration allows 30 auth preparations per minute and client
+    for _ in 0..30 {
+        assert!(limit_action("auth", &prepare("192.0.2.27")).is_ok());
+    }
+    assert!(limit_action("auth", &prepare("192.0.2.27")).is_err());
+    assert!(limit_action("auth", &prepare("192.0.2.28")).is_ok());
+    // Actions without a limit of their own are only limited by the middleware
+    assert!(limit_action("blob", &prepare("192.0.2.27")).is_ok());
+}
+
+#[test]
+fn users_are_rate_limited_once_authenticated() {
+    let (_, auth) = test_session("hasty");
+    let mut user =
+        User::load(UserHandle::from_string("@hasty:localhost".to_string()).unwrap()).unwrap();
+    user.tier = "throttled".to_string();
+    assert!(user.save());
+
+    // Requests without a valid session do not use up the user's requests
+    let forged = json!({ "userhandle": auth["userhandle"], "session": "01" });
+    for _ in 0..3 {
+        assert_eq!(
+            call(handle_blob, "list", &forged, json!({}))["data"]["error"],
+            ERROR_UNAUTHORIZED
+        );
+    }
+    assert_eq!(call(handle_blob, "list", &auth, json!({}))["success"], true);
+    let limited = call(handle_blob, "list", &auth, json!({}));
+    assert_eq!(limited["data"]["error"], ERROR_RATE_LIMITED);
+    assert!(limited["data"]["retry_after"].as_u64().unwrap() > 0);
+}
+
 #[test]
 fn load_config_test() {
-    let (config, config_file_path) = setup_config();
+    let config = test_config();
     assert_eq!(config.storage.data_dir, root_dir() + "/data");
     assert_eq!(config.storage.log_dir, root_dir() + "/logs");
     assert_eq!(config.storage.temp_dir, root_dir() + "/temp");
-    std::fs::remove_file(config_file_path).unwrap();
-    delete_test_storage();
+    let defaults = Config::default().rate_limit;
+    assert_eq!(config.rate_limit.ip, defaults.ip);
+    assert_eq!(config.rate_limit.routes, defaults.routes);
+    assert_eq!(config.get_tier("small").unwrap().quota, 100_000);
+}
+
+#[test]
+fn file_backend_roundtrip() {
+    let root_dir = std::env::temp_dir().join("synxit_test_file_backend");
+    let backend = FileBackend::new(root_dir.to_str().unwrap());
+    let userhandle = UserHandle::from_string("@alice:localhost".to_string()).unwrap();
+    let user = User::new(userhandle.to_owned(), "hash", "salt");
+
+    assert!(!backend.user_exists(&userhandle));
+    assert!(backend.save_user(&user));
+    assert!(backend.user_exists(&userhandle));
+    assert_eq!(backend.list_users().unwrap(), vec!["alice".to_string()]);
+    let loaded = backend.load_user(&userhandle).unwrap();
+    assert_eq!(loaded.auth.hash, "hash");
+    assert_eq!(loaded.userhandle.to_string(), userhandle.to_string());
+
+    let blob_id = BlobID::from("0000000000000000000000000000002A".to_string());
+    assert!(!backend.blob_exists(&userhandle, blob_id));
+    assert!(backend.write_blob(&userhandle, blob_id, vec![1, 2, 3]));
+    assert_eq!(
+        backend.read_blob(&userhandle, blob_id).unwrap(),
+        vec![1, 2, 3]
+    );
+
+    let share = Share {
+        id: ShareID::from("01".to_string()),
+        blobs: vec![blob_id],
+        write: false,
+        secret: ShareSecret::from("02".to_string()),
+    };
+    assert!(backend.load_shares(&userhandle).is_empty());
+    assert!(backend.save_shares(&userhandle, &[share]));
+    assert_eq!(backend.load_shares(&userhandle)[0].blobs, vec![blob_id]);
+
+    assert!(backend.delete_blob(&userhandle, blob_id));
+    assert!(!backend.blob_exists(&userhandle, blob_id));
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn sqlite_backend_roundtrip() {
+    let root_dir = std::env::temp_dir().join("synxit_test_sqlite_backend");
+    std::fs::create_dir_all(&root_dir).unwrap();
+    let database = root_dir.join("synxit.db");
+    let backend =
+        SqliteBackend::new(database.to_str().unwrap(), root_dir.to_str().unwrap()).unwrap();
+    let userhandle = UserHandle::from_string("@bob:localhost".to_string()).unwrap();
+    let mut user = User::new(userhandle.to_owned(), "hash", "salt");
+    user.create_session();
+    let auth_session = user.create_auth_session();
+    user.create_mfa(MFAMethodType::TOTP, "phone".to_string())
+        .unwrap();
+    user.generate_recovery_codes();
+
+    assert!(!backend.user_exists(&userhandle));
+    assert!(backend.save_user(&user));
+    assert!(backend.user_exists(&userhandle));
+    assert_eq!(backend.list_users().unwrap(), vec!["bob".to_string()]);
+
+    let loaded = backend.load_user(&userhandle).unwrap();
+    assert_eq!(loaded.auth.hash, "hash");
+    assert_eq!(loaded.sessions.len(), 1);
+    assert_eq!(loaded.sessions[0].id, user.sessions[0].id);
+    assert_eq!(
+        loaded
+            .get_auth_session_by_id(auth_session)
+            .unwrap()
+            .challenge,
+        user.get_auth_session_by_id(auth_session).unwrap().challenge
+    );
+    assert_eq!(loaded.auth.mfa.methods.len(), 1);
+    assert_eq!(
+        loaded.auth.mfa.methods[0].data,
+        user.auth.mfa.methods[0].data
+    );
+    assert_eq!(loaded.auth.mfa.recovery_codes, user.auth.mfa.recovery_codes);
+
+    user.delete_auth_session_by_id(auth_session);
+    user.upgrade_legacy_password();
+    let auth_session = user.create_auth_session();
+    assert!(backend.save_user(&user));
+    let loaded = backend.load_user(&userhandle).unwrap();
+    assert_eq!(loaded.auth.verifier, user.auth.verifier);
+    assert_eq!(
+        loaded.srp_server_public(auth_session),
+        user.srp_server_public(auth_session)
+    );
+    user.delete_auth_session_by_id(auth_session);
+    assert!(backend.save_user(&user));
+    assert!(
+        backend
+            .load_user(&userhandle)
+            .unwrap()
+            .auth
+            .auth_sessions
+            .is_empty()
+    );
+
+    let blob_id = BlobID::from("0000000000000000000000000000002A".to_string());
+    let share = Share {
+        id: ShareID::from("01".to_string()),
+        blobs: vec![blob_id],
+        write: true,
+        secret: ShareSecret::from("02".to_string()),
+    };
+    assert!(backend.save_shares(&userhandle, &[share]));
+    let shares = backend.load_shares(&userhandle);
+    assert_eq!(shares.len(), 1);
+    assert_eq!(shares[0].blobs, vec![blob_id]);
+    assert!(shares[0].write);
+
+    // A corrupt lockout must not read as an account that is not locked
+    rusqlite::Connection::open(&database)
+        .unwrap()
+        .execute(
+            "UPDATE users SET lockout = 'locked' WHERE username = 'bob'",
+            [],
+        )
+        .unwrap();
+    assert!(backend.load_user(&userhandle).is_err());
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+/// Starts a minimal in-memory stand-in for an S3-compatible object store and returns its endpoint.
+fn start_fake_s3() -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        let mut objects: HashMap<String, Vec<u8>> = HashMap::new();
+        for mut request in server.incoming_requests() {
+            let signed = request.headers().iter().any(|h| {
+                h.field.equiv("Authorization")
+                    && h.value
+                        .as_str()
+                        .starts_with("AWS4-HMAC-SHA256 Credential=test/")
+            });
+            let key = request.url().to_string();
+            let response = if !signed {
+                tiny_http::Response::from_data(vec![]).with_status_code(403)
+            } else {
+                match request.method() {
+                    tiny_http::Method::Put => {
+                        let mut content = vec![];
+                        request.as_reader().read_to_end(&mut content).unwrap();
+                        objects.insert(key, content);
+                        tiny_http::Response::from_data(vec![])
+                    }
+                    tiny_http::Method::Get => match objects.get(&key) {
+                        Some(content) => tiny_http::Response::from_data(content.to_owned()),
+                        None => tiny_http::Response::from_data(vec![]).with_status_code(404),
+                    },
+                    tiny_http::Method::Delete => {
+                        objects.remove(&key);
+                        tiny_http::Response::from_data(vec![]).with_status_code(204)
+                    }
+                    _ => tiny_http::Response::from_data(vec![]).with_status_code(405),
+                }
+            };
+            let _ = request.respond(response);
+        }
+    });
+    endpoint
+}
+
+/// Runs inside the runtime like a request handler, which the blocking client must cope with.
+#[actix_web::test]
+async fn s3_backend_roundtrip() {
+    let root_dir = std::env::temp_dir().join("synxit_test_s3_backend");
+    let config = S3 {
+        endpoint: start_fake_s3(),
+        access_key: "test".to_string(),
+        secret_key: "secret".to_string(),
+        ..Default::default()
+    };
+    let backend = S3Backend::new(root_dir.to_str().unwrap(), &config);
+    let userhandle = UserHandle::from_string("@carol:localhost".to_string()).unwrap();
+    assert!(backend.save_user(&User::new(userhandle.to_owned(), "hash", "salt")));
+
+    let blob_id = BlobID::from("0000000000000000000000000000002A".to_string());
+    assert!(!backend.blob_exists(&userhandle, blob_id));
+    assert!(backend.write_blob(&userhandle, blob_id, vec![1, 2, 3, 4]));
+    assert!(backend.blob_exists(&userhandle, blob_id));
+    assert_eq!(backend.used_space(&userhandle), 4);
+    assert_eq!(
+        backend.read_blob(&userhandle, blob_id).unwrap(),
+        vec![1, 2, 3, 4]
+    );
+    assert!(root_dir.join("users/carol/data.json").is_file());
+
+    assert!(backend.delete_blob(&userhandle, blob_id));
+    assert!(!backend.blob_exists(&userhandle, blob_id));
+    assert_eq!(backend.used_space(&userhandle), 0);
+    assert!(backend.read_blob(&userhandle, blob_id).is_err());
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn migrate_file_to_sqlite() {
+    let root_dir = std::env::temp_dir().join("synxit_test_migration");
+    let source_dir = root_dir.join("source");
+    let target_dir = root_dir.join("target");
+    std::fs::create_dir_all(&target_dir).unwrap();
+    let source = FileBackend::new(source_dir.to_str().unwrap());
+    let target = SqliteBackend::new(
+        target_dir.join("synxit.db").to_str().unwrap(),
+        target_dir.to_str().unwrap(),
+    )
+    .unwrap();
+    let state_path = root_dir.join("state.json");
+    let state_path = state_path.to_str().unwrap();
+
+    let userhandle = UserHandle::from_string("@dave:localhost".to_string()).unwrap();
+    assert!(source.save_user(&User::new(userhandle.to_owned(), "hash", "salt")));
+    let blob_id = BlobID::from("0000000000000000000000000000002A".to_string());
+    assert!(source.write_blob(&userhandle, blob_id, vec![4, 2]));
+    assert!(source.write_document(&userhandle, "versions.json", "{}"));
+
+    let report = migrate(&source, &target, state_path);
+    assert!(report.failures.is_empty());
+    assert_eq!(report.migrated_users, 1);
+    assert_eq!(report.copied_blobs, 1);
+    assert_eq!(target.load_user(&userhandle).unwrap().auth.hash, "hash");
+    assert_eq!(target.read_blob(&userhandle, blob_id).unwrap(), vec![4, 2]);
+    assert_eq!(target.list_documents(&userhandle), vec!["versions.json"]);
+
+    let report = migrate(&source, &target, state_path);
+    assert_eq!(report.migrated_users, 0);
+    assert_eq!(report.resumed_users, 1);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn file_backend_falls_back_to_backup() {
+    let root_dir = std::env::temp_dir().join("synxit_test_file_backup");
+    let backend = FileBackend::new(root_dir.to_str().unwrap());
+    let userhandle = UserHandle::from_string("@erin:localhost".to_string()).unwrap();
+    let mut user = User::new(userhandle.to_owned(), "first", "salt");
+    assert!(backend.save_user(&user));
+    user.auth.hash = "second".to_string();
+    assert!(backend.save_user(&user));
+
+    let user_dir = root_dir.join("users/erin");
+    assert!(user_dir.join("data.json.bak").is_file());
+    assert!(std::fs::read_dir(&user_dir).unwrap().all(|entry| {
+        !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")
+    }));
+    assert_eq!(backend.load_user(&userhandle).unwrap().auth.hash, "second");
+
+    std::fs::write(user_dir.join("data.json"), "{\"sessions\": [").unwrap();
+    assert_eq!(backend.load_user(&userhandle).unwrap().auth.hash, "first");
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn user_lock_serializes_and_is_reentrant() {
+    let root_dir = std::env::temp_dir().join("synxit_test_user_lock");
+    let dir = root_dir.to_str().unwrap().to_string();
+    let counter = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let dir = dir.to_string();
+            let counter = counter.clone();
+            std::thread::spawn(move || {
+                let _lock = UserLock::acquire_in(&dir, "frank").unwrap();
+                let _nested = UserLock::acquire_in(&dir, "frank").unwrap();
+                counter.lock().unwrap().push(i);
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                counter.lock().unwrap().push(i);
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let order = counter.lock().unwrap();
+    assert_eq!(order.len(), 8);
+    for pair in order.chunks(2) {
+        assert_eq!(pair[0], pair[1]);
+    }
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn cached_backend_tracks_users_and_quota() {
+    let root_dir = std::env::temp_dir().join("synxit_test_cached_backend");
+    let data_dir = root_dir.to_str().unwrap();
+    let generation_dir = data_dir.to_string() + "/locks";
+    let backend = CachedBackend::new(Box::new(FileBackend::new(data_dir)), &generation_dir, true);
+    // Another process, such as a CLI command, writing without a cache of its own
+    let other = CachedBackend::new(Box::new(FileBackend::new(data_dir)), &generation_dir, false);
+    let userhandle = UserHandle::from_string("@grace:localhost".to_string()).unwrap();
+    assert!(backend.save_user(&User::new(userhandle.to_owned(), "first", "salt")));
+    assert_eq!(backend.load_user(&userhandle).unwrap().auth.hash, "first");
+
+    let mut user = other.load_user(&userhandle).unwrap();
+    user.auth.hash = "changed".to_string();
+    assert!(other.save_user(&user));
+    assert_eq!(backend.load_user(&userhandle).unwrap().auth.hash, "changed");
+
+    let used = backend.used_space(&userhandle);
+    let blob_id = BlobID::from("0000000000000000000000000000002A".to_string());
+    assert!(backend.write_blob(&userhandle, blob_id, vec![0; 100]));
+    assert_eq!(backend.used_space(&userhandle), used + 100);
+    assert!(backend.write_blob(&userhandle, blob_id, vec![0; 40]));
+    assert_eq!(backend.used_space(&userhandle), used + 40);
+    assert!(backend.write_document(&userhandle, "notes.json", "[1,2,3]"));
+    assert_eq!(backend.used_space(&userhandle), used + 47);
+    assert_eq!(
+        backend.used_space(&userhandle),
+        FileBackend::new(data_dir).used_space(&userhandle)
+    );
+
+    assert!(other.delete_blob(&userhandle, blob_id));
+    assert_eq!(backend.used_space(&userhandle), used + 7);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn dedup_backend_shares_identical_content() {
+    let root_dir = std::env::temp_dir().join("synxit_test_dedup_backend");
+    let backend = DedupBackend::new(Box::new(CachedBackend::new(
+        Box::new(FileBackend::new(root_dir.to_str().unwrap())),
+        root_dir.join("locks").to_str().unwrap(),
+        true,
+    )));
+    let userhandle = UserHandle::from_string("@heidi:localhost".to_string()).unwrap();
+    assert!(backend.save_user(&User::new(userhandle.to_owned(), "hash", "salt")));
+    let used = backend.used_space(&userhandle);
+
+    let first = BlobID::from("00000000000000000000000000000001".to_string());
+    let second = BlobID::from("00000000000000000000000000000002".to_string());
+    let hash = BlobHash::hash(vec![7; 100]);
+    assert_eq!(backend.required_space(&userhandle, 100, &hash), 100);
+    assert!(backend.write_blob(&userhandle, first, vec![7; 100]));
+    assert_eq!(backend.required_space(&userhandle, 100, &hash), 0);
+    assert!(backend.write_blob(&userhandle, second, vec![7; 100]));
+    assert_eq!(backend.used_space(&userhandle), used + 100);
+    assert_eq!(
+        backend.read_blob(&userhandle, second).unwrap(),
+        vec![7; 100]
+    );
+    assert_eq!(backend.list_blobs(&userhandle).unwrap().len(), 2);
+
+    assert!(backend.delete_blob(&userhandle, first));
+    assert!(!backend.blob_exists(&userhandle, first));
+    assert_eq!(backend.used_space(&userhandle), used + 100);
+    assert!(backend.write_blob(&userhandle, second, vec![1; 30]));
+    assert_eq!(backend.used_space(&userhandle), used + 30);
+    assert!(backend.delete_blob(&userhandle, second));
+    assert_eq!(backend.used_space(&userhandle), used);
+    assert!(backend.list_blobs(&userhandle).unwrap().is_empty());
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn upload_store_assembles_chunks() {
+    let root_dir = std::env::temp_dir().join("synxit_test_upload");
+    let root = root_dir.to_str().unwrap();
+    let uploads = UploadStore::new(&(root.to_string() + "/temp"));
+    let backend = FileBackend::new(root);
+    let userhandle = UserHandle::from_string("@ivan:localhost".to_string()).unwrap();
+    let content: Vec<u8> = (0..250).map(|i| i as u8).collect();
+    let hash = BlobHash::hash(content.to_owned());
+
+    let session = uploads
+        .begin(&userhandle, 250, hash.to_owned(), 100, None)
+        .unwrap();
+    assert_eq!(session.chunks(), 3);
+    assert!(
+        uploads
+            .write_chunk(&userhandle, &session.id, 2, content[..10].to_vec())
+            .is_err()
+    );
+    uploads
+        .write_chunk(&userhandle, &session.id, 2, content[200..].to_vec())
+        .unwrap();
+    uploads
+        .write_chunk(&userhandle, &session.id, 0, content[..100].to_vec())
+        .unwrap();
+    assert!(uploads.assemble(&userhandle, &session).is_err());
+    assert_eq!(uploads.received_chunks(&userhandle, &session), vec![0, 2]);
+
+    let session = uploads.load(&userhandle, &session.id).unwrap();
+    uploads
+        .write_chunk(&userhandle, &session.id, 1, content[100..200].to_vec())
+        .unwrap();
+    let path = uploads.assemble(&userhandle, &session).unwrap();
+    let blob_id = BlobID::from("0000000000000000000000000000000B".to_string());
+    assert!(backend.write_blob_from_file(&userhandle, blob_id, &path));
+    assert_eq!(backend.read_blob(&userhandle, blob_id).unwrap(), content);
+    assert!(uploads.remove(&userhandle, &session.id));
+    assert!(uploads.load(&userhandle, &session.id).is_err());
+
+    for _ in 0..MAX_UPLOADS {
+        uploads
+            .begin(&userhandle, 10, hash.to_owned(), 10, None)
+            .unwrap();
+    }
+    assert_eq!(uploads.list(&userhandle).len(), MAX_UPLOADS);
+    assert!(
+        uploads
+            .begin(&userhandle, 10, hash.to_owned(), 10, None)
+            .is_err()
+    );
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn open_uploads_count_against_quota() {
+    let user = test_user("uploader", "small");
+    let hash = BlobHash::hash(vec![0; 45_000]);
+    let first = user
+        .begin_upload(45_000, hash.to_owned(), 45_000, None)
+        .unwrap();
+    user.begin_upload(45_000, hash.to_owned(), 45_000, None)
+        .unwrap();
+    // The quota is not enough for a third upload staged next to them
+    assert!(
+        user.begin_upload(45_000, hash.to_owned(), 45_000, None)
+            .is_err()
+    );
+    user.abort_upload(&first.id).unwrap();
+    user.begin_upload(45_000, hash.to_owned(), 45_000, None)
+        .unwrap();
+}
+
+#[test]
+fn range_headers_are_parsed_and_clamped() {
+    for (range, size, expected) in [
+        (None, 100, Ok(None)),
+        (Some("items=0-1"), 100, Ok(None)),
+        (Some("bytes=0-1,5-6"), 100, Ok(None)),
+        (Some("bytes=0-49"), 100, Ok(Some((0, 49)))),
+        (Some("bytes=10-"), 100, Ok(Some((10, 99)))),
+        (Some("bytes=-30"), 100, Ok(Some((70, 99)))),
+        (Some("bytes=-300"), 100, Ok(Some((0, 99)))),
+        (Some("bytes=90-500"), 100, Ok(Some((90, 99)))),
+        (Some("bytes=99-99"), 100, Ok(Some((99, 99)))),
+        (Some("bytes=-0"), 100, Err(())),
+        (Some("bytes=100-"), 100, Err(())),
+        (Some("bytes=50-40"), 100, Err(())),
+        (Some("bytes=0-0"), 0, Err(())),
+        (Some("bytes=-5"), 0, Err(())),
+        (Some("bytes=a-b"), 100, Err(())),
+        (Some("bytes=5"), 100, Err(())),
+    ] {
+        assert_eq!(parse_range(range, size), expected, "{:?}", range);
+    }
+}
+
+#[test]
+fn if_none_match_uses_weak_comparison() {
+    let etag = "\"abc\"";
+    assert!(etag_matches("\"abc\"", etag));
+    assert!(etag_matches("W/\"abc\"", etag));
+    assert!(etag_matches("\"xyz\", W/\"abc\"", etag));
+    assert!(etag_matches(" * ", etag));
+    assert!(!etag_matches("\"xyz\", W/\"abd\"", etag));
+    assert!(!etag_matches("abc", etag));
+}
+
+#[actix_web::test]
+async fn raw_writes_need_a_strong_if_match() {
+    assert_eq!(parse_if_match(None), Ok(IfMatch::Missing));
+    assert_eq!(parse_if_match(Some(" * ")), Ok(IfMatch::Any));
+    assert_eq!(
+        parse_if_match(Some("\"abc\", xyz")),
+        Ok(IfMatch::Hashes(vec![
+            BlobHash::from("abc".to_string()),
+            BlobHash::from("xyz".to_string())
+        ]))
+    );
+    assert!(parse_if_match(Some("\"abc\", W/\"xyz\"")).is_err());
+
+    let (user, auth) = test_session("matcher");
+    let (id, hash) = user.create_blob(base64_encode(b"v0".to_vec())).unwrap();
+    let app = init_service(App::new().route(
+        "/synxit/blob/{id}",
+        web::put().to(
+            |req: HttpRequest, id: web::Path<String>, payload: web::Payload| async move {
+                handle_raw_write(
+                    Request::from_http(&req),
+                    &req,
+                    id.into_inner().into(),
+                    payload,
+                )
+                .await
+            },
+        ),
+    ))
+    .await;
+    let put = |id: BlobID, if_match: &str| {
+        TestRequest::put()
+            .uri(&format!("/synxit/blob/{}", String::from(id)))
+            .insert_header(("X-Synxit-Userhandle", auth["userhandle"].as_str().unwrap()))
+            .insert_header(("X-Synxit-Session", auth["session"].as_str().unwrap()))
+            .insert_header(("If-Match", if_match))
+            .set_payload("v1")
+            .to_request()
+    };
+
+    let weak = format!("W/\"{}\"", String::from(hash.to_owned()));
+    let response = call_service(&app, put(id, &weak)).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let missing = BlobID::from("000000000000000000000000000000FF".to_string());
+    let response = call_service(&app, put(missing, "*")).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = call_service(&app, put(id, "*")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        user.read_blob(id).unwrap().1,
+        BlobHash::hash(b"v1".to_vec())
+    );
+}
+
+#[test]
+fn restoring_the_oldest_version_survives_pruning() {
+    let user = test_user("historian", "small");
+    let (id, v0) = user.create_blob(base64_encode(b"v0".to_vec())).unwrap();
+    let v1 = user
+        .update_blob(id, base64_encode(b"v1".to_vec()), v0.to_owned())
+        .unwrap();
+    let v2 = user
+        .update_blob(id, base64_encode(b"v2".to_vec()), v1)
+        .unwrap();
+    // The tier keeps two versions, so keeping the current content prunes the oldest one
+    let versions = user.list_versions(id).unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].hash, v0);
+    assert_eq!(user.restore_version(id, versions[0].id).unwrap(), v0);
+    assert_eq!(user.read_blob(id).unwrap().1, v0);
+    let versions = user.list_versions(id).unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[1].hash, v2);
+}
+
+#[test]
+fn hidden_blobs_are_rejected_by_blob_actions() {
+    let user = test_user("guardian", "");
+    let (id, v0) = user.create_blob(base64_encode(b"v0".to_vec())).unwrap();
+    let v1 = user
+        .update_blob(id, base64_encode(b"v1".to_vec()), v0.to_owned())
+        .unwrap();
+    let version = user.list_versions(id).unwrap()[0].id;
+    let (other, _) = user.create_blob(base64_encode(b"gone".to_vec())).unwrap();
+    assert!(user.delete_blob(other));
+    let trashed = user.list_trash()[0].stored;
+
+    for hidden in [version, trashed] {
+        assert!(!user.blob_exists(hidden));
+        assert!(user.read_blob(hidden).is_err());
+        assert!(user.get_blob_info(hidden).is_err());
+        assert!(user.get_blob_metadata(hidden).is_err());
+        assert!(
+            user.update_blob(hidden, base64_encode(b"x".to_vec()), v0.to_owned())
+                .is_err()
+        );
+        assert!(user.copy_blob(hidden, None).is_err());
+        assert!(user.list_versions(hidden).is_err());
+        assert!(!user.delete_blob(hidden));
+        assert!(
+            user.put_blob_file(hidden, "/nonexistent", 1, &v0, IfMatch::Missing)
+                .is_err()
+        );
+        assert!(
+            user.begin_upload(1, v0.to_owned(), 1, Some((hidden, v0.to_owned())))
+                .is_err()
+        );
+    }
+    // History and trash are untouched
+    assert_eq!(user.read_version(id, version).unwrap().1, v0);
+    assert_eq!(user.read_blob(id).unwrap().1, v1);
+    user.restore_from_trash(other, false).unwrap();
+    assert!(user.blob_exists(other));
+}
+
+#[test]
+fn deleted_blobs_wait_in_the_trash() {
+    let user = test_user("binman", "");
+    let share_id = ShareID::from("0000000000000000000000000000005A".to_string());
+    assert!(backend().save_shares(
+        &user.userhandle,
+        &[Share {
+            id: share_id,
+            blobs: vec![],
+            write: false,
+            secret: ShareSecret::from("01".to_string()),
+        }],
+    ));
+    let (id, hash) = user.create_blob(base64_encode(b"keep".to_vec())).unwrap();
+    user.add_blob_to_share(share_id, id).unwrap();
+    let used = user.get_used_quota();
+
+    assert!(user.delete_blob(id));
+    assert!(!user.blob_exists(id));
+    assert!(user.get_share_by_id(share_id).unwrap().blobs.is_empty());
+    let trash = user.list_trash();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].id, id);
+    assert_eq!(trash[0].size, 4);
+    assert_eq!(trash[0].shares, vec![share_id]);
+    // Trashed blobs still count against the quota
+    assert!(user.get_used_quota() >= used);
+
+    user.restore_from_trash(id, true).unwrap();
+    assert_eq!(user.read_blob(id).unwrap().1, hash);
+    assert_eq!(user.get_share_by_id(share_id).unwrap().blobs, vec![id]);
+    assert!(user.list_trash().is_empty());
+    assert!(user.restore_from_trash(id, false).is_err());
+
+    assert!(user.delete_blob(id));
+    let (other, _) = user.create_blob(base64_encode(b"other".to_vec())).unwrap();
+    assert!(user.delete_blob(other));
+    assert_eq!(user.empty_trash().unwrap(), 2);
+    assert!(user.list_trash().is_empty());
+    assert!(user.restore_from_trash(id, false).is_err());
+    assert!(backend().list_blobs(&user.userhandle).unwrap().is_empty());
+}
+
+#[test]
+fn expired_trash_is_purged() {
+    let user = test_user("sweeper", "");
+    let (old, _) = user.create_blob(base64_encode(b"old".to_vec())).unwrap();
+    let (new, _) = user.create_blob(base64_encode(b"new".to_vec())).unwrap();
+    assert!(user.delete_blob(old));
+    assert!(user.delete_blob(new));
+    let mut trash = user.list_trash();
+    trash[0].deleted_at -= test_config().storage.trash_retention;
+    assert!(backend().write_document(
+        &user.userhandle,
+        TRASH_DOCUMENT,
+        &serde_json::to_string(&trash).unwrap(),
+    ));
+
+    assert_eq!(user.purge_expired_trash().unwrap(), 1);
+    let trash = user.list_trash();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].id, new);
+    assert_eq!(user.purge_expired_trash().unwrap(), 0);
+    assert!(user.restore_from_trash(old, false).is_err());
+    user.restore_from_trash(new, false).unwrap();
+}
+
+#[test]
+fn blob_metadata_keeps_tags_and_lists_in_pages() {
+    let user = test_user("librarian", "");
+    let mut ids = vec![];
+    for content in ["a", "bb", "ccc", "dddd", "eeeee"] {
+        ids.push(
+            user.create_blob(base64_encode(content.as_bytes().to_vec()))
+                .unwrap()
+                .0,
+        );
+    }
+    ids.sort();
+    // Blobs stored before metadata was kept get it on first access
+    let legacy = BlobID::from("00000000000000000000000000000001".to_string());
+    assert!(backend().write_blob(&user.userhandle, legacy, b"legacy".to_vec()));
+    ids.insert(0, legacy);
+    // Listing leaves recording it to the scrubber
+    let unrecorded = BlobID::from("00000000000000000000000000000002".to_string());
+    assert!(backend().write_blob(&user.userhandle, unrecorded, b"old".to_vec()));
+    ids.insert(1, unrecorded);
+
+    let metadata = user.get_blob_metadata(legacy).unwrap();
+    assert_eq!(metadata.size, 6);
+    assert_eq!(metadata.hash, BlobHash::hash(b"legacy".to_vec()));
+    assert_eq!(metadata.tags, None);
+
+    let tagged = ids[3];
+    user.set_blob_tags(tagged, Some("encrypted tags".to_string()))
+        .unwrap();
+    assert!(
+        user.set_blob_tags(tagged, Some("x".repeat(MAX_TAGS_LENGTH + 1)))
+            .is_err()
+    );
+    let before = user.get_blob_metadata(tagged).unwrap();
+    let hash = user
+        .update_blob(tagged, base64_encode(b"new".to_vec()), before.hash)
+        .unwrap();
+    let after = user.get_blob_metadata(tagged).unwrap();
+    assert_eq!(after.tags.as_deref(), Some("encrypted tags"));
+    assert_eq!(after.hash, hash);
+    assert_eq!(after.size, 3);
+    assert_eq!(after.created_at, before.created_at);
+
+    let mut listed = vec![];
+    let mut cursor = None;
+    loop {
+        let (blobs, next) = user.list_blobs(cursor, 4).unwrap();
+        assert!(blobs.len() <= 4);
+        for (id, metadata) in &blobs {
+            assert_eq!(metadata.is_none(), *id == unrecorded);
+        }
+        listed.extend(blobs.iter().map(|(id, _)| *id));
+        match next {
+            Some(next) => {
+                assert_eq!(Some(next), listed.last().copied());
+                cursor = Some(next);
+            }
+            None => break,
+        }
+    }
+    // Versions of the updated blob are left out
+    assert_eq!(listed, ids);
+    let (blobs, next) = user.list_blobs(Some(ids[5]), 0).unwrap();
+    assert_eq!(blobs.len(), 1);
+    assert_eq!(blobs[0].0, ids[6]);
+    assert_eq!(next, None);
+    assert!(user.list_blobs(Some(ids[6]), 10).unwrap().0.is_empty());
+}
+
+#[test]
+fn change_journal_pages_and_compacts() {
+    let user = test_user("journalist", "");
+    let page = user.changes_since(0, 10);
+    assert!(page.changes.is_empty() && !page.more && !page.reset);
+    assert_eq!(page.cursor, 0);
+
+    let (id, hash) = user.create_blob(base64_encode(b"one".to_vec())).unwrap();
+    user.record_blob_map_change();
+    assert!(user.delete_blob(id));
+    let page = user.changes_since(0, 2);
+    assert_eq!(page.changes.len(), 2);
+    assert_eq!(page.changes[0].kind, ChangeKind::BlobCreated);
+    assert_eq!(page.changes[0].blob, Some(id));
+    assert_eq!(page.changes[0].hash, Some(hash));
+    assert_eq!(page.changes[1].kind, ChangeKind::BlobMapChanged);
+    assert!(page.more && !page.reset);
+    let page = user.changes_since(page.cursor, 2);
+    assert_eq!(page.changes.len(), 1);
+    assert_eq!(page.changes[0].kind, ChangeKind::BlobDeleted);
+    assert!(!page.more);
+    assert_eq!(user.changes_since(page.cursor, 2).changes.len(), 0);
+    // Cursors from the future mean the client synced with another journal
+    assert!(user.changes_since(page.cursor + 1, 2).reset);
+
+    // A full journal of blob map changes and changes to distinct blobs
+    let entries: Vec<serde_json::Value> = (1..=10_000u64)
+        .map(|seq| {
+            let blob = (seq % 2 == 0).then(|| BlobID::from(format!("{:032X}", seq)));
+            json!({
+                "seq": seq,
+                "kind": if blob.is_some() { "blob_updated" } else { "blob_map_changed" },
+                "blob": blob,
+                "share": null,
+                "hash": null,
+                "at": 0,
+            })
+        })
+        .collect();
+    assert!(backend().write_document(
+        &user.userhandle,
+        CHANGES_DOCUMENT,
+        &json!({ "seq": 10_000, "truncated": 0, "entries": entries }).to_string(),
+    ));
+    user.record_blob_map_change();
+
+    // Only the newest blob map change is kept, and then the oldest blob change is dropped
+    assert!(user.changes_since(0, 10).reset);
+    assert!(user.changes_since(1, 10).reset);
+    let mut cursor = 2;
+    let mut changes = vec![];
+    loop {
+        let page = user.changes_since(cursor, MAX_CHANGES_LIMIT);
+        assert!(!page.reset);
+        changes.extend(page.changes);
+        cursor = page.cursor;
+        if !page.more {
+            break;
+        }
+    }
+    assert_eq!(cursor, 10_001);
+    assert_eq!(changes.len(), 5000);
+    assert_eq!(changes[0].seq, 4);
+    assert!(
+        changes[..4999]
+            .iter()
+            .all(|change| change.kind == ChangeKind::BlobUpdated)
+    );
+    assert_eq!(changes[4999].seq, 10_001);
+    assert_eq!(changes[4999].kind, ChangeKind::BlobMapChanged);
+}
+
+#[test]
+fn stale_document_writes_are_rejected_by_revision() {
+    let (_, auth) = test_session("reviser");
+    let first = call(
+        handle_blob,
+        "set_blob_map",
+        &auth,
+        json!({ "blob_map": "one" }),
+    );
+    assert_eq!(first["success"], true);
+    let revision = first["data"]["revision"].as_str().unwrap().to_string();
+    assert_eq!(
+        call(handle_blob, "get_blob_map", &auth, json!({}))["data"]["revision"],
+        revision.as_str()
+    );
+
+    let second = call(
+        handle_blob,
+        "set_blob_map",
+        &auth,
+        json!({ "blob_map": "two", "revision": revision }),
+    );
+    assert_eq!(second["success"], true);
+    // A client still holding the first revision gets the current map to merge with
+    let stale = call(
+        handle_blob,
+        "set_blob_map",
+        &auth,
+        json!({ "blob_map": "three", "revision": revision }),
+    );
+    assert_eq!(stale["success"], false);
+    assert_eq!(stale["data"]["error"], ERROR_REVISION_MISMATCH);
+    assert_eq!(stale["data"]["blob_map"], "two");
+    assert_eq!(stale["data"]["revision"], second["data"]["revision"]);
+    assert_eq!(
+        call(handle_blob, "get_blob_map", &auth, json!({}))["data"]["blob_map"],
+        "two"
+    );
+    // Writes without a revision overwrite unconditionally
+    assert_eq!(
+        call(
+            handle_blob,
+            "set_blob_map",
+            &auth,
+            json!({ "blob_map": "four" })
+        )["success"],
+        true
+    );
+
+    let keyring = call(
+        handle_auth,
+        "set_keyring",
+        &auth,
+        json!({ "keyring": "k1" }),
+    );
+    let stale = call(
+        handle_auth,
+        "set_keyring",
+        &auth,
+        json!({ "keyring": "k2", "revision": "0" }),
+    );
+    assert_eq!(stale["data"]["error"], ERROR_REVISION_MISMATCH);
+    assert_eq!(stale["data"]["keyring"], "k1");
+    assert_eq!(stale["data"]["revision"], keyring["data"]["revision"]);
+    let stale = call(
+        handle_auth,
+        "set_master_key",
+        &auth,
+        json!({ "master_key": "m", "revision": "0" }),
+    );
+    assert_eq!(stale["data"]["error"], ERROR_REVISION_MISMATCH);
+    assert_eq!(stale["data"]["master_key"], "");
+}
+
+#[test]
+fn exported_accounts_import_under_a_new_name() {
+    let mut user = test_user("emigrant", "");
+    user.auth.encrypted.master_key = "master key".to_string();
+    user.auth.encrypted.blob_map = "blob map".to_string();
+    user.foreign_keyring = "foreign keyring".to_string();
+    user.create_session();
+    assert!(user.save());
+    let (id, hash) = user.create_blob(base64_encode(b"moving".to_vec())).unwrap();
+    user.set_blob_tags(id, Some("tags".to_string())).unwrap();
+    let share = Share {
+        id: ShareID::from("0000000000000000000000000000005B".to_string()),
+        blobs: vec![id],
+        write: true,
+        secret: ShareSecret::from("02".to_string()),
+    };
+    assert!(backend().save_shares(&user.userhandle, &[share.to_owned()]));
+    let path = root_dir() + "/emigrant.synxit-account.tar.gz";
+    user.export_account(&path).unwrap();
+
+    let userhandle = UserHandle::from_string("@immigrant:localhost".to_string()).unwrap();
+    let imported = User::import_account(userhandle.to_owned(), &path).unwrap();
+    assert_eq!(imported.userhandle.to_string(), userhandle.to_string());
+    let loaded = User::load(userhandle.to_owned()).unwrap();
+    assert_eq!(loaded.auth.encrypted.master_key, "master key");
+    assert_eq!(loaded.auth.encrypted.blob_map, "blob map");
+    assert_eq!(loaded.foreign_keyring, "foreign keyring");
+    // Sessions stay with the server the account was exported from
+    assert!(loaded.sessions.is_empty());
+    assert_eq!(loaded.read_blob(id).unwrap().1, hash);
+    assert_eq!(
+        loaded.get_blob_metadata(id).unwrap().tags.as_deref(),
+        Some("tags")
+    );
+    assert_eq!(loaded.get_share_by_id(share.id).unwrap().blobs, vec![id]);
+    assert_eq!(
+        loaded.changes_since(0, 10).changes.len(),
+        user.changes_since(0, 10).changes.len()
+    );
+
+    assert!(User::import_account(userhandle, &path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn imported_accounts_log_in_with_their_srp_identity() {
+    let mut user = test_user("nomad", "");
+    user.auth.salt = "salt".to_string();
+    user.auth.verifier = srp_verifier("nomad", "salt", "password");
+    // Left over from before the verifier, it must not travel with the account
+    user.auth.hash = "hash".to_string();
+    assert!(user.save());
+    let path = root_dir() + "/nomad.synxit-account.tar.gz";
+    user.export_account(&path).unwrap();
+    read_archive(&path, KIND_ACCOUNT, u64::MAX, |entry, _, content| {
+        if entry == "account.json" {
+            let record: serde_json::Value = serde_json::from_reader(content).unwrap();
+            assert!(record.get("hash").is_none());
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    let userhandle = UserHandle::from_string("@settler:localhost".to_string()).unwrap();
+    User::import_account(userhandle.to_owned(), &path).unwrap();
+    assert_eq!(User::load(userhandle.to_owned()).unwrap().auth.hash, "");
+    let auth = json!({ "userhandle": userhandle.to_string() });
+    let (proof, server_proof) = srp_proof(&auth, "password");
+    let login = call(handle_auth, "auth", &auth, proof);
+    assert_eq!(login["success"], true);
+    assert_eq!(login["data"]["srp_m2"], server_proof);
+    assert_eq!(
+        call(handle_auth, "prepare", &auth, json!({}))["data"]["srp_identity"],
+        "nomad"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn changing_the_password_needs_a_fresh_proof() {
+    let (mut user, auth) = test_session("changer");
+    user.auth.salt = "salt".to_string();
+    user.auth.verifier = srp_verifier("changer", "salt", "old");
+    assert!(user.save());
+    let new_password = json!({
+        "verifier": srp_verifier("changer", "new salt", "new"),
+        "salt": "new salt",
+        "master_key": "new master key",
+    });
+    let change = |proof: serde_json::Value| {
+        let mut data = new_password.to_owned();
+        data.as_object_mut()
+            .unwrap()
+            .extend(proof.as_object().unwrap().to_owned());
+        call(handle_auth, "change_password", &auth, data)
+    };
+
+    let forgive = || {
+        let mut user = User::load(user.userhandle.to_owned()).unwrap();
+        user.auth.lockout = Lockout::default();
+        assert!(user.save());
+    };
+
+    // A session alone does not prove the password
+    assert_eq!(
+        change(json!({}))["data"]["error"],
+        ERROR_INVALID_CREDENTIALS
+    );
+    forgive();
+    let (wrong, _) = srp_proof(&auth, "wrong");
+    assert_eq!(change(wrong)["data"]["error"], ERROR_INVALID_CREDENTIALS);
+    // Failures hold off further attempts like failed logins
+    let (proof, _) = srp_proof(&auth, "old");
+    assert_eq!(change(proof)["data"]["error"], ERROR_TOO_MANY_ATTEMPTS);
+    forgive();
+    let (proof, _) = srp_proof(&auth, "old");
+    assert_eq!(change(proof.to_owned())["success"], true);
+
+    let changed = User::load(user.userhandle.to_owned()).unwrap();
+    assert_eq!(changed.auth.salt, "new salt");
+    assert_eq!(changed.auth.encrypted.master_key, "new master key");
+    let (login, _) = srp_proof(&auth, "new");
+    assert_eq!(call(handle_auth, "auth", &auth, login)["success"], true);
+    // Every proof is good for one change only
+    assert_eq!(change(proof)["success"], false);
+}
+
+#[test]
+fn a_verifier_replaces_the_password_hash() {
+    test_config();
+    let userhandle = UserHandle::from_string("@both:localhost".to_string()).unwrap();
+    let registered = call(
+        handle_registration,
+        "",
+        &json!({ "userhandle": userhandle.to_string() }),
+        json!({
+            "password": "hash",
+            "verifier": srp_verifier("both", "salt", "password"),
+            "salt": "salt",
+        }),
+    );
+    assert_eq!(registered["success"], true);
+    let user = User::load(userhandle).unwrap();
+    assert_eq!(user.auth.hash, "");
+    assert_eq!(user.auth.verifier, srp_verifier("both", "salt", "password"));
+
+    let (mut user, auth) = test_session("changeboth");
+    user.auth.salt = "salt".to_string();
+    user.auth.verifier = srp_verifier("changeboth", "salt", "password");
+    assert!(user.save());
+    let (proof, _) = srp_proof(&auth, "password");
+    let mut data = json!({
+        "password": "new hash",
+        "verifier": srp_verifier("both", "new salt", "new"),
+        "salt": "new salt",
+    });
+    data.as_object_mut()
+        .unwrap()
+        .extend(proof.as_object().unwrap().to_owned());
+    assert_eq!(
+        call(handle_auth, "change_password", &auth, data)["success"],
+        true
+    );
+    assert_eq!(User::load(user.userhandle).unwrap().auth.hash, "");
+}
+
+#[test]
+fn only_wrong_credentials_count_as_failed_logins() {
+    let (mut user, auth) = test_session("mistaken");
+    user.auth.salt = "salt".to_string();
+    user.auth.verifier = srp_verifier("mistaken", "salt", "password");
+    assert!(user.save());
+    let failures = || {
+        User::load(user.userhandle.to_owned())
+            .unwrap()
+            .auth
+            .lockout
+            .failures_since_login
+    };
+
+    let unknown = json!({
+        "auth_session": u128_to_32_char_hex_string(1),
+        "mfa_id": 1,
+        "mfa_code": "000000",
+    });
+    assert_eq!(
+        call(handle_auth, "auth_mfa", &auth, unknown)["success"],
+        false
+    );
+    let (proof, _) = srp_proof(&auth, "password");
+    let without_mfa = json!({ "auth_session": proof["auth_session"] });
+    assert_eq!(
+        call(handle_auth, "auth_mfa", &auth, without_mfa)["data"]["error"],
+        "MFA is not enabled"
+    );
+    assert_eq!(failures(), 0);
+
+    let (wrong, _) = srp_proof(&auth, "wrong");
+    assert_eq!(
+        call(handle_auth, "auth", &auth, wrong)["data"]["error"],
+        ERROR_INVALID_CREDENTIALS
+    );
+    assert_eq!(failures(), 1);
+}
+
+#[test]
+fn backends_read_blob_ranges_and_hashes() {
+    let root_dir = std::env::temp_dir().join("synxit_test_blob_range");
+    let root = root_dir.to_str().unwrap();
+    let userhandle = UserHandle::from_string("@judy:localhost".to_string()).unwrap();
+    let content: Vec<u8> = (0..100).collect();
+    let hash = BlobHash::hash(content.to_owned());
+    let blob_id = BlobID::from("0000000000000000000000000000000C".to_string());
+
+    let file = FileBackend::new(root);
+    let dedup = DedupBackend::new(Box::new(FileBackend::new(root)));
+    for backend in [&file as &dyn StorageBackend, &dedup] {
+        assert!(backend.write_blob(&userhandle, blob_id, content.to_owned()));
+        assert_eq!(
+            backend
+                .read_blob_range(&userhandle, blob_id, 10, 5)
+                .unwrap(),
+            vec![10, 11, 12, 13, 14]
+        );
+        assert_eq!(
+            backend
+                .read_blob_range(&userhandle, blob_id, 95, 50)
+                .unwrap(),
+            content[95..].to_vec()
+        );
+        assert_eq!(backend.blob_hash(&userhandle, blob_id).unwrap(), hash);
+        assert!(backend.delete_blob(&userhandle, blob_id));
+    }
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn file_backend_copies_blobs() {
+    let root_dir = std::env::temp_dir().join("synxit_test_copy_blob");
+    let backend = FileBackend::new(root_dir.to_str().unwrap());
+    let userhandle = UserHandle::from_string("@kim:localhost".to_string()).unwrap();
+    let original = BlobID::from("0000000000000000000000000000000D".to_string());
+    let copy = BlobID::from("0000000000000000000000000000000E".to_string());
+
+    assert!(backend.write_blob(&userhandle, original, vec![1, 2, 3]));
+    assert!(backend.copy_blob(&userhandle, original, copy));
+    assert!(backend.write_blob(&userhandle, original, vec![4]));
+    assert_eq!(backend.read_blob(&userhandle, copy).unwrap(), vec![1, 2, 3]);
+    assert!(backend.copy_blob(&userhandle, original, copy));
+    assert_eq!(backend.read_blob(&userhandle, copy).unwrap(), vec![4]);
+    assert_eq!(backend.list_blobs(&userhandle).unwrap().len(), 2);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn scrub_finds_and_quarantines_corrupt_blobs() {
+    let root_dir = std::env::temp_dir().join("synxit_test_scrub");
+    let data_dir = root_dir.to_str().unwrap();
+    let lock_dir = root_dir.join("locks");
+    let backend = FileBackend::new(data_dir);
+    let userhandle = UserHandle::from_string("@lee:localhost".to_string()).unwrap();
+    let intact = BlobID::from("00000000000000000000000000000001".to_string());
+    let corrupt = BlobID::from("00000000000000000000000000000002".to_string());
+    let missing = BlobID::from("00000000000000000000000000000003".to_string());
+    let legacy = BlobID::from("00000000000000000000000000000004".to_string());
+
+    assert!(backend.save_user(&User::new(userhandle.to_owned(), "hash", "salt")));
+    assert!(backend.write_blob(&userhandle, intact, vec![1]));
+    assert!(backend.write_blob(&userhandle, corrupt, vec![2]));
+    let metadata = serde_json::json!({
+        String::from(intact): { "size": 1, "hash": BlobHash::hash(vec![1]), "created_at": 0, "updated_at": 0 },
+        String::from(corrupt): { "size": 1, "hash": BlobHash::hash(vec![3]), "created_at": 0, "updated_at": 0 },
+    });
+    assert!(backend.write_document(&userhandle, "blobs.json", &metadata.to_string()));
+    let share = Share {
+        id: ShareID::from("01".to_string()),
+        blobs: vec![intact, missing],
+        write: false,
+        secret: ShareSecret::from("02".to_string()),
+    };
+    assert!(backend.save_shares(&userhandle, &[share]));
+    // Blobs stored before metadata was kept get it recorded
+    assert!(backend.write_blob(&userhandle, legacy, vec![4, 4]));
+
+    let report = scrub(&backend, lock_dir.to_str().unwrap(), false);
+    assert_eq!(report.users, 1);
+    assert_eq!(report.verified_blobs, 1);
+    assert_eq!(report.recorded_blobs, 1);
+    let metadata: serde_json::Value =
+        serde_json::from_str(&backend.read_document(&userhandle, "blobs.json").unwrap()).unwrap();
+    assert_eq!(metadata[String::from(legacy)]["size"], 2);
+    assert_eq!(
+        metadata[String::from(legacy)]["hash"],
+        serde_json::json!(BlobHash::hash(vec![4, 4]))
+    );
+    let problems: Vec<Problem> = report.findings.iter().map(|f| f.problem).collect();
+    assert_eq!(
+        problems,
+        vec![Problem::HashMismatch, Problem::MissingSharedBlob]
+    );
+    assert!(backend.blob_exists(&userhandle, corrupt));
+
+    let report = scrub(&backend, lock_dir.to_str().unwrap(), true);
+    assert!(report.findings[0].quarantined);
+    assert!(!backend.blob_exists(&userhandle, corrupt));
+    let entries = quarantined(&backend, &userhandle);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        backend.read_blob(&userhandle, entries[0].stored).unwrap(),
+        vec![2]
+    );
+
+    std::fs::write(root_dir.join("users/lee/shares.json"), "[{").unwrap();
+    let report = scrub(&backend, lock_dir.to_str().unwrap(), true);
+    let problems: Vec<Problem> = report.findings.iter().map(|f| f.problem).collect();
+    assert_eq!(problems, vec![Problem::Record]);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn blobs_are_read_and_hashed_in_pieces() {
+    let root_dir = std::env::temp_dir().join("synxit_test_blob_reader");
+    let backend = FileBackend::new(root_dir.to_str().unwrap());
+    let userhandle = UserHandle::from_string("@reader:localhost".to_string()).unwrap();
+    let id = BlobID::from("00000000000000000000000000000001".to_string());
+    // Spans several pieces, the last one partial
+    let content: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|i| (i % 251) as u8).collect();
+    assert!(backend.write_blob(&userhandle, id, content.to_owned()));
+
+    let mut reader = HashingReader::new(BlobReader::new(&backend, &userhandle, id).unwrap());
+    let mut read = vec![];
+    reader.read_to_end(&mut read).unwrap();
+    assert!(read == content);
+    assert_eq!(
+        BlobHash::from(reader.finish()),
+        BlobHash::hash(content.to_owned())
+    );
+
+    // A blob shrinking while it is read does not pass for complete
+    let mut reader = BlobReader::new(&backend, &userhandle, id).unwrap();
+    assert!(backend.write_blob(&userhandle, id, vec![1]));
+    assert!(reader.read_to_end(&mut vec![]).is_err());
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn backup_restores_into_fresh_data_dir() {
+    let root_dir = std::env::temp_dir().join("synxit_test_backup");
+    let mut config = Config::default();
+    config.storage.data_dir = root_dir.join("data").to_str().unwrap().to_string();
+    let archive = root_dir.join("backup.tar.gz");
+    let archive = archive.to_str().unwrap();
+    let backend = FileBackend::new(&config.storage.data_dir);
+    let userhandle = UserHandle::from_string("@max:localhost".to_string()).unwrap();
+    let blob_id = BlobID::from("0000000000000000000000000000000F".to_string());
+
+    assert!(backend.save_user(&User::new(userhandle.to_owned(), "hash", "salt")));
+    assert!(backend.write_blob(&userhandle, blob_id, vec![1, 2, 3]));
+    assert!(backend.write_document(&userhandle, "blobs.json", "{}"));
+    let lock_dir = root_dir.join("locks");
+    let summary = create_backup(&backend, lock_dir.to_str().unwrap(), &config, archive).unwrap();
+    assert_eq!((summary.users, summary.blobs, summary.documents), (1, 1, 1));
+
+    assert!(backend.write_blob(&userhandle, blob_id, vec![9]));
+    restore_backup(archive, &config.storage).unwrap();
+    assert_eq!(
+        backend.read_blob(&userhandle, blob_id).unwrap(),
+        vec![1, 2, 3]
+    );
+    assert_eq!(backend.load_user(&userhandle).unwrap().auth.hash, "hash");
+    assert_eq!(
+        backend.read_document(&userhandle, "blobs.json").unwrap(),
+        "{}"
+    );
+
+    // A tampered archive is rejected before the data directory is touched
+    let mut bytes = std::fs::read(archive).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xFF;
+    std::fs::write(archive, bytes).unwrap();
+    assert!(restore_backup(archive, &config.storage).is_err());
+    assert_eq!(
+        backend.read_blob(&userhandle, blob_id).unwrap(),
+        vec![1, 2, 3]
+    );
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn archive_rejects_other_kinds_and_unlisted_entries() {
+    let root_dir = std::env::temp_dir().join("synxit_test_archive");
+    std::fs::create_dir_all(&root_dir).unwrap();
+    let path = root_dir.join("account.tar.gz");
+    let path = path.to_str().unwrap();
+
+    let mut archive = ArchiveWriter::create(path, KIND_ACCOUNT).unwrap();
+    archive.add("account.json", b"{}").unwrap();
+    archive.finish().unwrap();
+    let mut entries = vec![];
+    let manifest = read_archive(path, KIND_ACCOUNT, u64::MAX, |entry, size, content| {
+        let mut read = vec![];
+        content.read_to_end(&mut read).unwrap();
+        entries.push((entry.to_string(), size, read));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(manifest.kind, KIND_ACCOUNT);
+    assert_eq!(
+        entries,
+        vec![("account.json".to_string(), 2, b"{}".to_vec())]
+    );
+    // Entries left unread are still checked against the manifest
+    assert!(read_archive(path, KIND_ACCOUNT, u64::MAX, |_, _, _| Ok(())).is_ok());
+    assert!(read_archive(path, KIND_BACKUP, u64::MAX, |_, _, _| Ok(())).is_err());
+
+    // An archive whose manifest does not list every entry is rejected
+    let file = std::fs::File::create(path).unwrap();
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        file,
+        flate2::Compression::default(),
+    ));
+    for (name, content) in [
+        ("account.json", b"{}".as_slice()),
+        (
+            "manifest.json",
+            br#"{"format":1,"kind":"account","created_at":0,"server_version":"","files":{}}"#,
+        ),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, name, content).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+    assert!(read_archive(path, KIND_ACCOUNT, u64::MAX, |_, _, _| Ok(())).is_err());
+
+    // Entries are not unpacked past the limit, whatever they compress to
+    let mut archive = ArchiveWriter::create(path, KIND_ACCOUNT).unwrap();
+    archive.add("blobs/small", &[0; 10]).unwrap();
+    archive.add("blobs/large", &[0; 100_000]).unwrap();
+    archive.finish().unwrap();
+    let mut read = vec![];
+    let result = read_archive(path, KIND_ACCOUNT, 50_000, |entry, _, _| {
+        read.push(entry.to_string());
+        Ok(())
+    });
+    assert_eq!(result.unwrap_err().to_string(), ERROR_QUOTA_EXCEEDED);
+    assert_eq!(read, vec!["blobs/small".to_string()]);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn user_records_are_upgraded_to_current_schema() {
+    let root_dir = std::env::temp_dir().join("synxit_test_schema");
+    let backend = FileBackend::new(root_dir.join("data").to_str().unwrap());
+    let lock_dir = root_dir.join("locks");
+    let userhandle = UserHandle::from_string("@old:localhost".to_string()).unwrap();
+
+    // A record written before schema_version, foreign_keyring and tier existed
+    let old = r#"{
+        "sessions": [{"id": 340282366920938463463374607431768211455, "created_at": 0, "last_used": 0, "root": false}],
+        "auth": {
+            "hash": "hash",
+            "salt": "salt",
+            "auth_sessions": [],
+            "mfa": {"enabled": false, "methods": [], "recovery_codes": ["", "", "", "", "", "", "", ""], "min_methods": 0},
+            "encrypted": {"master_key": "", "keyring": "", "blob_map": ""}
+        }
+    }"#;
+    let user = User::from_json(old).unwrap();
+    assert_eq!(user.schema_version, SCHEMA_VERSION);
+    assert_eq!(user.tier, "");
+    assert_eq!(
+        String::from(user.sessions[0].id),
+        "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"
+    );
+
+    std::fs::create_dir_all(root_dir.join("data/users/old")).unwrap();
+    std::fs::write(root_dir.join("data/users/old/data.json"), old).unwrap();
+    assert_eq!(backend.schema_version(&userhandle).unwrap(), 0);
+    let report = upgrade_all(&backend, lock_dir.to_str().unwrap());
+    assert_eq!((report.upgraded_users, report.failures.len()), (1, 0));
+    assert_eq!(backend.schema_version(&userhandle).unwrap(), SCHEMA_VERSION);
+    let report = upgrade_all(&backend, lock_dir.to_str().unwrap());
+    assert_eq!((report.upgraded_users, report.current_users), (0, 1));
+
+    // Records of a newer server are reported instead of being overwritten
+    let newer = old.replacen(
+        '{',
+        &format!("{{\"schema_version\": {},", SCHEMA_VERSION + 1),
+        1,
+    );
+    assert!(User::from_json(&newer).is_err());
+    std::fs::write(root_dir.join("data/users/old/data.json"), &newer).unwrap();
+    let report = upgrade_all(&backend, lock_dir.to_str().unwrap());
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(
+        std::fs::read_to_string(root_dir.join("data/users/old/data.json")).unwrap(),
+        newer
+    );
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[test]
+fn blobs_move_from_flat_into_sharded_layout() {
+    let root_dir = std::env::temp_dir().join("synxit_test_blob_layout");
+    let backend = FileBackend::new(root_dir.to_str().unwrap());
+    let userhandle = UserHandle::from_string("@max:localhost".to_string()).unwrap();
+    let legacy = BlobID::from("ABCD0000000000000000000000000001".to_string());
+    let sharded = BlobID::from("ABCD0000000000000000000000000002".to_string());
+
+    assert!(backend.write_blob(&userhandle, sharded, vec![2]));
+    assert!(
+        root_dir
+            .join("users/max/blobs/AB/CD/ABCD0000000000000000000000000002")
+            .is_file()
+    );
+    // A blob stored before the sharded layout existed
+    std::fs::write(
+        root_dir.join("users/max/blobs/ABCD0000000000000000000000000001"),
+        [1],
+    )
+    .unwrap();
+
+    assert_eq!(
+        backend.list_blobs(&userhandle).unwrap(),
+        vec![legacy, sharded]
+    );
+    assert_eq!(backend.read_blob(&userhandle, legacy).unwrap(), vec![1]);
+    assert_eq!(backend.blob_size(&userhandle, legacy), Some(1));
+    assert_eq!(backend.used_space(&userhandle), 2);
+
+    assert_eq!(backend.relayout_blobs(&userhandle).unwrap(), 1);
+    assert!(
+        !root_dir
+            .join("users/max/blobs/ABCD0000000000000000000000000001")
+            .exists()
+    );
+    assert_eq!(backend.read_blob(&userhandle, legacy).unwrap(), vec![1]);
+    assert_eq!(
+        backend.list_blobs(&userhandle).unwrap(),
+        vec![legacy, sharded]
+    );
+    assert_eq!(backend.relayout_blobs(&userhandle).unwrap(), 0);
+
+    assert!(backend.delete_blob(&userhandle, legacy));
+    assert!(!backend.blob_exists(&userhandle, legacy));
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+/// An ES256 authenticator for localhost.
+struct TestAuthenticator {
+    key: ring::signature::EcdsaKeyPair,
+    rng: ring::rand::SystemRandom,
+    credential_id: Vec<u8>,
+}
+
+impl TestAuthenticator {
+    fn new(credential_id: &str) -> TestAuthenticator {
+        use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair};
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        TestAuthenticator {
+            key: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap(),
+            rng,
+            credential_id: credential_id.as_bytes().to_vec(),
+        }
+    }
+
+    fn encode(data: impl AsRef<[u8]>) -> String {
+        use base64::engine::{Engine, general_purpose::URL_SAFE_NO_PAD};
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    fn cbor(value: ciborium::Value) -> Vec<u8> {
+        let mut bytes = vec![];
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn client_data(ceremony: &str, challenge: &str, origin: &str) -> String {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"{}"}}"#,
+            ceremony, challenge, origin
+        )
+    }
+
+    fn sign(&self, auth_data: &[u8], client_data: &str) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+        let signed = [auth_data, &Sha256::digest(client_data)].concat();
+        self.key.sign(&self.rng, &signed).unwrap().as_ref().to_vec()
+    }
+
+    fn auth_data(flags: u8, sign_count: u32) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+        [
+            &Sha256::digest("localhost")[..],
+            &[flags],
+            &sign_count.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Returns clientDataJSON and a packed self attestation for the challenge.
+    fn register(&self, challenge: &str, origin: &str, flags: u8) -> (String, String) {
+        use ciborium::Value;
+        use ring::signature::KeyPair;
+        let point = self.key.public_key().as_ref();
+        let cose_key = Self::cbor(Value::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), (-7).into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), Value::Bytes(point[1..33].to_vec())),
+            ((-3).into(), Value::Bytes(point[33..].to_vec())),
+        ]));
+        let auth_data = [
+            &Self::auth_data(flags | 0x40, 0)[..],
+            &[0; 16],
+            &(self.credential_id.len() as u16).to_be_bytes(),
+            &self.credential_id,
+            &cose_key,
+        ]
+        .concat();
+        let client_data = Self::client_data("webauthn.create", challenge, origin);
+        let statement = Value::Map(vec![
+            ("alg".into(), (-7).into()),
+            (
+                "sig".into(),
+                Value::Bytes(self.sign(&auth_data, &client_data)),
+            ),
+        ]);
+        let attestation = Self::cbor(Value::Map(vec![
+            ("fmt".into(), "packed".into()),
+            ("attStmt".into(), statement),
+            ("authData".into(), Value::Bytes(auth_data)),
+        ]));
+        (Self::encode(&client_data), Self::encode(attestation))
+    }
+
+    fn assert(&self, challenge: &str, flags: u8, sign_count: u32) -> WebAuthnAssertion {
+        let auth_data = Self::auth_data(flags, sign_count);
+        let client_data = Self::client_data("webauthn.get", challenge, "http://localhost:8044");
+        WebAuthnAssertion {
+            credential_id: Self::encode(&self.credential_id),
+            user_handle: None,
+            client_data_json: Self::encode(&client_data),
+            authenticator_data: Self::encode(&auth_data),
+            signature: Self::encode(self.sign(&auth_data, &client_data)),
+        }
+    }
+}
+
+#[test]
+fn webauthn_registers_and_verifies_credentials() {
+    let authenticator = TestAuthenticator::new("credential");
+    let mut user = User::new(
+        UserHandle::from_string("@keys:localhost".to_string()).unwrap(),
+        "",
+        "",
+    );
+    let method = user
+        .create_mfa(MFAMethodType::WebAuthn, "key".to_string())
+        .unwrap();
+    assert!(!method.enabled);
+    let options = user.webauthn_creation_options(method.id, None).unwrap();
+    assert_eq!(options["rp"]["id"], "localhost");
+    let challenge = options["challenge"].as_str().unwrap();
+
+    let (client_data, attestation) =
+        authenticator.register(challenge, "https://evil.example", 0x01);
+    assert!(
+        user.finish_webauthn_registration(method.id, &client_data, &attestation)
+            .is_err()
+    );
+    let (client_data, attestation) =
+        authenticator.register(challenge, "http://localhost:8044", 0x01);
+    user.finish_webauthn_registration(method.id, &client_data, &attestation)
+        .unwrap();
+    assert!(user.auth.mfa.methods[0].enabled);
+
+    // Assertions are bound to the auth session and must advance the counter
+    let auth_session = user.create_auth_session();
+    let options = user
+        .webauthn_request_options(auth_session, &user.auth.mfa.methods[0])
+        .unwrap();
+    let challenge = options["challenge"].as_str().unwrap();
+    let other_session = user.create_auth_session();
+    assert!(
+        user.check_webauthn_assertion(
+            other_session,
+            method.id,
+            &authenticator.assert(challenge, 0x01, 1)
+        )
+        .is_err()
+    );
+    user.check_webauthn_assertion(
+        auth_session,
+        method.id,
+        &authenticator.assert(challenge, 0x01, 1),
+    )
+    .unwrap();
+    let data = WebAuthnData::from_method(&user.auth.mfa.methods[0]);
+    assert_eq!(data.credential.unwrap().sign_count, 1);
+    assert!(
+        user.check_webauthn_assertion(
+            auth_session,
+            method.id,
+            &authenticator.assert(challenge, 0x01, 1)
+        )
+        .is_err()
+    );
+    assert!(!user.check_mfa(method.id, "000000"));
+    // Second factor credentials cannot sign in on their own
+    assert!(
+        user.check_passkey_for_auth_session(
+            auth_session,
+            &authenticator.assert(challenge, 0x05, 2)
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn passkeys_sign_in_without_password() {
+    let authenticator = TestAuthenticator::new("passkey");
+    let mut user = User::new(
+        UserHandle::from_string("@passkey:localhost".to_string()).unwrap(),
+        "",
+        "",
+    );
+    user.create_mfa(MFAMethodType::TOTP, "totp".to_string())
+        .unwrap();
+    user.auth.mfa.enabled = true;
+    user.auth.mfa.min_methods = 2;
+    let method = user.create_passkey("passkey".to_string()).unwrap();
+    let options = user.webauthn_creation_options(method.id, None).unwrap();
+    assert_eq!(
+        options["authenticatorSelection"]["userVerification"],
+        "required"
+    );
+    let challenge = options["challenge"].as_str().unwrap();
+    let salt = options["extensions"]["prf"]["eval"]["first"]
+        .as_str()
+        .unwrap();
+
+    // Passkeys have to verify the user
+    let (client_data, attestation) =
+        authenticator.register(challenge, "http://localhost:8044", 0x01);
+    assert!(
+        user.finish_webauthn_registration(method.id, &client_data, &attestation)
+            .is_err()
+    );
+    let (client_data, attestation) =
+        authenticator.register(challenge, "http://localhost:8044", 0x05);
+    user.finish_webauthn_registration(method.id, &client_data, &attestation)
+        .unwrap();
+    user.set_passkey_master_key(method.id, "wrapped").unwrap();
+
+    let auth_session = user.create_auth_session();
+    let options = user.passkey_request_options(auth_session, None).unwrap();
+    let credential_id = TestAuthenticator::encode("passkey");
+    assert_eq!(
+        options["extensions"]["prf"]["evalByCredential"][&credential_id]["first"],
+        salt
+    );
+    let challenge = options["challenge"].as_str().unwrap();
+    assert!(
+        user.check_passkey_for_auth_session(
+            auth_session,
+            &authenticator.assert(challenge, 0x01, 0)
+        )
+        .is_err()
+    );
+    let mut assertion = authenticator.assert(challenge, 0x05, 0);
+    assertion.user_handle = Some(TestAuthenticator::encode("@someone:localhost"));
+    assert!(
+        user.check_passkey_for_auth_session(auth_session, &assertion)
+            .is_err()
+    );
+    assertion.user_handle = Some(TestAuthenticator::encode("@passkey:localhost"));
+    assert_eq!(
+        user.check_passkey_for_auth_session(auth_session, &assertion)
+            .unwrap(),
+        "wrapped"
+    );
+    let auth_session = user.get_auth_session_by_id(auth_session).unwrap();
+    assert!(auth_session.password_correct);
+    assert_eq!(auth_session.completed_mfa.len(), 2);
 }
@@ -0,0 +1,251 @@
+use std::io::Read;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    EncryptedData, MFA, User, UserHandle,
+    blob::{BlobID, Share},
+    changes::CHANGES_DOCUMENT,
+    metadata::METADATA_DOCUMENT,
+    trash::TRASH_DOCUMENT,
+    versions::VERSIONS_DOCUMENT,
+};
+use crate::{
+    config::get_config,
+    logger::error::{
+        ERROR_BLOB_WRITE_FAILED, ERROR_INVALID_ARCHIVE, ERROR_QUOTA_EXCEEDED,
+        ERROR_USER_ALREADY_EXISTS, Error,
+    },
+    storage::{
+        archive::{ArchiveWriter, KIND_ACCOUNT, archive_error, read_archive},
+        backend,
+        file::{create_dir, dir_exists, remove_file},
+        lock::UserLock,
+        scrub::QUARANTINE_DOCUMENT,
+        stream::{BlobReader, copy_to_file},
+    },
+    utils::{random_u128, u128_to_32_char_hex_string},
+};
+
+const ACCOUNT: &str = "account.json";
+const SHARES: &str = "shares.json";
+
+/// Room for the account record, shares and manifest on top of the blobs and documents an
+/// imported account may bring within its quota.
+pub const IMPORT_OVERHEAD: u64 = 64 * 1024 * 1024;
+
+/// Documents carried by an account archive. Anything else in an uploaded archive is rejected.
+const DOCUMENTS: [&str; 5] = [
+    METADATA_DOCUMENT,
+    VERSIONS_DOCUMENT,
+    TRASH_DOCUMENT,
+    CHANGES_DOCUMENT,
+    QUARANTINE_DOCUMENT,
+];
+
+/// The part of the user record that moves with an account. Sessions and the tier belong
+/// to the server and stay behind.
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountRecord {
+    /// Only kept for accounts still on the legacy scheme, as it would let anyone log in
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    hash: String,
+    salt: String,
+    /// Missing in archives of accounts exported before SRP
+    #[serde(default)]
+    verifier: String,
+    /// Missing in archives written before accounts kept their SRP identity
+    #[serde(default)]
+    srp_identity: String,
+    mfa: MFA,
+    encrypted: EncryptedData,
+    foreign_keyring: String,
+}
+
+/// Everything in an account archive except the blob contents.
+#[derive(Default)]
+struct AccountContents {
+    record: Option<AccountRecord>,
+    shares: Vec<Share>,
+    documents: Vec<(String, String)>,
+    /// Bytes of the blobs and documents, which count towards the quota
+    used_space: u64,
+}
+
+fn is_blob_id(id: &str) -> bool {
+    id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Reads an account archive, passing every blob to `store_blob`. Archives unpacking to more
+/// than `limit` bytes are rejected with `ERROR_QUOTA_EXCEEDED`.
+fn read_account(
+    path: &str,
+    limit: u64,
+    mut store_blob: impl FnMut(BlobID, &mut dyn Read) -> Result<(), Error>,
+) -> Result<AccountContents, Error> {
+    let mut contents = AccountContents::default();
+    read_archive(path, KIND_ACCOUNT, limit, |entry, size, content| {
+        let invalid = || archive_error(format!("unexpected entry {}", entry));
+        match entry.split_once('/') {
+            None if entry == ACCOUNT => {
+                contents.record = Some(serde_json::from_reader(content).map_err(archive_error)?);
+            }
+            None if entry == SHARES => {
+                contents.shares = serde_json::from_reader(content).map_err(archive_error)?;
+            }
+            Some(("documents", name)) if DOCUMENTS.contains(&name) => {
+                let mut text = String::new();
+                content.read_to_string(&mut text).map_err(|_| invalid())?;
+                contents.used_space += size;
+                contents.documents.push((name.to_string(), text));
+            }
+            Some(("blobs", id)) if is_blob_id(id) => {
+                contents.used_space += size;
+                store_blob(BlobID::from(id.to_string()), content)?;
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    })?;
+    if contents.record.is_none() {
+        return Err(archive_error("account record missing"));
+    }
+    Ok(contents)
+}
+
+impl User {
+    /// Writes the user's account record, shares, documents and blobs to a gzipped tar archive
+    /// at `path`. Everything in it is either client-encrypted or server metadata. The caller
+    /// holds the user's lock.
+    pub fn export_account(&self, path: &str) -> Result<(), Error> {
+        let mut archive = ArchiveWriter::create(path, KIND_ACCOUNT)?;
+        match self.write_account(&mut archive) {
+            Ok(()) => archive.finish(),
+            Err(err) => {
+                archive.abort();
+                Err(err)
+            }
+        }
+    }
+
+    fn write_account(&self, archive: &mut ArchiveWriter) -> Result<(), Error> {
+        let record = AccountRecord {
+            hash: if self.auth.verifier.is_empty() {
+                self.auth.hash.to_string()
+            } else {
+                String::new()
+            },
+            salt: self.auth.salt.to_string(),
+            verifier: self.auth.verifier.to_string(),
+            srp_identity: self.srp_identity(),
+            mfa: self.auth.mfa.to_owned(),
+            encrypted: self.auth.encrypted.to_owned(),
+            foreign_keyring: self.foreign_keyring.to_string(),
+        };
+        archive.add(
+            ACCOUNT,
+            &serde_json::to_vec(&record).map_err(archive_error)?,
+        )?;
+        archive.add(
+            SHARES,
+            &serde_json::to_vec(&backend().load_shares(&self.userhandle)).map_err(archive_error)?,
+        )?;
+        for name in backend().list_documents(&self.userhandle) {
+            if let Some(content) = backend().read_document(&self.userhandle, &name) {
+                archive.add(&("documents/".to_string() + &name), content.as_bytes())?;
+            }
+        }
+        for id in backend().list_blobs(&self.userhandle)? {
+            let content = BlobReader::new(backend(), &self.userhandle, id)?;
+            archive.add_reader(
+                &("blobs/".to_string() + &String::from(id)),
+                content.size(),
+                content,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Creates a new user under `userhandle` from an archive written by `export_account`.
+    /// The account keeps logging in with the SRP identity it was exported with. The archive is verified against its manifest and the quota of the new user before
+    /// anything is stored; the user only exists once all of it was written.
+    pub fn import_account(userhandle: UserHandle, path: &str) -> Result<User, Error> {
+        let _lock = UserLock::acquire(&userhandle)?;
+        if User::user_exists(userhandle.to_owned()) {
+            return Err(Error::new(ERROR_USER_ALREADY_EXISTS));
+        }
+        let mut user = User::new(userhandle, "", "");
+        let limit = user.get_tier_quota().saturating_add(IMPORT_OVERHEAD);
+        let verified = read_account(path, limit, |_, _| Ok(())).map_err(|err| {
+            warn!("Rejected account import for {}: {}", user.userhandle, err);
+            if err.to_string() == ERROR_QUOTA_EXCEEDED {
+                err
+            } else {
+                Error::new(ERROR_INVALID_ARCHIVE)
+            }
+        })?;
+        if verified.used_space > user.get_tier_quota() {
+            return Err(Error::new(ERROR_QUOTA_EXCEEDED));
+        }
+
+        let temp_dir = get_config().storage.temp_dir;
+        if !dir_exists(&temp_dir) && !create_dir(&temp_dir) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        // Blobs are unpacked to a file first and moved into place from there
+        let temp_path = format!(
+            "{}/.import.{}.tmp",
+            temp_dir,
+            u128_to_32_char_hex_string(random_u128())
+        );
+        let mut blobs = vec![];
+        let written = read_account(path, limit, |id, content| {
+            blobs.push(id);
+            let stored = copy_to_file(content, &temp_path).is_ok()
+                && backend().write_blob_from_file(&user.userhandle, id, &temp_path);
+            if stored {
+                Ok(())
+            } else {
+                Err(Error::new(ERROR_BLOB_WRITE_FAILED))
+            }
+        })
+        .and_then(|contents| {
+            let Some(record) = contents.record else {
+                return Err(Error::new(ERROR_INVALID_ARCHIVE));
+            };
+            user.auth.hash = record.hash;
+            user.auth.salt = record.salt;
+            user.auth.verifier = record.verifier;
+            // The verifier still belongs to the name the account was exported under
+            if record.srp_identity != user.userhandle.get_local_username() {
+                user.auth.srp_identity = record.srp_identity;
+            }
+            // Drops a hash that came along with a verifier
+            user.upgrade_legacy_password();
+            user.auth.mfa = record.mfa;
+            user.auth.encrypted = record.encrypted;
+            user.foreign_keyring = record.foreign_keyring;
+            let stored =
+                contents.documents.iter().all(|(name, content)| {
+                    backend().write_document(&user.userhandle, name, content)
+                }) && backend().save_shares(&user.userhandle, &contents.shares)
+                    && user.save();
+            if stored {
+                Ok(())
+            } else {
+                Err(Error::new(ERROR_BLOB_WRITE_FAILED))
+            }
+        });
+        remove_file(&temp_path);
+        if let Err(err) = written {
+            for id in blobs {
+                backend().delete_blob(&user.userhandle, id);
+            }
+            warn!("Could not import account {}: {}", user.userhandle, err);
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        info!("Imported account {}", user.userhandle);
+        Ok(user)
+    }
+}
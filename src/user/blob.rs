@@ -1,14 +1,14 @@
 use crate::{
+    User,
+    config::get_config,
     logger::error::{
-        Error, ERROR_BLOB_HASH_NOT_MATCH, ERROR_BLOB_NOT_FOUND, ERROR_BLOB_NOT_IN_SHARE,
-        ERROR_NO_WRITE_ACCESS, ERROR_QUOTA_EXCEEDED, ERROR_SHARE_NOT_FOUND, ERROR_WRONG_SECRET,
-    },
-    storage::file::{
-        create_dir, dir_exists, file_exists, read_file, read_file_to_string, remove_file,
-        write_file, write_file_from_string,
+        ERROR_BLOB_HASH_NOT_MATCH, ERROR_BLOB_HASH_REQUIRED, ERROR_BLOB_NOT_FOUND,
+        ERROR_BLOB_NOT_IN_SHARE, ERROR_BLOB_WRITE_FAILED, ERROR_NO_WRITE_ACCESS,
+        ERROR_QUOTA_EXCEEDED, ERROR_SHARE_NOT_FOUND, ERROR_WRONG_SECRET, Error,
     },
+    storage::{backend, lock::UserLock},
+    user::changes::ChangeKind,
     utils::{char_hex_string_to_u128, random_u128, u128_to_32_char_hex_string},
-    User,
 };
 
 use serde::{Deserialize, Serialize};
@@ -25,7 +25,7 @@ pub struct Share {
 pub struct ShareSecret(u128);
 #[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq)]
 pub struct ShareID(u128);
-#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct BlobID(u128);
 #[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
 pub struct BlobHash(String);
@@ -34,6 +34,17 @@ pub struct BlobContent(Vec<u8>);
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct Base64(String);
 
+/// What a write expects of the content it replaces, as given in `If-Match`.
+#[derive(Debug, PartialEq)]
+pub enum IfMatch {
+    /// No condition, so the blob must not exist yet
+    Missing,
+    /// `*`, the blob must exist with any content
+    Any,
+    /// The blob must have one of these hashes
+    Hashes(Vec<BlobHash>),
+}
+
 impl From<BlobID> for String {
     fn from(val: BlobID) -> Self {
         u128_to_32_char_hex_string(val.0)
@@ -52,6 +63,18 @@ impl From<String> for ShareID {
     }
 }
 
+impl From<ShareID> for String {
+    fn from(val: ShareID) -> Self {
+        u128_to_32_char_hex_string(val.0)
+    }
+}
+
+impl From<ShareSecret> for String {
+    fn from(val: ShareSecret) -> Self {
+        u128_to_32_char_hex_string(val.0)
+    }
+}
+
 impl From<String> for ShareSecret {
     fn from(val: String) -> Self {
         ShareSecret(char_hex_string_to_u128(val))
@@ -64,6 +87,12 @@ impl From<String> for Base64 {
     }
 }
 
+impl BlobID {
+    pub fn random() -> Self {
+        BlobID(random_u128())
+    }
+}
+
 impl BlobHash {
     pub fn hash(data: Vec<u8>) -> Self {
         BlobHash(sha256::digest(data))
@@ -96,43 +125,69 @@ pub fn base64_decode(data: Base64) -> Result<Vec<u8>, Error> {
 }
 
 impl User {
-    fn create_blob_dir(&self) {
-        if !dir_exists(self.resolve_data_path("blobs/").as_str()) {
-            create_dir(self.resolve_data_path("blobs/").as_str());
-        }
+    pub fn create_blob(&self, content: Base64) -> Result<(BlobID, BlobHash), Error> {
+        self.store_blob(base64_decode(content)?)
     }
 
-    fn resolve_blob_path(&self, id: BlobID) -> String {
-        let string: String = id.into();
-        self.resolve_data_path("blobs/") + string.as_str()
+    /// Stores content under a new blob ID. With deduplication enabled, content
+    /// the user already stores is not charged against the quota again.
+    fn store_blob(&self, data: Vec<u8>) -> Result<(BlobID, BlobHash), Error> {
+        let hash = BlobHash::hash(data.to_owned());
+        self.check_quota(data.len() as u64, &hash)?;
+        let size = data.len() as u64;
+        let id = self.new_blob_id();
+        if !backend().write_blob(&self.userhandle, id, data) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        self.record_blob(id, size, &hash);
+        Ok((id, hash))
     }
 
-    pub fn create_blob(&self, content: Base64) -> Result<(BlobID, BlobHash), Error> {
-        let data = base64_decode(content)?;
-        let available_quota = self.get_available_quota();
-        if available_quota < data.len() as u64 {
-            return Err(Error::new(ERROR_QUOTA_EXCEEDED));
+    /// Returns a random blob ID not used by the user yet.
+    pub(super) fn new_blob_id(&self) -> BlobID {
+        let mut id = BlobID::random();
+        while backend().blob_exists(&self.userhandle, id) {
+            id = BlobID::random();
         }
-        self.create_blob_dir();
-        let mut id = BlobID(random_u128());
-        while file_exists(self.resolve_blob_path(id).as_str()) {
-            id = BlobID(random_u128());
+        id
+    }
+
+    /// Fails if storing content of the given size and hash would exceed the user's quota,
+    /// even after pruning old blob versions.
+    pub(super) fn check_quota(&self, size: u64, hash: &BlobHash) -> Result<(), Error> {
+        if self.make_room(backend().required_space(&self.userhandle, size, hash)) {
+            Ok(())
+        } else {
+            Err(Error::new(ERROR_QUOTA_EXCEEDED))
         }
+    }
 
-        write_file(self.resolve_blob_path(id).as_str(), data.to_owned());
-        Ok((id, BlobHash::hash(data)))
+    /// Copies a blob to a new blob ID, optionally adding the copy to a share.
+    pub fn copy_blob(
+        &self,
+        id: BlobID,
+        share_id: Option<ShareID>,
+    ) -> Result<(BlobID, BlobHash), Error> {
+        if let Some(share_id) = share_id {
+            self.get_share_by_id(share_id)?;
+        }
+        if !self.blob_exists(id) {
+            return Err(Error::new(ERROR_BLOB_NOT_FOUND));
+        }
+        let copy = self.store_blob(backend().read_blob(&self.userhandle, id)?)?;
+        if let Some(share_id) = share_id {
+            self.add_blob_to_share(share_id, copy.0)?;
+        }
+        Ok(copy)
     }
 
     pub fn read_blob(&self, id: BlobID) -> Result<(Base64, BlobHash), Error> {
-        self.create_blob_dir();
-        let path = self.resolve_blob_path(id);
-        if !file_exists(path.as_str()) {
+        if !self.blob_exists(id) {
             return Err(Error::new(ERROR_BLOB_NOT_FOUND));
         }
-        match read_file(path.as_str()) {
-            Ok(content) => Ok((base64_encode(content.to_owned()), BlobHash::hash(content))),
-            Err(_) => Err(Error::new(ERROR_BLOB_NOT_FOUND)),
-        }
+        let hash = self.get_blob_metadata(id)?.hash;
+        let content = backend().read_blob(&self.userhandle, id)?;
+        Ok((base64_encode(content), hash))
     }
 
     pub fn update_blob(
@@ -141,56 +196,97 @@ impl User {
         content: Base64,
         old_hash: BlobHash,
     ) -> Result<BlobHash, Error> {
-        self.create_blob_dir();
-        let path = self.resolve_blob_path(id);
-        if !file_exists(path.as_str()) {
+        if !self.blob_exists(id) {
+            return Err(Error::new(ERROR_BLOB_NOT_FOUND));
+        }
+        if old_hash != self.get_blob_metadata(id)?.hash {
+            return Err(Error::new(ERROR_BLOB_HASH_NOT_MATCH));
+        }
+        let data = base64_decode(content)?;
+        let size = data.len() as u64;
+        let new_hash = BlobHash::hash(data.to_owned());
+        self.prepare_replace(id, size, &new_hash)?;
+        if !backend().write_blob(&self.userhandle, id, data) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        self.record_blob(id, size, &new_hash);
+        Ok(new_hash)
+    }
+
+    /// Returns the size and hash of a blob.
+    pub fn get_blob_info(&self, id: BlobID) -> Result<(u64, BlobHash), Error> {
+        let metadata = self.get_blob_metadata(id)?;
+        Ok((metadata.size, metadata.hash))
+    }
+
+    /// Stores a fully received file as the content of a blob, creating the blob if it does not
+    /// exist. Replacing an existing blob requires the hash it currently has, like `update_blob`,
+    /// or `*`. Returns true if the blob was created.
+    pub fn put_blob_file(
+        &self,
+        id: BlobID,
+        path: &str,
+        size: u64,
+        hash: &BlobHash,
+        if_match: IfMatch,
+    ) -> Result<bool, Error> {
+        if self.hidden_blob_ids().contains(&id) {
             return Err(Error::new(ERROR_BLOB_NOT_FOUND));
         }
-        match read_file(path.as_str()) {
-            Ok(old_content) => {
-                let data = base64_decode(content)?;
-                let hash = BlobHash::hash(old_content);
-                if old_hash != hash {
-                    return Err(Error::new(ERROR_BLOB_HASH_NOT_MATCH));
-                }
-                let available_quota = self.get_available_quota();
-                if available_quota < data.len() as u64 {
-                    return Err(Error::new(ERROR_QUOTA_EXCEEDED));
-                }
-                write_file(path.as_str(), data.to_owned());
-                Ok(BlobHash::hash(data))
+        let exists = backend().blob_exists(&self.userhandle, id);
+        match if_match {
+            IfMatch::Missing if exists => return Err(Error::new(ERROR_BLOB_HASH_REQUIRED)),
+            IfMatch::Any if !exists => return Err(Error::new(ERROR_BLOB_HASH_NOT_MATCH)),
+            IfMatch::Hashes(hashes)
+                if !exists || !hashes.contains(&self.get_blob_metadata(id)?.hash) =>
+            {
+                return Err(Error::new(ERROR_BLOB_HASH_NOT_MATCH));
             }
-            Err(_) => Err(Error::new(ERROR_BLOB_NOT_FOUND)),
+            _ => {}
+        }
+        if exists {
+            self.prepare_replace(id, size, hash)?;
+        } else {
+            self.check_quota(size, hash)?;
         }
+        if !backend().write_blob_from_file(&self.userhandle, id, path) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        self.record_blob(id, size, hash);
+        Ok(!exists)
     }
 
+    /// Deletes a blob, moving it to the trash unless the trash is disabled.
     pub fn delete_blob(&self, id: BlobID) -> bool {
-        self.create_blob_dir();
-        let path = self.resolve_blob_path(id);
-        if !file_exists(path.as_str()) {
+        if !self.blob_exists(id) {
             return false;
         }
-        remove_file(path.as_str());
+        if get_config().storage.trash_retention > 0 {
+            let shares = self
+                .get_share_data()
+                .iter()
+                .filter(|share| share.blobs.contains(&id))
+                .map(|share| share.id)
+                .collect();
+            if self.move_to_trash(id, shares).is_err() {
+                return false;
+            }
+        } else {
+            backend().delete_blob(&self.userhandle, id);
+            self.delete_versions(id);
+            self.forget_blob(id);
+        }
+        self.record_blob_change(ChangeKind::BlobDeleted, id, None);
         let _ = self.delete_shared_blob(id);
         true
     }
 
     fn get_share_data(&self) -> Vec<Share> {
-        serde_json::from_str(
-            read_file_to_string(self.resolve_data_path("shares.json").as_str())
-                .unwrap_or("[]".to_string())
-                .as_str(),
-        )
-        .unwrap_or(vec![])
+        backend().load_shares(&self.userhandle)
     }
 
     fn set_share_data(&self, shares: Vec<Share>) -> bool {
-        write_file_from_string(
-            self.resolve_data_path("shares.json").as_str(),
-            serde_json::to_string(&shares)
-                .unwrap_or("[]".to_string())
-                .as_str(),
-        )
+        backend().save_shares(&self.userhandle, &shares)
     }
 
     pub fn check_share_permissions(
@@ -203,7 +299,7 @@ impl User {
         let share = self.get_share_by_id(id)?;
         if share.secret != secret {
             Err(Error::new(ERROR_WRONG_SECRET))
-        } else if !share.blobs.iter().any(|b| *b == blob_id) {
+        } else if !share.blobs.contains(&blob_id) {
             Err(Error::new(ERROR_BLOB_NOT_IN_SHARE))
         } else if write && !share.write {
             Err(Error::new(ERROR_NO_WRITE_ACCESS))
@@ -222,19 +318,27 @@ impl User {
     }
 
     pub fn delete_shared_blob(&self, blob: BlobID) -> Result<(), Error> {
+        let _lock = UserLock::acquire(&self.userhandle)?;
         let mut shares = self.get_share_data();
+        let mut changed = vec![];
         for share in &mut shares {
-            for i in 0..share.blobs.len() {
-                if share.blobs[i] == blob {
-                    share.blobs.remove(i);
-                }
+            if share.blobs.contains(&blob) {
+                share.blobs.retain(|id| *id != blob);
+                changed.push(share.id);
             }
         }
+        if changed.is_empty() {
+            return Ok(());
+        }
         self.set_share_data(shares);
+        for share in changed {
+            self.record_share_change(share);
+        }
         Ok(())
     }
 
     pub fn add_blob_to_share(&self, share_id: ShareID, blob: BlobID) -> Result<(), Error> {
+        let _lock = UserLock::acquire(&self.userhandle)?;
         let mut shares = self.get_share_data();
         for share in &mut shares {
             if share.id == share_id {
@@ -243,6 +347,7 @@ impl User {
             }
         }
         self.set_share_data(shares);
+        self.record_share_change(share_id);
         Ok(())
     }
 
@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    User,
+    blob::{BlobHash, BlobID, ShareID},
+};
+use crate::{
+    storage::{backend, lock::UserLock},
+    utils::current_time,
+};
+
+pub const CHANGES_DOCUMENT: &str = "changes.json";
+
+/// Journal length that triggers compaction.
+const MAX_JOURNAL_ENTRIES: usize = 10000;
+
+/// Most changes returned by a single `changes_since` call.
+pub const MAX_CHANGES_LIMIT: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    BlobCreated,
+    BlobUpdated,
+    BlobDeleted,
+    ShareChanged,
+    BlobMapChanged,
+}
+
+/// A single entry of the change journal.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub seq: u64,
+    pub kind: ChangeKind,
+    pub blob: Option<BlobID>,
+    pub share: Option<ShareID>,
+    pub hash: Option<BlobHash>,
+    pub at: u64,
+}
+
+impl Change {
+    /// Identifies what the change is about; only the newest change per target survives compaction.
+    fn target(&self) -> String {
+        match (self.blob, self.share) {
+            (Some(blob), _) => "blob:".to_string() + String::from(blob).as_str(),
+            (None, Some(share)) => "share:".to_string() + String::from(share).as_str(),
+            (None, None) => "blob_map".to_string(),
+        }
+    }
+}
+
+/// Per-user journal of changes with monotonically increasing sequence numbers.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Journal {
+    /// Sequence number of the newest change, 0 if nothing changed yet.
+    seq: u64,
+    /// Changes up to this sequence number were dropped from the journal.
+    truncated: u64,
+    entries: Vec<Change>,
+}
+
+impl Journal {
+    /// Drops changes superseded by a newer change to the same target, then the oldest
+    /// changes if the journal is still too long.
+    fn compact(&mut self) {
+        let mut seen = HashSet::new();
+        let mut entries: Vec<Change> = self
+            .entries
+            .drain(..)
+            .rev()
+            .filter(|change| seen.insert(change.target()))
+            .collect();
+        entries.reverse();
+        if entries.len() > MAX_JOURNAL_ENTRIES / 2 {
+            let dropped = entries.len() - MAX_JOURNAL_ENTRIES / 2;
+            self.truncated = entries[dropped - 1].seq;
+            entries.drain(..dropped);
+        }
+        self.entries = entries;
+    }
+}
+
+/// Changes after a cursor, as returned by `changes_since`.
+pub struct ChangePage {
+    pub changes: Vec<Change>,
+    /// Cursor to pass to the next call.
+    pub cursor: u64,
+    /// True if more changes are available right away.
+    pub more: bool,
+    /// True if changes after the given cursor were compacted away and the client has to
+    /// resynchronize everything before continuing from `cursor`.
+    pub reset: bool,
+}
+
+impl User {
+    fn load_journal(&self) -> Journal {
+        serde_json::from_str(
+            backend()
+                .read_document(&self.userhandle, CHANGES_DOCUMENT)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn save_journal(&self, journal: &Journal) -> bool {
+        backend().write_document(
+            &self.userhandle,
+            CHANGES_DOCUMENT,
+            serde_json::to_string(journal)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+
+    fn append_change(
+        &self,
+        kind: ChangeKind,
+        blob: Option<BlobID>,
+        share: Option<ShareID>,
+        hash: Option<BlobHash>,
+    ) {
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return;
+        };
+        let mut journal = self.load_journal();
+        journal.seq += 1;
+        journal.entries.push(Change {
+            seq: journal.seq,
+            kind,
+            blob,
+            share,
+            hash,
+            at: current_time(),
+        });
+        if journal.entries.len() > MAX_JOURNAL_ENTRIES {
+            journal.compact();
+        }
+        if !self.save_journal(&journal) {
+            warn!("Could not record change of {}", self.userhandle);
+        }
+    }
+
+    /// Records a change of a blob's content or existence.
+    pub(super) fn record_blob_change(&self, kind: ChangeKind, id: BlobID, hash: Option<BlobHash>) {
+        self.append_change(kind, Some(id), None, hash);
+    }
+
+    /// Records a change of a share's blobs.
+    pub(super) fn record_share_change(&self, id: ShareID) {
+        self.append_change(ChangeKind::ShareChanged, None, Some(id), None);
+    }
+
+    /// Records a change of the blob map.
+    pub fn record_blob_map_change(&self) {
+        self.append_change(ChangeKind::BlobMapChanged, None, None, None);
+    }
+
+    /// Returns up to `limit` changes with a sequence number above `cursor`, oldest first.
+    pub fn changes_since(&self, cursor: u64, limit: usize) -> ChangePage {
+        let limit = limit.clamp(1, MAX_CHANGES_LIMIT);
+        let journal = self.load_journal();
+        if cursor < journal.truncated || cursor > journal.seq {
+            return ChangePage {
+                changes: vec![],
+                cursor: journal.seq,
+                more: false,
+                reset: true,
+            };
+        }
+        let mut changes: Vec<Change> = journal
+            .entries
+            .into_iter()
+            .filter(|change| change.seq > cursor)
+            .collect();
+        let more = changes.len() > limit;
+        changes.truncate(limit);
+        ChangePage {
+            cursor: match changes.last() {
+                Some(change) if more => change.seq,
+                _ => journal.seq,
+            },
+            changes,
+            more,
+            reset: false,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{AuthSessionID, User};
+use crate::{
+    config::{Auth, get_config},
+    utils::current_time,
+};
+
+/// Failed attempts of one kind of client, with exponential backoff between attempts and a
+/// lockout once too many of them failed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Throttle {
+    pub failed_attempts: u32,
+    pub last_failure: u64,
+    pub locked_until: u64,
+}
+
+impl Throttle {
+    /// Returns the time until which no attempt is accepted, if that is in the future.
+    pub fn blocked_until(&self, config: &Auth) -> Option<u64> {
+        let backoff = match self.failed_attempts {
+            0 => 0,
+            failures => config
+                .backoff_base
+                .saturating_mul(1u64 << (failures - 1).min(32))
+                .min(config.backoff_max),
+        };
+        let until = self
+            .locked_until
+            .max(self.last_failure.saturating_add(backoff));
+        (until > current_time()).then_some(until)
+    }
+
+    /// Counts a failed attempt, returning true if it locked the client out. Failures older
+    /// than a lockout are forgotten first.
+    pub fn record_failure(&mut self, config: &Auth, max_attempts: u32) -> bool {
+        let now = current_time();
+        if now.saturating_sub(self.last_failure) > config.lockout_duration {
+            self.failed_attempts = 0;
+        }
+        self.failed_attempts += 1;
+        self.last_failure = now;
+        if max_attempts > 0 && self.failed_attempts >= max_attempts {
+            self.failed_attempts = 0;
+            self.locked_until = now + config.lockout_duration;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked_until > current_time()
+    }
+}
+
+/// Failed logins of a user. The counts since the last successful login are shown to the
+/// user when it happens.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Lockout {
+    pub throttle: Throttle,
+    pub failures_since_login: u32,
+    pub lockouts_since_login: u32,
+}
+
+impl User {
+    /// Returns the time until which the user can not try to log in.
+    pub fn login_blocked_until(&self) -> Option<u64> {
+        self.auth.lockout.throttle.blocked_until(&get_config().auth)
+    }
+
+    /// Counts a failed login attempt against the user and the auth session. Auth sessions
+    /// with too many failed attempts are discarded. The caller saves the user.
+    pub fn record_failed_login(&mut self, id: AuthSessionID) {
+        let config = get_config().auth;
+        let lockout = &mut self.auth.lockout;
+        lockout.failures_since_login += 1;
+        if lockout
+            .throttle
+            .record_failure(&config, config.max_failed_attempts)
+        {
+            lockout.lockouts_since_login += 1;
+            warn!(
+                "Locked out user {} for {} seconds after {} failed logins",
+                self.userhandle, config.lockout_duration, config.max_failed_attempts
+            );
+        }
+        if let Some(auth_session) = self.auth.auth_sessions.iter_mut().find(|s| s.id == id) {
+            auth_session.failed_attempts += 1;
+            if config.max_session_attempts > 0
+                && auth_session.failed_attempts >= config.max_session_attempts
+            {
+                self.delete_auth_session_by_id(id);
+            }
+        }
+    }
+
+    /// Resets the failed logins after a successful one, returning what the user should be
+    /// told about.
+    pub fn take_lockout(&mut self) -> Lockout {
+        std::mem::take(&mut self.auth.lockout)
+    }
+
+    /// Lifts a lockout of the user. Returns false if it was not locked out.
+    pub fn unlock(&mut self) -> bool {
+        let locked = self.auth.lockout.throttle.is_locked();
+        self.auth.lockout.throttle = Throttle::default();
+        locked
+    }
+}
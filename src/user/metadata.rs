@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    User,
+    blob::{BlobHash, BlobID},
+    changes::ChangeKind,
+};
+use crate::{
+    logger::error::{ERROR_BLOB_NOT_FOUND, ERROR_BLOB_WRITE_FAILED, ERROR_TAGS_TOO_LARGE, Error},
+    storage::{backend, lock::UserLock, scrub::quarantined},
+    utils::current_time,
+};
+
+pub const METADATA_DOCUMENT: &str = "blobs.json";
+
+/// Largest `tags` value a client may attach to a blob.
+pub const MAX_TAGS_LENGTH: usize = 4096;
+
+/// Most blobs returned by a single `list_blobs` call.
+pub const MAX_LIST_LIMIT: usize = 1000;
+
+/// Server-side metadata of a blob. `tags` is opaque to the server and usually encrypted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlobMetadata {
+    pub size: u64,
+    pub hash: BlobHash,
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub tags: Option<String>,
+}
+
+/// Metadata of every blob of a user, keyed by blob ID.
+type Metadata = HashMap<String, BlobMetadata>;
+
+/// One page of listed blobs and the cursor of the next page, if any. Blobs without
+/// recorded metadata have `None`.
+pub type BlobPage = (Vec<(BlobID, Option<BlobMetadata>)>, Option<BlobID>);
+
+impl User {
+    fn load_metadata(&self) -> Metadata {
+        serde_json::from_str(
+            backend()
+                .read_document(&self.userhandle, METADATA_DOCUMENT)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn save_metadata(&self, metadata: &Metadata) -> bool {
+        backend().write_document(
+            &self.userhandle,
+            METADATA_DOCUMENT,
+            serde_json::to_string(metadata)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+
+    /// Records new content of a blob, keeping its creation time and tags, and notes the
+    /// change in the change journal.
+    pub(super) fn record_blob(&self, id: BlobID, size: u64, hash: &BlobHash) {
+        let kind = if self.update_metadata(id, size, hash) {
+            ChangeKind::BlobCreated
+        } else {
+            ChangeKind::BlobUpdated
+        };
+        self.record_blob_change(kind, id, Some(hash.to_owned()));
+    }
+
+    /// Stores the size and hash of a blob, returning true if the blob had no metadata yet.
+    fn update_metadata(&self, id: BlobID, size: u64, hash: &BlobHash) -> bool {
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return false;
+        };
+        let mut metadata = self.load_metadata();
+        let now = current_time();
+        let created = !metadata.contains_key(&String::from(id));
+        let entry = metadata.entry(id.into()).or_insert(BlobMetadata {
+            size,
+            hash: hash.to_owned(),
+            created_at: now,
+            updated_at: now,
+            tags: None,
+        });
+        entry.size = size;
+        entry.hash = hash.to_owned();
+        entry.updated_at = now;
+        self.save_metadata(&metadata);
+        created
+    }
+
+    /// Drops the metadata of a deleted blob.
+    pub(super) fn forget_blob(&self, id: BlobID) {
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return;
+        };
+        let mut metadata = self.load_metadata();
+        if metadata.remove(&String::from(id)).is_some() {
+            self.save_metadata(&metadata);
+        }
+    }
+
+    /// Moves the metadata of a blob to another blob ID.
+    pub(super) fn move_metadata(&self, from: BlobID, to: BlobID) {
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return;
+        };
+        let mut metadata = self.load_metadata();
+        if let Some(entry) = metadata.remove(&String::from(from)) {
+            metadata.insert(to.into(), entry);
+            self.save_metadata(&metadata);
+        }
+    }
+
+    /// Returns the IDs under which versions, trashed and quarantined blobs are stored. They
+    /// are only reachable through the actions for them, so clients can not rewrite history
+    /// behind the indexes pointing to them.
+    pub(super) fn hidden_blob_ids(&self) -> HashSet<BlobID> {
+        self.version_blob_ids()
+            .into_iter()
+            .chain(self.trash_blob_ids())
+            .chain(
+                quarantined(backend(), &self.userhandle)
+                    .into_iter()
+                    .map(|entry| entry.stored),
+            )
+            .collect()
+    }
+
+    /// Checks if a blob is hidden. Of the hidden blobs only trashed ones keep metadata, so
+    /// versions and quarantine are only looked up for blobs without it.
+    fn is_hidden(&self, id: BlobID, metadata: &Metadata) -> bool {
+        if metadata.contains_key(&String::from(id)) {
+            self.trash_blob_ids().contains(&id)
+        } else {
+            self.hidden_blob_ids().contains(&id)
+        }
+    }
+
+    /// Checks if the user has a blob with the ID, leaving out the hidden ones.
+    pub fn blob_exists(&self, id: BlobID) -> bool {
+        backend().blob_exists(&self.userhandle, id) && !self.is_hidden(id, &self.load_metadata())
+    }
+
+    /// Returns the metadata of a blob. Blobs stored before metadata was kept get
+    /// their metadata recorded on first access.
+    pub fn get_blob_metadata(&self, id: BlobID) -> Result<BlobMetadata, Error> {
+        let metadata = self.load_metadata();
+        if self.is_hidden(id, &metadata) {
+            return Err(Error::new(ERROR_BLOB_NOT_FOUND));
+        }
+        if let Some(entry) = metadata.get(&String::from(id)) {
+            return Ok(entry.to_owned());
+        }
+        let size = backend()
+            .blob_size(&self.userhandle, id)
+            .ok_or_else(|| Error::new(ERROR_BLOB_NOT_FOUND))?;
+        let hash = backend().blob_hash(&self.userhandle, id)?;
+        self.update_metadata(id, size, &hash);
+        self.load_metadata()
+            .remove(&String::from(id))
+            .ok_or_else(|| Error::new(ERROR_BLOB_NOT_FOUND))
+    }
+
+    /// Replaces the client tags of a blob.
+    pub fn set_blob_tags(&self, id: BlobID, tags: Option<String>) -> Result<(), Error> {
+        if tags
+            .as_ref()
+            .is_some_and(|tags| tags.len() > MAX_TAGS_LENGTH)
+        {
+            return Err(Error::new(ERROR_TAGS_TOO_LARGE));
+        }
+        let _lock = UserLock::acquire(&self.userhandle)?;
+        self.get_blob_metadata(id)?;
+        let mut metadata = self.load_metadata();
+        if let Some(entry) = metadata.get_mut(&String::from(id)) {
+            entry.tags = tags;
+        }
+        if self.save_metadata(&metadata) {
+            Ok(())
+        } else {
+            Err(Error::new(ERROR_BLOB_WRITE_FAILED))
+        }
+    }
+
+    /// Lists the user's blobs with their metadata in ascending ID order, starting after
+    /// `cursor`. Returns at most `limit` blobs and the cursor of the next page, if any.
+    /// Versions, trashed and quarantined blobs, which are stored as blobs of their own,
+    /// are left out. Blobs stored before metadata was kept are listed without it until
+    /// the scrubber records it.
+    pub fn list_blobs(&self, cursor: Option<BlobID>, limit: usize) -> Result<BlobPage, Error> {
+        let limit = limit.clamp(1, MAX_LIST_LIMIT);
+        let hidden = self.hidden_blob_ids();
+        let mut ids: Vec<BlobID> = backend()
+            .list_blobs(&self.userhandle)?
+            .into_iter()
+            .filter(|id| !hidden.contains(id) && cursor.is_none_or(|cursor| *id > cursor))
+            .collect();
+        ids.sort();
+
+        let mut metadata = self.load_metadata();
+        let blobs = ids
+            .iter()
+            .take(limit)
+            .map(|id| (*id, metadata.remove(&String::from(*id))))
+            .collect();
+        let next = if ids.len() > limit {
+            ids.get(limit - 1).copied()
+        } else {
+            None
+        };
+        Ok((blobs, next))
+    }
+}
@@ -1,23 +1,32 @@
+pub mod account;
 pub mod blob;
+pub mod changes;
+pub mod lockout;
+pub mod metadata;
+pub mod schema;
 mod sessions;
+pub mod trash;
+mod upload;
+pub mod versions;
+pub mod webauthn;
 
 use std::fmt::Display;
 
 use crate::config::Config;
 use crate::logger::error::Error;
-use crate::storage::file::{
-    create_dir, dir_exists, file_exists, get_folder_size, read_dir, read_file_to_string,
-    write_file_from_string,
-};
+use crate::storage::{backend, lock::UserLock};
 use crate::utils::{char_hex_string_to_u128, u128_to_32_char_hex_string};
-use log::{error, info, warn};
+use lockout::Lockout;
+use log::{error, warn};
+use schema::SCHEMA_VERSION;
 use serde::{Deserialize, Serialize};
 use totp_rs::TOTP;
+use webauthn::WebAuthnData;
 
-use super::config::{get_config, CONFIG};
+use super::config::CONFIG;
 use super::security::verify_totp_code;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Session {
     pub id: SessionID,
     pub created_at: u64,
@@ -25,42 +34,59 @@ pub struct Session {
     pub root: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AuthSession {
     pub id: AuthSessionID,
     pub expires_at: u64,
     pub challenge: u128,
     pub completed_mfa: Vec<u8>,
     pub password_correct: bool,
+    /// Secret SRP server ephemeral, empty once it was used
+    #[serde(default)]
+    pub srp_secret: String,
+    #[serde(default)]
+    pub failed_attempts: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     #[serde(skip)]
     pub userhandle: UserHandle,
+    #[serde(default)]
+    pub schema_version: u32,
     pub sessions: Vec<Session>,
     pub auth: Auth,
     pub foreign_keyring: String,
     pub tier: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EncryptedData {
     pub master_key: String,
     pub keyring: String,
     pub blob_map: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Auth {
+    /// Password hash of accounts on the legacy challenge-response scheme
     pub hash: String,
     pub salt: String,
+    /// SRP-6a verifier, empty for accounts on the legacy scheme
+    #[serde(default)]
+    pub verifier: String,
+    /// Username the verifier was computed with, kept when the account moves to another name.
+    /// Empty for the local username.
+    #[serde(default)]
+    pub srp_identity: String,
     pub auth_sessions: Vec<AuthSession>,
     pub mfa: MFA,
     pub encrypted: EncryptedData,
+    #[serde(default)]
+    pub lockout: Lockout,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MFA {
     pub enabled: bool,
     pub methods: Vec<MFAMethod>,
@@ -73,6 +99,9 @@ pub struct MFAMethodPublic {
     pub id: u8,
     pub name: String,
     pub r#type: MFAMethodType,
+    /// Options for `navigator.credentials.get`, only set for WebAuthn methods
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webauthn: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -88,8 +117,9 @@ pub struct MFAMethod {
 pub enum MFAMethodType {
     #[serde(rename = "totp")]
     TOTP,
-    #[serde(rename = "u2f")]
-    U2F,
+    /// Stored as "u2f" before WebAuthn replaced the U2F stub
+    #[serde(rename = "webauthn", alias = "u2f")]
+    WebAuthn,
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Username(String);
@@ -142,17 +172,17 @@ impl Display for Server {
 impl Display for UserHandle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = self.split();
-        if s.0 .0 == "root" {
-            write!(f, "@{}:", s.1 .0)
+        if s.0.0 == "root" {
+            write!(f, "@{}:", s.1.0)
         } else {
-            write!(f, "@{}:{}", s.0 .0, s.1 .0)
+            write!(f, "@{}:{}", s.0.0, s.1.0)
         }
     }
 }
 
 impl UserHandle {
     pub fn get_local_username(&self) -> String {
-        self.split().0 .0
+        self.split().0.0
     }
 
     pub fn get_server(&self) -> Server {
@@ -191,16 +221,13 @@ impl UserHandle {
 impl User {
     pub fn all() -> Vec<User> {
         let mut users = vec![];
-        match read_dir(
-            &(get_config().storage.data_dir.to_string() + "/users/"),
-            false,
-        ) {
+        match backend().list_users() {
             Ok(dir) => {
                 for user in dir {
                     match UserHandle::from_string("@".to_string() + user.as_str() + ":localhost") {
                         Ok(userhandle) => match User::load(userhandle) {
                             Ok(loaded_user) => users.push(loaded_user),
-                            Err(err) => warn!("Error loading user {}: {}", user, err),
+                            Err(err) => error!("Error loading user {}: {}", user, err),
                         },
                         Err(err) => {
                             warn!(
@@ -220,10 +247,13 @@ impl User {
     pub fn new(userhandle: UserHandle, hash: &str, salt: &str) -> User {
         User {
             userhandle,
+            schema_version: SCHEMA_VERSION,
             sessions: vec![],
             auth: Auth {
                 hash: hash.to_string(),
                 salt: salt.to_string(),
+                verifier: String::new(),
+                srp_identity: String::new(),
                 auth_sessions: vec![],
                 mfa: MFA {
                     enabled: false,
@@ -245,6 +275,7 @@ impl User {
                     keyring: String::new(),
                     blob_map: String::new(),
                 },
+                lockout: Lockout::default(),
             },
             foreign_keyring: String::new(),
             tier: String::new(),
@@ -252,7 +283,7 @@ impl User {
     }
 
     pub fn user_exists(userhandle: UserHandle) -> bool {
-        file_exists(Self::resolve_user_data_path(userhandle, "data.json"))
+        backend().user_exists(&userhandle)
     }
 
     pub fn to_string(&self) -> Result<String, Error> {
@@ -260,60 +291,25 @@ impl User {
             .map_err(|e| Error::new(format!("Error serializing user data: {}", e).as_str()))
     }
 
-    pub fn from_json(json: &str) -> Result<User, Error> {
-        serde_json::from_str(json)
-            .map_err(|e| Error::new(format!("Error parsing user data: {}", e).as_str()))
-    }
-
-    /// Save the user data to the disk
+    /// Save the user data to the storage backend
     pub fn save(&self) -> bool {
-        if !dir_exists(self.resolve_data_path("")) {
-            if !create_dir(self.resolve_data_path("")) {
-                error!("Error creating user directory");
-                return false;
-            }
-            info!("User directory created {}", self.resolve_data_path(""));
-        }
-        match &self.to_string() {
-            Ok(string) => {
-                if write_file_from_string(self.resolve_data_path("data.json").as_str(), string) {
-                    true
-                } else {
-                    error!("Error saving user data");
-                    false
-                }
-            }
-            Err(err) => {
-                error!("Error serializing user data: {}", err);
-                false
-            }
-        }
+        backend().save_user(self)
     }
 
     pub fn load(userhandle: UserHandle) -> Result<User, Error> {
-        match read_file_to_string(Self::resolve_user_data_path(
-            userhandle.to_owned(),
-            "data.json",
-        )) {
-            Ok(data) => match User::from_json(data.as_str()) {
-                Ok(mut user) => {
-                    user.userhandle = userhandle;
-                    Ok(user)
-                }
-                Err(err) => {
-                    warn!("Error parsing user data: {}", err);
-                    Err(Error::new("Could not parse user data"))
-                }
-            },
-            Err(_) => {
-                warn!("Error loading user data");
-                Err(Error::new("Could not load user data"))
-            }
-        }
+        backend().load_user(&userhandle)
     }
 
-    pub fn resolve_data_path(&self, path: &str) -> String {
-        Self::resolve_user_data_path(self.userhandle.to_owned(), path)
+    /// Load a user while holding its lock and pass it to the given function.
+    /// The lock is held until the function returns, so it can modify and save the user
+    /// without racing other requests for the same user.
+    pub fn with_locked<T>(
+        userhandle: UserHandle,
+        f: impl FnOnce(&mut User) -> T,
+    ) -> Result<T, Error> {
+        let _lock = UserLock::acquire(&userhandle)?;
+        let mut user = User::load(userhandle)?;
+        Ok(f(&mut user))
     }
 
     pub fn resolve_user(user: &str) -> Result<(String, String), Error> {
@@ -332,14 +328,6 @@ impl User {
         }
     }
 
-    fn resolve_user_data_path(username: UserHandle, path: &str) -> String {
-        CONFIG.get().unwrap().storage.data_dir.to_string()
-            + "/users/"
-            + username.get_local_username().as_str()
-            + "/"
-            + path
-    }
-
     pub fn create_mfa(&mut self, r#type: MFAMethodType, name: String) -> Option<MFAMethod> {
         match r#type {
             MFAMethodType::TOTP => {
@@ -362,18 +350,29 @@ impl User {
                 self.auth.mfa.methods.push(method.clone());
                 Some(method)
             }
-            MFAMethodType::U2F => {
-                // rand u16
+            MFAMethodType::WebAuthn => {
+                // Registrations that were never finished only take up IDs
+                self.auth.mfa.methods.retain(|m| {
+                    !matches!(m.r#type, MFAMethodType::WebAuthn)
+                        || !WebAuthnData::from_method(m).is_abandoned()
+                });
+                if self.auth.mfa.methods.len() >= 255 {
+                    error!("Too many MFA methods");
+                    return None;
+                }
                 let mut free_mfa_id = rand::random::<u8>();
-                while self.auth.mfa.methods.iter().any(|m| m.id == free_mfa_id) {
+                while self.auth.mfa.methods.iter().any(|m| m.id == free_mfa_id)
+                    || free_mfa_id == 255
+                {
                     free_mfa_id = rand::random::<u8>();
                 }
+                // Enabled once finish_webauthn_registration verified the credential
                 let method = MFAMethod {
                     id: free_mfa_id,
-                    name: "U2F".to_string(),
-                    enabled: true,
-                    data: "".to_string(),
-                    r#type: MFAMethodType::U2F,
+                    name,
+                    enabled: false,
+                    data: WebAuthnData::registration(false).to_json(),
+                    r#type: MFAMethodType::WebAuthn,
                 };
                 self.auth.mfa.methods.push(method.clone());
                 Some(method)
@@ -396,7 +395,8 @@ impl User {
             if method.enabled {
                 match method.r#type {
                     MFAMethodType::TOTP => verify_totp_code(method.data.to_string(), code),
-                    MFAMethodType::U2F => false,
+                    // Verified with check_webauthn_assertion instead of a code
+                    MFAMethodType::WebAuthn => false,
                 }
             } else {
                 false
@@ -423,7 +423,7 @@ impl User {
     }
 
     pub fn get_used_quota(&self) -> u64 {
-        get_folder_size(self.resolve_data_path("")).unwrap_or_default()
+        backend().used_space(&self.userhandle)
     }
 
     pub fn get_available_quota(&self) -> u64 {
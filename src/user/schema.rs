@@ -0,0 +1,220 @@
+use std::fmt::Display;
+
+use log::{error, info};
+use serde_json::{Map, Value, json};
+
+use super::{User, UserHandle};
+use crate::{
+    logger::error::Error,
+    storage::{StorageBackend, lock::UserLock},
+};
+
+/// A step upgrading a user record by one schema version.
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// Upgrade steps, where the step at index `n` turns version `n` into version `n + 1`.
+/// Any change to `User`, `Auth`, `MFA` or `Session` that older records cannot be parsed as
+/// needs a new step here.
+const MIGRATIONS: &[Migration] = &[
+    fill_missing_fields,
+    rename_u2f_methods,
+    add_srp_verifier,
+    add_lockout,
+    add_srp_identity,
+];
+
+/// Schema version of the user records written by this server.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Version 0 records predate `schema_version`. Fields that were added later without a
+/// default are filled in.
+fn fill_missing_fields(user: &mut Map<String, Value>) -> Result<(), String> {
+    user.entry("sessions").or_insert(json!([]));
+    user.entry("foreign_keyring").or_insert(json!(""));
+    user.entry("tier").or_insert(json!(""));
+    let auth = user
+        .get_mut("auth")
+        .and_then(Value::as_object_mut)
+        .ok_or("auth is missing")?;
+    auth.entry("auth_sessions").or_insert(json!([]));
+    let mfa = auth
+        .get_mut("mfa")
+        .and_then(Value::as_object_mut)
+        .ok_or("auth.mfa is missing")?;
+    mfa.entry("min_methods").or_insert(json!(0));
+    Ok(())
+}
+
+/// Version 1 records may hold methods of the U2F stub, which never had a credential. They
+/// become disabled WebAuthn methods.
+fn rename_u2f_methods(user: &mut Map<String, Value>) -> Result<(), String> {
+    let methods = user
+        .get_mut("auth")
+        .and_then(|auth| auth.get_mut("mfa"))
+        .and_then(|mfa| mfa.get_mut("methods"))
+        .and_then(Value::as_array_mut)
+        .ok_or("auth.mfa.methods is missing")?;
+    for method in methods.iter_mut().filter_map(Value::as_object_mut) {
+        if method.get("type") == Some(&json!("u2f")) {
+            method.insert("type".to_string(), json!("webauthn"));
+            method.insert("enabled".to_string(), json!(false));
+            method.insert("data".to_string(), json!(""));
+        }
+    }
+    Ok(())
+}
+
+/// Version 2 records predate SRP. Accounts keep their password hash until `upgrade_user`
+/// or their next login replaces it with a verifier.
+fn add_srp_verifier(user: &mut Map<String, Value>) -> Result<(), String> {
+    user.get_mut("auth")
+        .and_then(Value::as_object_mut)
+        .ok_or("auth is missing")?
+        .entry("verifier")
+        .or_insert(json!(""));
+    Ok(())
+}
+
+/// Version 3 records predate counting failed logins.
+fn add_lockout(user: &mut Map<String, Value>) -> Result<(), String> {
+    user.get_mut("auth")
+        .and_then(Value::as_object_mut)
+        .ok_or("auth is missing")?
+        .entry("lockout")
+        .or_insert(json!({}));
+    Ok(())
+}
+
+/// Version 4 records predate imported accounts keeping their SRP identity. Their verifiers
+/// were all computed with the local username.
+fn add_srp_identity(user: &mut Map<String, Value>) -> Result<(), String> {
+    user.get_mut("auth")
+        .and_then(Value::as_object_mut)
+        .ok_or("auth is missing")?
+        .entry("srp_identity")
+        .or_insert(json!(""));
+    Ok(())
+}
+
+/// Returns the schema version of a user record, 0 if it has none.
+pub fn stored_version(user: &Value) -> u32 {
+    user.get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32
+}
+
+fn newer_version(version: u32) -> Error {
+    Error::new(
+        format!(
+            "User data has schema version {}, newer than the supported version {}",
+            version, SCHEMA_VERSION
+        )
+        .as_str(),
+    )
+}
+
+/// Upgrades a user record to `SCHEMA_VERSION`. Records written by a newer server are
+/// refused, as saving them again would drop what this server does not know about.
+pub fn upgrade(user: &mut Value) -> Result<(), Error> {
+    let version = stored_version(user);
+    if version > SCHEMA_VERSION {
+        return Err(newer_version(version));
+    }
+    let record = user
+        .as_object_mut()
+        .ok_or_else(|| Error::new("User data is not an object"))?;
+    for (step, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(record).map_err(|err| {
+            Error::new(format!("Migration to schema version {} failed: {}", step + 1, err).as_str())
+        })?;
+        record.insert("schema_version".to_string(), json!(step + 1));
+    }
+    Ok(())
+}
+
+/// Summary of the schema upgrade run at startup.
+#[derive(Debug, Default)]
+pub struct SchemaReport {
+    pub upgraded_users: usize,
+    pub current_users: usize,
+    pub failures: Vec<String>,
+}
+
+impl Display for SchemaReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Schema version: {}", SCHEMA_VERSION)?;
+        writeln!(f, "Users upgraded: {}", self.upgraded_users)?;
+        writeln!(f, "Users up to date: {}", self.current_users)?;
+        write!(f, "Failures: {}", self.failures.len())?;
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+/// Upgrades the stored record of every user older than `SCHEMA_VERSION`, reporting users
+/// that cannot be upgraded or loaded.
+pub fn upgrade_all(backend: &dyn StorageBackend, lock_dir: &str) -> SchemaReport {
+    let mut report = SchemaReport::default();
+    let users = match backend.list_users() {
+        Ok(users) => users,
+        Err(err) => {
+            report
+                .failures
+                .push(format!("Could not list users: {}", err));
+            return report;
+        }
+    };
+    for user in users {
+        match upgrade_user(backend, lock_dir, &user) {
+            Ok(true) => {
+                info!(
+                    "Upgraded user {} to schema version {}",
+                    user, SCHEMA_VERSION
+                );
+                report.upgraded_users += 1;
+            }
+            Ok(false) => report.current_users += 1,
+            Err(err) => {
+                error!("Could not upgrade user {}: {}", user, err);
+                report.failures.push(format!("{}: {}", user, err));
+            }
+        }
+    }
+    report
+}
+
+/// Returns true if the user's record was upgraded. Password hashes of the legacy scheme are
+/// replaced with SRP verifiers, as anyone reading them could log in.
+fn upgrade_user(backend: &dyn StorageBackend, lock_dir: &str, user: &str) -> Result<bool, Error> {
+    let userhandle = UserHandle::from_string("@".to_string() + user + ":localhost")?;
+    let _lock = UserLock::acquire_in(lock_dir, user)?;
+    let version = backend.schema_version(&userhandle)?;
+    if version > SCHEMA_VERSION {
+        return Err(newer_version(version));
+    }
+    // Loading upgrades the record in memory and fails for records newer than supported
+    let mut user = backend.load_user(&userhandle)?;
+    if !user.upgrade_legacy_password() && version == SCHEMA_VERSION {
+        return Ok(false);
+    }
+    if backend.save_user(&user) {
+        Ok(true)
+    } else {
+        Err(Error::new("Could not save upgraded user data"))
+    }
+}
+
+impl User {
+    /// Parses a stored user record, upgrading it on the way. The record passes through a
+    /// `Value` for the migrations, which only keeps session and auth session IDs above
+    /// `u64::MAX` intact because serde_json is built with `arbitrary_precision`.
+    pub fn from_json(json: &str) -> Result<User, Error> {
+        let mut value: Value = serde_json::from_str(json)
+            .map_err(|e| Error::new(format!("Error parsing user data: {}", e).as_str()))?;
+        upgrade(&mut value)?;
+        serde_json::from_value(value)
+            .map_err(|e| Error::new(format!("Error parsing user data: {}", e).as_str()))
+    }
+}
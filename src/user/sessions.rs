@@ -1,9 +1,12 @@
 use crate::config::CONFIG;
 use crate::logger::error::Error;
-use crate::security::verify_challenge_response;
-use crate::user::{AuthSession, Session, User};
+use crate::security::{
+    srp_server_public, srp_server_secret, srp_verifier, srp_verify_client,
+    verify_challenge_response,
+};
+use crate::user::{AuthSession, Session, User, webauthn::WebAuthnAssertion};
 use crate::utils::{
-    create_unique_id, current_time, random_u128, u128_to_32_char_hex_string, HasID,
+    HasID, create_unique_id, current_time, random_u128, u128_to_32_char_hex_string,
 };
 
 use super::{AuthSessionID, SessionID};
@@ -42,6 +45,12 @@ impl User {
             challenge: random_u128(),
             completed_mfa: Vec::new(),
             password_correct: false,
+            srp_secret: if self.auth.verifier.is_empty() {
+                String::new()
+            } else {
+                srp_server_secret()
+            },
+            failed_attempts: 0,
         });
         id
     }
@@ -76,12 +85,17 @@ impl User {
         self.sessions.retain(|s| s.id != id);
     }
 
+    /// Checks a response of the legacy scheme. A successful login moves the account to SRP.
     pub fn check_password_for_auth_session(&mut self, id: AuthSessionID, response: &str) -> bool {
+        if !self.auth.verifier.is_empty() {
+            return false;
+        }
         let password_hash = self.auth.hash.clone();
         match self.get_mut_auth_session_by_id(id) {
             Ok(auth_session) => {
                 if verify_challenge_response(auth_session.challenge, response, password_hash) {
                     auth_session.password_correct = true;
+                    self.upgrade_legacy_password();
                     self.save();
                     true
                 } else {
@@ -96,6 +110,77 @@ impl User {
         }
     }
 
+    /// Public SRP server ephemeral B of an auth session.
+    pub fn srp_server_public(&self, id: AuthSessionID) -> Option<String> {
+        let auth_session = self.get_auth_session_by_id(id).ok()?;
+        srp_server_public(&self.auth.verifier, &auth_session.srp_secret)
+    }
+
+    /// Checks the client's SRP ephemeral A and proof M1 and returns the server proof M2.
+    /// Every server ephemeral is used for one attempt only.
+    pub fn check_srp_for_auth_session(
+        &mut self,
+        id: AuthSessionID,
+        client_public: &str,
+        client_proof: &str,
+    ) -> Option<String> {
+        let verifier = self.auth.verifier.clone();
+        let auth_session = self.get_mut_auth_session_by_id(id).ok()?;
+        if verifier.is_empty() || auth_session.srp_secret.is_empty() {
+            return None;
+        }
+        let secret = std::mem::take(&mut auth_session.srp_secret);
+        let server_proof = srp_verify_client(&verifier, &secret, client_public, client_proof);
+        auth_session.password_correct = server_proof.is_some();
+        server_proof
+    }
+
+    /// Username the client computes its SRP private key with.
+    pub fn srp_identity(&self) -> String {
+        if self.auth.srp_identity.is_empty() {
+            self.userhandle.get_local_username()
+        } else {
+            self.auth.srp_identity.to_string()
+        }
+    }
+
+    /// Replaces the password hash of an account on the legacy scheme with an SRP verifier
+    /// derived from it. A hash next to an existing verifier is dropped, as it would still let
+    /// anyone reading it log in. Returns true if a hash was removed.
+    pub fn upgrade_legacy_password(&mut self) -> bool {
+        if self.auth.hash.is_empty() {
+            return false;
+        }
+        if self.auth.verifier.is_empty() {
+            self.auth.verifier =
+                srp_verifier(&self.srp_identity(), &self.auth.salt, &self.auth.hash);
+        }
+        self.auth.hash = String::new();
+        true
+    }
+
+    /// A passkey assertion with user verification stands in for the password and every
+    /// second factor. Returns the master key wrapped for the passkey.
+    pub fn check_passkey_for_auth_session(
+        &mut self,
+        id: AuthSessionID,
+        assertion: &WebAuthnAssertion,
+    ) -> Result<String, Error> {
+        let passkey = self.check_passkey_assertion(id, assertion)?;
+        let completed_mfa = self
+            .auth
+            .mfa
+            .methods
+            .iter()
+            .filter(|m| m.enabled)
+            .map(|m| m.id)
+            .collect();
+        let auth_session = self.get_mut_auth_session_by_id(id)?;
+        auth_session.password_correct = true;
+        auth_session.completed_mfa = completed_mfa;
+        Ok(passkey.master_key)
+    }
+
     pub fn convert_auth_session_to_session(
         &mut self,
         id: AuthSessionID,
@@ -0,0 +1,181 @@
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    User,
+    blob::{BlobID, ShareID},
+    changes::ChangeKind,
+};
+use crate::{
+    config::get_config,
+    logger::error::{
+        ERROR_BLOB_ALREADY_EXISTS, ERROR_BLOB_NOT_IN_TRASH, ERROR_BLOB_WRITE_FAILED, Error,
+    },
+    storage::{backend, lock::UserLock},
+    utils::current_time,
+};
+
+pub const TRASH_DOCUMENT: &str = "trash.json";
+
+/// How often the background sweeper looks for expired trash entries.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A deleted blob waiting in the trash, stored under a blob ID of its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashEntry {
+    pub id: BlobID,
+    pub stored: BlobID,
+    pub size: u64,
+    pub deleted_at: u64,
+    /// Shares the blob was removed from when it was deleted.
+    pub shares: Vec<ShareID>,
+}
+
+impl User {
+    fn load_trash(&self) -> Vec<TrashEntry> {
+        serde_json::from_str(
+            backend()
+                .read_document(&self.userhandle, TRASH_DOCUMENT)
+                .unwrap_or("[]".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn save_trash(&self, trash: &[TrashEntry]) -> bool {
+        backend().write_document(
+            &self.userhandle,
+            TRASH_DOCUMENT,
+            serde_json::to_string(trash)
+                .unwrap_or("[]".to_string())
+                .as_str(),
+        )
+    }
+
+    /// Moves a blob with its versions into the trash, remembering the shares it was in.
+    pub(super) fn move_to_trash(&self, id: BlobID, shares: Vec<ShareID>) -> Result<(), Error> {
+        let _lock = UserLock::acquire(&self.userhandle)?;
+        let size = backend().blob_size(&self.userhandle, id).unwrap_or(0);
+        let stored = self.new_blob_id();
+        if !backend().copy_blob(&self.userhandle, id, stored) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        let mut trash = self.load_trash();
+        trash.push(TrashEntry {
+            id,
+            stored,
+            size,
+            deleted_at: current_time(),
+            shares,
+        });
+        if !self.save_trash(&trash) {
+            backend().delete_blob(&self.userhandle, stored);
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        backend().delete_blob(&self.userhandle, id);
+        self.move_versions(id, stored);
+        self.move_metadata(id, stored);
+        Ok(())
+    }
+
+    /// Returns all blobs in the trash, oldest deletion first.
+    pub fn list_trash(&self) -> Vec<TrashEntry> {
+        self.load_trash()
+    }
+
+    /// Returns the blob IDs under which trashed blobs are stored.
+    pub(super) fn trash_blob_ids(&self) -> Vec<BlobID> {
+        self.load_trash()
+            .into_iter()
+            .map(|entry| entry.stored)
+            .collect()
+    }
+
+    /// Restores the most recently deleted blob with the given ID, optionally putting it back
+    /// into the shares it was removed from that still exist.
+    pub fn restore_from_trash(&self, id: BlobID, restore_shares: bool) -> Result<(), Error> {
+        let _lock = UserLock::acquire(&self.userhandle)?;
+        if backend().blob_exists(&self.userhandle, id) {
+            return Err(Error::new(ERROR_BLOB_ALREADY_EXISTS));
+        }
+        let mut trash = self.load_trash();
+        let index = trash
+            .iter()
+            .rposition(|entry| entry.id == id)
+            .ok_or_else(|| Error::new(ERROR_BLOB_NOT_IN_TRASH))?;
+        let entry = trash.remove(index);
+        if !backend().copy_blob(&self.userhandle, entry.stored, id) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        if !self.save_trash(&trash) {
+            backend().delete_blob(&self.userhandle, id);
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        backend().delete_blob(&self.userhandle, entry.stored);
+        self.move_versions(entry.stored, id);
+        self.move_metadata(entry.stored, id);
+        let hash = self
+            .get_blob_metadata(id)
+            .ok()
+            .map(|metadata| metadata.hash);
+        self.record_blob_change(ChangeKind::BlobCreated, id, hash);
+        if restore_shares {
+            for share in entry.shares {
+                if self.get_share_by_id(share).is_ok() {
+                    self.add_blob_to_share(share, id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Permanently deletes trash entries matching the filter, returning how many were purged.
+    fn purge_trash(&self, filter: impl Fn(&TrashEntry) -> bool) -> Result<usize, Error> {
+        let _lock = UserLock::acquire(&self.userhandle)?;
+        let (purged, kept): (Vec<TrashEntry>, Vec<TrashEntry>) =
+            self.load_trash().into_iter().partition(filter);
+        if purged.is_empty() {
+            return Ok(0);
+        }
+        if !self.save_trash(&kept) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        for entry in &purged {
+            backend().delete_blob(&self.userhandle, entry.stored);
+            self.delete_versions(entry.stored);
+            self.forget_blob(entry.stored);
+        }
+        Ok(purged.len())
+    }
+
+    /// Permanently deletes everything in the trash.
+    pub fn empty_trash(&self) -> Result<usize, Error> {
+        self.purge_trash(|_| true)
+    }
+
+    /// Permanently deletes trash entries older than the configured retention.
+    pub fn purge_expired_trash(&self) -> Result<usize, Error> {
+        let retention = get_config().storage.trash_retention;
+        let now = current_time();
+        self.purge_trash(|entry| entry.deleted_at.saturating_add(retention) <= now)
+    }
+}
+
+/// Starts a background thread purging expired trash entries of all users.
+pub fn start_trash_sweeper() {
+    thread::spawn(|| {
+        loop {
+            for user in User::all() {
+                match user.purge_expired_trash() {
+                    Ok(0) => {}
+                    Ok(purged) => info!("Purged {} expired blobs of {}", purged, user.userhandle),
+                    Err(err) => warn!("Could not purge trash of {}: {}", user.userhandle, err),
+                }
+            }
+            thread::sleep(SWEEP_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,106 @@
+use super::{
+    User,
+    blob::{Base64, BlobHash, BlobID, base64_decode},
+};
+use crate::{
+    config::get_config,
+    logger::error::{
+        ERROR_BLOB_HASH_NOT_MATCH, ERROR_BLOB_NOT_FOUND, ERROR_BLOB_WRITE_FAILED,
+        ERROR_QUOTA_EXCEEDED, Error,
+    },
+    storage::{
+        backend,
+        upload::{UploadSession, UploadStore},
+    },
+};
+
+fn uploads() -> UploadStore {
+    UploadStore::new(&get_config().storage.temp_dir)
+}
+
+impl User {
+    /// Starts a chunked upload of a new blob, or of new content for `blob` if given
+    /// together with the hash the blob currently has.
+    pub fn begin_upload(
+        &self,
+        size: u64,
+        hash: BlobHash,
+        chunk_size: u64,
+        blob: Option<(BlobID, BlobHash)>,
+    ) -> Result<UploadSession, Error> {
+        if let Some((id, _)) = &blob
+            && !self.blob_exists(*id)
+        {
+            return Err(Error::new(ERROR_BLOB_NOT_FOUND));
+        }
+        // Space of open uploads is taken on their commit, and versions give way to them
+        let uploads = uploads();
+        let staged: u64 = uploads
+            .list(&self.userhandle)
+            .iter()
+            .map(|session| session.size)
+            .sum();
+        let required = backend().required_space(&self.userhandle, size, &hash);
+        if required.saturating_add(staged)
+            > self
+                .get_available_quota()
+                .saturating_add(self.versions_size())
+        {
+            return Err(Error::new(ERROR_QUOTA_EXCEEDED));
+        }
+        uploads.begin(&self.userhandle, size, hash, chunk_size, blob)
+    }
+
+    /// Stores one chunk of an upload, returning the indices of all chunks received so far.
+    pub fn upload_chunk(&self, id: &str, index: u64, content: Base64) -> Result<Vec<u64>, Error> {
+        let uploads = uploads();
+        let session = uploads.write_chunk(&self.userhandle, id, index, base64_decode(content)?)?;
+        Ok(uploads.received_chunks(&self.userhandle, &session))
+    }
+
+    /// Returns an upload session with the indices of all chunks received so far.
+    pub fn get_upload(&self, id: &str) -> Result<(UploadSession, Vec<u64>), Error> {
+        let uploads = uploads();
+        let session = uploads.load(&self.userhandle, id)?;
+        let received = uploads.received_chunks(&self.userhandle, &session);
+        Ok((session, received))
+    }
+
+    /// Completes an upload once all chunks are received and the content matches the
+    /// declared hash, moving it into place as a blob.
+    pub fn commit_upload(&self, id: &str) -> Result<(BlobID, BlobHash), Error> {
+        let uploads = uploads();
+        let session = uploads.load(&self.userhandle, id)?;
+        let path = uploads.assemble(&self.userhandle, &session)?;
+        let blob_id = match &session.blob {
+            Some((blob_id, old_hash)) => {
+                if self.get_blob_metadata(*blob_id)?.hash != *old_hash {
+                    uploads.remove(&self.userhandle, id);
+                    return Err(Error::new(ERROR_BLOB_HASH_NOT_MATCH));
+                }
+                self.prepare_replace(*blob_id, session.size, &session.hash)?;
+                *blob_id
+            }
+            None => {
+                self.check_quota(session.size, &session.hash)?;
+                self.new_blob_id()
+            }
+        };
+        let stored = backend().write_blob_from_file(&self.userhandle, blob_id, &path);
+        uploads.remove(&self.userhandle, id);
+        if stored {
+            self.record_blob(blob_id, session.size, &session.hash);
+            Ok((blob_id, session.hash))
+        } else {
+            Err(Error::new(ERROR_BLOB_WRITE_FAILED))
+        }
+    }
+
+    /// Cancels an upload and removes all received chunks.
+    pub fn abort_upload(&self, id: &str) -> Result<(), Error> {
+        let uploads = uploads();
+        uploads.load(&self.userhandle, id)?;
+        uploads.remove(&self.userhandle, id);
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    User,
+    blob::{Base64, BlobHash, BlobID, base64_encode},
+};
+use crate::{
+    config::{CONFIG, DEFAULT_TIER_VERSIONS},
+    logger::error::{
+        ERROR_BLOB_NOT_FOUND, ERROR_BLOB_WRITE_FAILED, ERROR_QUOTA_EXCEEDED,
+        ERROR_VERSION_NOT_FOUND, Error,
+    },
+    storage::{backend, lock::UserLock},
+    utils::current_time,
+};
+
+pub const VERSIONS_DOCUMENT: &str = "versions.json";
+
+/// A previous content of a blob, stored as a blob of its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlobVersion {
+    pub id: BlobID,
+    pub hash: BlobHash,
+    pub size: u64,
+    pub created_at: u64,
+}
+
+/// Versions of every blob of a user, oldest first, keyed by blob ID.
+pub type Versions = HashMap<String, Vec<BlobVersion>>;
+
+impl User {
+    fn load_versions(&self) -> Versions {
+        serde_json::from_str(
+            backend()
+                .read_document(&self.userhandle, VERSIONS_DOCUMENT)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+        .unwrap_or_default()
+    }
+
+    fn save_versions(&self, versions: &Versions) -> bool {
+        backend().write_document(
+            &self.userhandle,
+            VERSIONS_DOCUMENT,
+            serde_json::to_string(versions)
+                .unwrap_or("{}".to_string())
+                .as_str(),
+        )
+    }
+
+    /// Returns how many previous versions of each blob the user's tier keeps.
+    pub fn get_tier_versions(&self) -> u64 {
+        CONFIG
+            .get()
+            .and_then(|config| config.get_tier(self.tier.as_str()))
+            .map_or(DEFAULT_TIER_VERSIONS, |tier| tier.versions)
+    }
+
+    /// Returns the space taken by previous versions, which is freed as needed.
+    pub(super) fn versions_size(&self) -> u64 {
+        self.load_versions()
+            .values()
+            .flatten()
+            .map(|version| version.size)
+            .sum()
+    }
+
+    /// Frees quota by deleting the oldest versions of any blob until `needed` bytes are
+    /// available. Returns false if that is not possible.
+    pub(super) fn make_room(&self, needed: u64) -> bool {
+        if self.get_available_quota() >= needed {
+            return true;
+        }
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return false;
+        };
+        let mut versions = self.load_versions();
+        while self.get_available_quota() < needed {
+            let oldest = versions
+                .iter()
+                .filter_map(|(blob, list)| list.first().map(|version| (blob, version)))
+                .min_by_key(|(_, version)| version.created_at)
+                .map(|(blob, _)| blob.to_string());
+            let Some(blob) = oldest else {
+                break;
+            };
+            let list = versions.entry(blob.to_string()).or_default();
+            let version = list.remove(0);
+            if list.is_empty() {
+                versions.remove(&blob);
+            }
+            backend().delete_blob(&self.userhandle, version.id);
+        }
+        self.save_versions(&versions);
+        self.get_available_quota() >= needed
+    }
+
+    /// Makes sure replacing the content of a blob with content of the given size and hash fits
+    /// into the quota, and keeps the current content as a version if there is room for it.
+    pub(super) fn prepare_replace(
+        &self,
+        id: BlobID,
+        size: u64,
+        hash: &BlobHash,
+    ) -> Result<(), Error> {
+        let required = backend().required_space(&self.userhandle, size, hash);
+        if !self.make_room(required) {
+            return Err(Error::new(ERROR_QUOTA_EXCEEDED));
+        }
+        self.keep_version(id, required)
+    }
+
+    /// Copies the current content of a blob into its version history, dropping the oldest
+    /// versions beyond the tier's limit. `reserved` bytes of quota are left untouched.
+    fn keep_version(&self, id: BlobID, reserved: u64) -> Result<(), Error> {
+        let limit = self.get_tier_versions() as usize;
+        if limit == 0 {
+            return Ok(());
+        }
+        let _lock = UserLock::acquire(&self.userhandle)?;
+        let (size, hash) = self.get_blob_info(id)?;
+        let cost = backend().required_space(&self.userhandle, size, &hash);
+        if !self.make_room(reserved.saturating_add(cost)) {
+            warn!(
+                "Not keeping a version of blob {} of {}, quota exhausted",
+                String::from(id),
+                self.userhandle
+            );
+            return Ok(());
+        }
+        let version_id = self.new_blob_id();
+        if !backend().copy_blob(&self.userhandle, id, version_id) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+
+        let mut versions = self.load_versions();
+        let list = versions.entry(id.into()).or_default();
+        list.push(BlobVersion {
+            id: version_id,
+            hash,
+            size,
+            created_at: current_time(),
+        });
+        let excess = list.len().saturating_sub(limit);
+        for version in list.drain(..excess) {
+            backend().delete_blob(&self.userhandle, version.id);
+        }
+        if self.save_versions(&versions) {
+            Ok(())
+        } else {
+            Err(Error::new(ERROR_BLOB_WRITE_FAILED))
+        }
+    }
+
+    /// Deletes all versions of a blob.
+    pub(super) fn delete_versions(&self, id: BlobID) {
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return;
+        };
+        let mut versions = self.load_versions();
+        if let Some(list) = versions.remove(&String::from(id)) {
+            for version in list {
+                backend().delete_blob(&self.userhandle, version.id);
+            }
+            self.save_versions(&versions);
+        }
+    }
+
+    /// Moves the version history of a blob to another blob ID.
+    pub(super) fn move_versions(&self, from: BlobID, to: BlobID) {
+        let Ok(_lock) = UserLock::acquire(&self.userhandle) else {
+            return;
+        };
+        let mut versions = self.load_versions();
+        if let Some(list) = versions.remove(&String::from(from)) {
+            versions.insert(to.into(), list);
+            self.save_versions(&versions);
+        }
+    }
+
+    /// Returns the blob IDs under which versions of any blob are stored.
+    pub(super) fn version_blob_ids(&self) -> Vec<BlobID> {
+        self.load_versions()
+            .into_values()
+            .flatten()
+            .map(|version| version.id)
+            .collect()
+    }
+
+    /// Returns the previous versions of a blob, oldest first.
+    pub fn list_versions(&self, id: BlobID) -> Result<Vec<BlobVersion>, Error> {
+        if !self.blob_exists(id) {
+            return Err(Error::new(ERROR_BLOB_NOT_FOUND));
+        }
+        Ok(self
+            .load_versions()
+            .remove(&String::from(id))
+            .unwrap_or_default())
+    }
+
+    fn get_version(&self, id: BlobID, version: BlobID) -> Result<BlobVersion, Error> {
+        self.list_versions(id)?
+            .into_iter()
+            .find(|v| v.id == version)
+            .ok_or_else(|| Error::new(ERROR_VERSION_NOT_FOUND))
+    }
+
+    /// Reads a previous version of a blob.
+    pub fn read_version(&self, id: BlobID, version: BlobID) -> Result<(Base64, BlobHash), Error> {
+        let version = self.get_version(id, version)?;
+        let content = backend().read_blob(&self.userhandle, version.id)?;
+        Ok((base64_encode(content), version.hash))
+    }
+
+    /// Makes a previous version the current content of a blob. The replaced content is kept
+    /// as a version itself, so a restore can be undone.
+    pub fn restore_version(&self, id: BlobID, version: BlobID) -> Result<BlobHash, Error> {
+        let _lock = UserLock::acquire(&self.userhandle)?;
+        let version = self.get_version(id, version)?;
+        // Keeping the replaced content may prune the version being restored, so stage it first
+        let staged = self.new_blob_id();
+        if !backend().copy_blob(&self.userhandle, version.id, staged) {
+            return Err(Error::new(ERROR_BLOB_WRITE_FAILED));
+        }
+        let restored = self
+            .prepare_replace(id, version.size, &version.hash)
+            .and_then(|_| {
+                if backend().copy_blob(&self.userhandle, staged, id) {
+                    self.record_blob(id, version.size, &version.hash);
+                    Ok(version.hash)
+                } else {
+                    Err(Error::new(ERROR_BLOB_WRITE_FAILED))
+                }
+            });
+        backend().delete_blob(&self.userhandle, staged);
+        restored
+    }
+}
@@ -0,0 +1,710 @@
+use base64::engine::{Engine, general_purpose::URL_SAFE_NO_PAD};
+use ciborium::Value as Cbor;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{AuthSessionID, MFAMethod, MFAMethodType, User};
+use crate::{
+    config::get_config,
+    logger::error::Error,
+    utils::{current_time, u128_to_32_char_hex_string},
+};
+
+/// Seconds an authenticator has to answer a registration challenge.
+pub const REGISTRATION_TIMEOUT: u64 = 300;
+
+/// COSE algorithms accepted for credentials, in order of preference.
+const ES256: i64 = -7;
+const EDDSA: i64 = -8;
+const RS256: i64 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// A credential created by an authenticator.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebAuthnCredential {
+    /// Credential ID, base64url
+    pub id: String,
+    /// COSE public key, base64url
+    pub public_key: String,
+    pub sign_count: u32,
+    pub rp_id: String,
+    /// Format of the attestation the credential was registered with
+    pub attestation: String,
+    /// Set for passkeys, which sign in without the password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passkey: Option<Passkey>,
+}
+
+/// What a passkey needs to sign in on its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Passkey {
+    /// Input of the PRF extension whose output wraps `master_key`, base64url
+    pub prf_salt: String,
+    /// `EncryptedData::master_key` wrapped by the client with the PRF output
+    #[serde(default)]
+    pub master_key: String,
+}
+
+/// Contents of `MFAMethod::data` for WebAuthn methods.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct WebAuthnData {
+    /// Challenge of a registration that was not finished yet, base64url
+    #[serde(default)]
+    pub challenge: String,
+    #[serde(default)]
+    pub challenge_expires_at: u64,
+    #[serde(default)]
+    pub credential: Option<WebAuthnCredential>,
+    /// PRF input of a passkey registration that was not finished yet, base64url
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prf_salt: String,
+}
+
+impl WebAuthnData {
+    /// Starts a registration with a new random challenge.
+    pub fn registration(passkey: bool) -> WebAuthnData {
+        WebAuthnData {
+            challenge: encode(&rand::random::<[u8; 32]>()),
+            challenge_expires_at: current_time() + REGISTRATION_TIMEOUT,
+            credential: None,
+            prf_salt: if passkey {
+                encode(&rand::random::<[u8; 32]>())
+            } else {
+                String::new()
+            },
+        }
+    }
+
+    /// Methods created by the former U2F stub hold no data and never get a credential.
+    pub fn from_method(method: &MFAMethod) -> WebAuthnData {
+        serde_json::from_str(&method.data).unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// True for registrations that can no longer be finished.
+    pub fn is_abandoned(&self) -> bool {
+        self.credential.is_none() && self.challenge_expires_at < current_time()
+    }
+}
+
+/// An authenticator's response to `navigator.credentials.get`, all fields base64url.
+#[derive(Debug, Deserialize)]
+pub struct WebAuthnAssertion {
+    pub credential_id: String,
+    /// User handle returned by discoverable credentials
+    #[serde(default)]
+    pub user_handle: Option<String>,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    r#type: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    /// Credential ID and COSE public key, only present when registering
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+enum PublicKey {
+    Es256(Vec<u8>),
+    EdDsa(Vec<u8>),
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+fn webauthn_error(message: &str) -> Error {
+    Error::new(format!("WebAuthn: {}", message).as_str())
+}
+
+fn encode(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, Error> {
+    URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .map_err(|_| webauthn_error("invalid base64url"))
+}
+
+/// Relying party IDs accepted by this server, one for every configured FQDN.
+pub fn rp_ids() -> Vec<String> {
+    get_config()
+        .network
+        .fqdns
+        .iter()
+        .map(|fqdn| fqdn.to_string())
+        .collect()
+}
+
+/// The origin has to be the RP ID or one of its subdomains, served over HTTPS unless the
+/// RP ID is localhost.
+fn origin_matches(origin: &str, rp_id: &str) -> bool {
+    let host = match origin.split_once("://") {
+        Some(("https", host)) => host,
+        Some(("http", host)) if rp_id == "localhost" => host,
+        _ => return false,
+    };
+    let host = host.split(':').next().unwrap_or_default();
+    host == rp_id || host.ends_with(&format!(".{}", rp_id))
+}
+
+/// Challenge signed to complete an auth session. It is derived from the random challenge
+/// of the auth session, so every login gets a new one.
+fn assertion_challenge(challenge: u128) -> String {
+    encode(&Sha256::digest(format!(
+        "webauthn:{}",
+        u128_to_32_char_hex_string(challenge)
+    )))
+}
+
+/// Checks clientDataJSON and returns its hash, which the authenticator signs.
+fn check_client_data(
+    client_data_json: &[u8],
+    ceremony: &str,
+    challenge: &str,
+    rp_id: &str,
+) -> Result<Vec<u8>, Error> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| webauthn_error("invalid client data"))?;
+    if client_data.r#type != ceremony {
+        Err(webauthn_error("unexpected ceremony"))
+    } else if client_data.challenge.trim_end_matches('=') != challenge {
+        Err(webauthn_error("challenge does not match"))
+    } else if !origin_matches(&client_data.origin, rp_id) {
+        Err(webauthn_error(
+            format!("origin {} does not belong to {}", client_data.origin, rp_id).as_str(),
+        ))
+    } else {
+        Ok(Sha256::digest(client_data_json).to_vec())
+    }
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, Error> {
+    let truncated = || webauthn_error("authenticator data is truncated");
+    if data.len() < 37 {
+        return Err(truncated());
+    }
+    let flags = data[32];
+    let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        // AAGUID, credential ID length, credential ID, COSE key and maybe extensions
+        let attested = data.get(37 + 16..).ok_or_else(truncated)?;
+        let id_length = u16::from_be_bytes([
+            *attested.first().ok_or_else(truncated)?,
+            *attested.get(1).ok_or_else(truncated)?,
+        ]) as usize;
+        let id = attested.get(2..2 + id_length).ok_or_else(truncated)?;
+        let key = &attested[2 + id_length..];
+        let mut rest = key;
+        ciborium::from_reader::<Cbor, _>(&mut rest)
+            .map_err(|_| webauthn_error("invalid credential public key"))?;
+        Some((id.to_vec(), key[..key.len() - rest.len()].to_vec()))
+    } else {
+        None
+    };
+    Ok(AuthenticatorData {
+        rp_id_hash: data[..32].to_vec(),
+        flags,
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        credential,
+    })
+}
+
+fn field<'a>(map: &'a [(Cbor, Cbor)], key: &str) -> Option<&'a Cbor> {
+    map.iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn integer(value: &Cbor) -> Option<i64> {
+    value.as_integer().and_then(|i| i64::try_from(i).ok())
+}
+
+impl PublicKey {
+    fn from_cose(key: &[u8]) -> Result<PublicKey, Error> {
+        let key: Cbor =
+            ciborium::from_reader(key).map_err(|_| webauthn_error("invalid public key"))?;
+        let map = key
+            .as_map()
+            .ok_or_else(|| webauthn_error("invalid public key"))?;
+        let label = |label: i64| {
+            map.iter()
+                .find(|(k, _)| integer(k) == Some(label))
+                .map(|(_, v)| v)
+        };
+        let bytes = |l: i64| {
+            label(l)
+                .and_then(Cbor::as_bytes)
+                .cloned()
+                .ok_or_else(|| webauthn_error("incomplete public key"))
+        };
+        let kty = label(1).and_then(integer);
+        let alg = label(3).and_then(integer);
+        let crv = label(-1).and_then(integer);
+        match (kty, alg, crv) {
+            (Some(2), Some(ES256), Some(1)) => {
+                let mut point = vec![4];
+                point.extend(bytes(-2)?);
+                point.extend(bytes(-3)?);
+                Ok(PublicKey::Es256(point))
+            }
+            (Some(1), Some(EDDSA), Some(6)) => Ok(PublicKey::EdDsa(bytes(-2)?)),
+            (Some(3), Some(RS256), _) => Ok(PublicKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            _ => Err(webauthn_error("unsupported public key algorithm")),
+        }
+    }
+
+    /// Returns the P-256 key of the leaf certificate in an attestation statement.
+    fn from_certificate(statement: &[(Cbor, Cbor)]) -> Result<PublicKey, Error> {
+        let invalid = || webauthn_error("invalid attestation certificate");
+        let leaf = field(statement, "x5c")
+            .and_then(Cbor::as_array)
+            .and_then(|chain| chain.first())
+            .and_then(Cbor::as_bytes)
+            .ok_or_else(invalid)?;
+        let (_, certificate) = X509Certificate::from_der(leaf).map_err(|_| invalid())?;
+        Ok(PublicKey::Es256(
+            certificate.public_key().subject_public_key.data.to_vec(),
+        ))
+    }
+
+    fn algorithm(&self) -> i64 {
+        match self {
+            PublicKey::Es256(_) => ES256,
+            PublicKey::EdDsa(_) => EDDSA,
+            PublicKey::Rs256 { .. } => RS256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+        match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            PublicKey::EdDsa(key) => UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, sig)
+                .is_ok(),
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+        }
+    }
+}
+
+/// Verifies the attestation signature. Certificates are not checked against vendor roots,
+/// the format is recorded with the credential instead.
+fn verify_attestation(
+    format: &str,
+    statement: &[(Cbor, Cbor)],
+    auth_data: &[u8],
+    credential: (&[u8], &PublicKey),
+    client_data_hash: &[u8],
+) -> Result<(), Error> {
+    let sig = field(statement, "sig").and_then(Cbor::as_bytes);
+    let signed = [auth_data, client_data_hash].concat();
+    let valid = match (format, sig) {
+        ("none", _) => statement.is_empty(),
+        ("packed", Some(sig)) => {
+            let alg = field(statement, "alg").and_then(integer);
+            if field(statement, "x5c").is_some() {
+                alg == Some(ES256) && PublicKey::from_certificate(statement)?.verify(&signed, sig)
+            } else {
+                // Self attestation, signed with the new credential itself
+                alg == Some(credential.1.algorithm()) && credential.1.verify(&signed, sig)
+            }
+        }
+        ("fido-u2f", Some(sig)) => {
+            let PublicKey::Es256(point) = credential.1 else {
+                return Err(webauthn_error("U2F credentials must use ES256"));
+            };
+            let signed = [
+                &[0][..],
+                &auth_data[..32],
+                client_data_hash,
+                credential.0,
+                point,
+            ]
+            .concat();
+            PublicKey::from_certificate(statement)?.verify(&signed, sig)
+        }
+        ("packed" | "fido-u2f", None) => false,
+        _ => {
+            return Err(webauthn_error(
+                format!("unsupported attestation format {}", format).as_str(),
+            ));
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(webauthn_error("invalid attestation"))
+    }
+}
+
+/// Verifies a response to `navigator.credentials.create` against the registration challenge.
+fn verify_registration(
+    challenge: &str,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+    user_verification: bool,
+) -> Result<WebAuthnCredential, Error> {
+    let invalid = || webauthn_error("invalid attestation object");
+    let object: Cbor = ciborium::from_reader(attestation_object).map_err(|_| invalid())?;
+    let object = object.as_map().ok_or_else(invalid)?;
+    let format = field(object, "fmt")
+        .and_then(Cbor::as_text)
+        .ok_or_else(invalid)?;
+    let statement = field(object, "attStmt")
+        .and_then(Cbor::as_map)
+        .ok_or_else(invalid)?;
+    let auth_data = field(object, "authData")
+        .and_then(Cbor::as_bytes)
+        .ok_or_else(invalid)?;
+
+    let parsed = parse_authenticator_data(auth_data)?;
+    let rp_id = rp_ids()
+        .into_iter()
+        .find(|rp_id| Sha256::digest(rp_id.as_bytes())[..] == parsed.rp_id_hash[..])
+        .ok_or_else(|| webauthn_error("RP ID is not a configured FQDN"))?;
+    let client_data_hash =
+        check_client_data(client_data_json, "webauthn.create", challenge, &rp_id)?;
+    check_flags(parsed.flags, user_verification)?;
+    let (id, public_key) = parsed
+        .credential
+        .as_ref()
+        .ok_or_else(|| webauthn_error("no credential was created"))?;
+    let key = PublicKey::from_cose(public_key)?;
+    verify_attestation(format, statement, auth_data, (id, &key), &client_data_hash)?;
+    Ok(WebAuthnCredential {
+        id: encode(id),
+        public_key: encode(public_key),
+        sign_count: parsed.sign_count,
+        rp_id,
+        attestation: format.to_string(),
+        passkey: None,
+    })
+}
+
+fn check_flags(flags: u8, user_verification: bool) -> Result<(), Error> {
+    if flags & FLAG_USER_PRESENT == 0 {
+        Err(webauthn_error("user was not present"))
+    } else if user_verification && flags & FLAG_USER_VERIFIED == 0 {
+        Err(webauthn_error("user was not verified"))
+    } else {
+        Ok(())
+    }
+}
+
+impl User {
+    fn webauthn_credentials(&self) -> Vec<WebAuthnCredential> {
+        self.auth
+            .mfa
+            .methods
+            .iter()
+            .filter(|m| matches!(m.r#type, MFAMethodType::WebAuthn))
+            .filter_map(|m| WebAuthnData::from_method(m).credential)
+            .collect()
+    }
+
+    /// Creates a WebAuthn method whose credential will be a discoverable passkey. Passkeys
+    /// sign in without the password and are usable as a second factor as well.
+    pub fn create_passkey(&mut self, name: String) -> Option<MFAMethod> {
+        let id = self.create_mfa(MFAMethodType::WebAuthn, name)?.id;
+        let method = self.auth.mfa.methods.iter_mut().find(|m| m.id == id)?;
+        method.data = WebAuthnData::registration(true).to_json();
+        Some(method.clone())
+    }
+
+    /// Options for `navigator.credentials.create` to register a credential for a method
+    /// created by `create_mfa` or `create_passkey`. The RP ID is the requested FQDN if it
+    /// is configured.
+    pub fn webauthn_creation_options(&self, id: u8, rp_id: Option<&str>) -> Option<Value> {
+        let method = self.auth.mfa.methods.iter().find(|m| m.id == id)?;
+        let data = WebAuthnData::from_method(method);
+        let rp_ids = rp_ids();
+        let rp_id = rp_id
+            .filter(|rp_id| rp_ids.iter().any(|id| id == rp_id))
+            .or(rp_ids.first().map(String::as_str))?;
+        let exclude: Vec<Value> = self
+            .webauthn_credentials()
+            .iter()
+            .map(|c| json!({ "type": "public-key", "id": c.id }))
+            .collect();
+        let params: Vec<Value> = [ES256, EDDSA, RS256]
+            .iter()
+            .map(|alg| json!({ "type": "public-key", "alg": alg }))
+            .collect();
+        let mut options = json!({
+            "challenge": data.challenge,
+            "rp": { "id": rp_id, "name": "synxit" },
+            "user": {
+                // Discoverable credentials return it to tell whose passkey signed
+                "id": encode(self.userhandle.to_string().as_bytes()),
+                "name": self.userhandle.to_string(),
+                "displayName": self.userhandle.get_local_username(),
+            },
+            "pubKeyCredParams": params,
+            "timeout": REGISTRATION_TIMEOUT * 1000,
+            "attestation": "direct",
+            "excludeCredentials": exclude,
+            "authenticatorSelection": { "userVerification": "discouraged" },
+        });
+        if !data.prf_salt.is_empty() {
+            options["authenticatorSelection"] = json!({
+                "residentKey": "required",
+                "requireResidentKey": true,
+                "userVerification": "required",
+            });
+            options["extensions"] = json!({ "prf": { "eval": { "first": data.prf_salt } } });
+        }
+        Some(options)
+    }
+
+    /// Verifies the authenticator's response to a registration and enables the method with
+    /// the new credential.
+    pub fn finish_webauthn_registration(
+        &mut self,
+        id: u8,
+        client_data_json: &str,
+        attestation_object: &str,
+    ) -> Result<(), Error> {
+        let registered = self.webauthn_credentials();
+        let method = self
+            .auth
+            .mfa
+            .methods
+            .iter_mut()
+            .find(|m| m.id == id && matches!(m.r#type, MFAMethodType::WebAuthn))
+            .ok_or_else(|| Error::new("MFA method not found"))?;
+        let data = WebAuthnData::from_method(method);
+        if data.credential.is_some() {
+            return Err(webauthn_error("method already has a credential"));
+        }
+        if data.is_abandoned() {
+            return Err(webauthn_error("registration challenge expired"));
+        }
+        let passkey = !data.prf_salt.is_empty();
+        let mut credential = verify_registration(
+            &data.challenge,
+            &decode(client_data_json)?,
+            &decode(attestation_object)?,
+            passkey,
+        )?;
+        if registered.iter().any(|c| c.id == credential.id) {
+            return Err(webauthn_error("credential is already registered"));
+        }
+        if passkey {
+            credential.passkey = Some(Passkey {
+                prf_salt: data.prf_salt,
+                master_key: String::new(),
+            });
+        }
+        method.data = WebAuthnData {
+            credential: Some(credential),
+            ..Default::default()
+        }
+        .to_json();
+        method.enabled = true;
+        Ok(())
+    }
+
+    /// Stores the master key wrapped with the PRF output of a passkey, replacing the
+    /// previous copy.
+    pub fn set_passkey_master_key(&mut self, id: u8, master_key: &str) -> Result<(), Error> {
+        let method = self
+            .auth
+            .mfa
+            .methods
+            .iter_mut()
+            .find(|m| m.id == id && matches!(m.r#type, MFAMethodType::WebAuthn))
+            .ok_or_else(|| Error::new("MFA method not found"))?;
+        let mut data = WebAuthnData::from_method(method);
+        let passkey = data
+            .credential
+            .as_mut()
+            .and_then(|c| c.passkey.as_mut())
+            .ok_or_else(|| webauthn_error("method is not a passkey"))?;
+        passkey.master_key = master_key.to_string();
+        method.data = data.to_json();
+        Ok(())
+    }
+
+    /// Options for `navigator.credentials.get` to complete an auth session with a method.
+    pub fn webauthn_request_options(
+        &self,
+        auth_session: AuthSessionID,
+        method: &MFAMethod,
+    ) -> Option<Value> {
+        if !matches!(method.r#type, MFAMethodType::WebAuthn) {
+            return None;
+        }
+        let credential = WebAuthnData::from_method(method).credential?;
+        let auth_session = self.get_auth_session_by_id(auth_session).ok()?;
+        Some(json!({
+            "challenge": assertion_challenge(auth_session.challenge),
+            "rpId": credential.rp_id,
+            "allowCredentials": [{ "type": "public-key", "id": credential.id }],
+            "userVerification": "discouraged",
+        }))
+    }
+
+    /// Options for `navigator.credentials.get` to sign in with one of the user's passkeys
+    /// for the requested FQDN, asking for the PRF output that unwraps the master key.
+    pub fn passkey_request_options(
+        &self,
+        auth_session: AuthSessionID,
+        rp_id: Option<&str>,
+    ) -> Option<Value> {
+        let rp_id = rp_id.map(str::to_string).or(rp_ids().first().cloned())?;
+        let passkeys: Vec<(String, Passkey)> = self
+            .auth
+            .mfa
+            .methods
+            .iter()
+            .filter(|m| m.enabled && matches!(m.r#type, MFAMethodType::WebAuthn))
+            .filter_map(|m| WebAuthnData::from_method(m).credential)
+            .filter(|c| c.rp_id == rp_id)
+            .filter_map(|c| Some((c.id, c.passkey?)))
+            .collect();
+        if passkeys.is_empty() {
+            return None;
+        }
+        let auth_session = self.get_auth_session_by_id(auth_session).ok()?;
+        let allow: Vec<Value> = passkeys
+            .iter()
+            .map(|(id, _)| json!({ "type": "public-key", "id": id }))
+            .collect();
+        let salts: serde_json::Map<String, Value> = passkeys
+            .into_iter()
+            .map(|(id, passkey)| (id, json!({ "first": passkey.prf_salt })))
+            .collect();
+        Some(json!({
+            "challenge": assertion_challenge(auth_session.challenge),
+            "rpId": rp_id,
+            "allowCredentials": allow,
+            "userVerification": "required",
+            "extensions": { "prf": { "evalByCredential": salts } },
+        }))
+    }
+
+    /// Verifies an assertion signed for the auth session and stores the new signature counter.
+    pub fn check_webauthn_assertion(
+        &mut self,
+        auth_session: AuthSessionID,
+        id: u8,
+        assertion: &WebAuthnAssertion,
+    ) -> Result<(), Error> {
+        self.verify_assertion(auth_session, assertion, false, |method, _| method.id == id)
+            .map(|_| ())
+    }
+
+    /// Verifies a passkey assertion with user verification signed for the auth session and
+    /// returns the passkey.
+    pub fn check_passkey_assertion(
+        &mut self,
+        auth_session: AuthSessionID,
+        assertion: &WebAuthnAssertion,
+    ) -> Result<Passkey, Error> {
+        if let Some(user_handle) = &assertion.user_handle
+            && decode(user_handle)? != self.userhandle.to_string().as_bytes()
+        {
+            return Err(webauthn_error("passkey belongs to another user"));
+        }
+        self.verify_assertion(auth_session, assertion, true, |_, credential| {
+            credential.passkey.is_some()
+        })?
+        .passkey
+        .ok_or_else(|| webauthn_error("method is not a passkey"))
+    }
+
+    /// Verifies an assertion by the credential of an enabled method picked by `select` and
+    /// returns the credential with its new signature counter.
+    fn verify_assertion(
+        &mut self,
+        auth_session: AuthSessionID,
+        assertion: &WebAuthnAssertion,
+        user_verification: bool,
+        select: impl Fn(&MFAMethod, &WebAuthnCredential) -> bool,
+    ) -> Result<WebAuthnCredential, Error> {
+        let auth_session = self.get_auth_session_by_id(auth_session)?;
+        if auth_session.expires_at < current_time() {
+            return Err(webauthn_error("auth session expired"));
+        }
+        let challenge = assertion_challenge(auth_session.challenge);
+        let credential_id = assertion.credential_id.trim_end_matches('=');
+        let (method, mut data) = self
+            .auth
+            .mfa
+            .methods
+            .iter_mut()
+            .filter(|m| m.enabled && matches!(m.r#type, MFAMethodType::WebAuthn))
+            .map(|m| {
+                let data = WebAuthnData::from_method(m);
+                (m, data)
+            })
+            .find(|(m, data)| {
+                data.credential
+                    .as_ref()
+                    .is_some_and(|c| c.id == credential_id && select(m, c))
+            })
+            .ok_or_else(|| webauthn_error("unknown credential"))?;
+        let Some(credential) = data.credential.as_mut() else {
+            return Err(webauthn_error("unknown credential"));
+        };
+        if !rp_ids().contains(&credential.rp_id) {
+            return Err(webauthn_error("RP ID is no longer a configured FQDN"));
+        }
+
+        let authenticator_data = decode(&assertion.authenticator_data)?;
+        let parsed = parse_authenticator_data(&authenticator_data)?;
+        if parsed.rp_id_hash[..] != Sha256::digest(credential.rp_id.as_bytes())[..] {
+            return Err(webauthn_error("RP ID does not match"));
+        }
+        check_flags(parsed.flags, user_verification)?;
+        let client_data_hash = check_client_data(
+            &decode(&assertion.client_data_json)?,
+            "webauthn.get",
+            &challenge,
+            &credential.rp_id,
+        )?;
+        let key = PublicKey::from_cose(&decode(&credential.public_key)?)?;
+        if !key.verify(
+            &[&authenticator_data[..], &client_data_hash].concat(),
+            &decode(&assertion.signature)?,
+        ) {
+            return Err(webauthn_error("invalid signature"));
+        }
+        // Authenticators without a counter always send 0. Otherwise a counter that did not
+        // increase means the credential was cloned or the assertion replayed.
+        if (parsed.sign_count != 0 || credential.sign_count != 0)
+            && parsed.sign_count <= credential.sign_count
+        {
+            return Err(webauthn_error("signature counter did not increase"));
+        }
+        credential.sign_count = parsed.sign_count;
+        let credential = credential.clone();
+        method.data = data.to_json();
+        Ok(credential)
+    }
+}
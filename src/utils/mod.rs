@@ -1,5 +1,5 @@
-use rand::rngs::OsRng;
 use rand::RngCore;
+use rand::rngs::OsRng;
 
 pub fn as_str(value: &serde_json::Value) -> &str {
     value.as_str().unwrap_or_default()
@@ -31,6 +31,11 @@ pub fn char_hex_string_to_u128(hex: String) -> u128 {
     u128::from_str_radix(&hex, 16).unwrap_or_default()
 }
 
+/// Revision of an encrypted document, used to detect concurrent changes.
+pub fn revision(value: &str) -> String {
+    sha256::digest(value)
+}
+
 pub fn current_time() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
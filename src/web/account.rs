@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{Read, Write};
+
+use super::{
+    Request, Response,
+    blob::{STREAM_CHUNK_SIZE, raw_error, send_raw},
+};
+use crate::{
+    config::get_config,
+    logger::error::{
+        ERROR_BLOB_WRITE_FAILED, ERROR_QUOTA_EXCEEDED, ERROR_REGISTRATION_DISABLED,
+        ERROR_UPLOAD_INCOMPLETE, ERROR_USER_NOT_FOUND,
+    },
+    storage::file::{create_dir, dir_exists, remove_file},
+    user::{User, account::IMPORT_OVERHEAD},
+    utils::{random_u128, u128_to_32_char_hex_string},
+};
+use actix_web::{
+    HttpResponse,
+    body::SizedStream,
+    http::StatusCode,
+    web::{self, Bytes},
+};
+use futures_util::{StreamExt, stream};
+use serde_json::json;
+
+/// Returns a new path in the temp directory, creating the directory if needed.
+fn temp_path(kind: &str) -> Result<String, &'static str> {
+    let temp_dir = get_config().storage.temp_dir;
+    if !dir_exists(&temp_dir) && !create_dir(&temp_dir) {
+        return Err(ERROR_BLOB_WRITE_FAILED);
+    }
+    Ok(format!(
+        "{}/.{}.{}.tmp",
+        temp_dir,
+        kind,
+        u128_to_32_char_hex_string(random_u128())
+    ))
+}
+
+/// Streams an archive of the user's account as written by `User::export_account`.
+pub fn handle_export(req: Request) -> HttpResponse {
+    let path = match temp_path("export") {
+        Ok(path) => path,
+        Err(error) => return raw_error(error),
+    };
+    let mut username = String::new();
+    let result = req.with_auth_user(|user| {
+        username = user.userhandle.get_local_username();
+        match user.export_account(&path) {
+            Ok(()) => Response::success(json!({})),
+            Err(e) => Response::error(e.to_string().as_str()),
+        }
+    });
+    if result.0.is_err() {
+        return send_raw(result);
+    }
+    // The open file stays readable after its path is removed
+    let file = File::open(&path);
+    let _ = remove_file(&path);
+    let (file, size) = match file.and_then(|file| Ok((file.metadata()?.len(), file))) {
+        Ok((size, file)) => (file, size),
+        Err(_) => return raw_error(ERROR_BLOB_WRITE_FAILED),
+    };
+
+    let body = stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut chunk = vec![0; STREAM_CHUNK_SIZE as usize];
+        match file.read(&mut chunk) {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok::<_, std::io::Error>(Bytes::from(chunk)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    HttpResponse::Ok()
+        .append_header(("Access-Control-Allow-Origin", "*"))
+        .append_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}.synxit-account.tar.gz\"",
+                username
+            ),
+        ))
+        .content_type("application/gzip")
+        .body(SizedStream::new(size, body))
+}
+
+/// Creates a new user under the requested userhandle from an uploaded account archive.
+/// Like registration, this requires registration to be enabled.
+pub async fn handle_import(req: Request, mut payload: web::Payload) -> HttpResponse {
+    if !get_config().auth.registration_enabled {
+        return raw_error(ERROR_REGISTRATION_DISABLED);
+    }
+    let userhandle = match req.userhandle() {
+        Ok(userhandle) => userhandle,
+        Err(_) => return raw_error(ERROR_USER_NOT_FOUND),
+    };
+    let path = match temp_path("import") {
+        Ok(path) => path,
+        Err(error) => return raw_error(error),
+    };
+    let limit = User::new(userhandle.to_owned(), "", "")
+        .get_tier_quota()
+        .saturating_add(IMPORT_OVERHEAD);
+    let received = async {
+        let mut file = File::create(&path).map_err(|_| ERROR_BLOB_WRITE_FAILED)?;
+        let mut size = 0u64;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|_| ERROR_UPLOAD_INCOMPLETE)?;
+            size += chunk.len() as u64;
+            if size > limit {
+                return Err(ERROR_QUOTA_EXCEEDED);
+            }
+            file.write_all(&chunk)
+                .map_err(|_| ERROR_BLOB_WRITE_FAILED)?;
+        }
+        file.sync_all().map_err(|_| ERROR_BLOB_WRITE_FAILED)
+    }
+    .await;
+
+    let response = match received
+        .map_err(str::to_string)
+        .and_then(|_| User::import_account(userhandle, &path).map_err(|e| e.to_string()))
+    {
+        Ok(user) => Response::success(json!({ "username": user.userhandle }))
+            .send_with_status(StatusCode::CREATED),
+        Err(error) => raw_error(&error),
+    };
+    let _ = remove_file(&path);
+    response
+}
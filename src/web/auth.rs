@@ -1,9 +1,16 @@
+use log::warn;
 use serde_json::json;
 
 use crate::{
-    logger::error::{Error, ERROR_INVALID_ACTION, ERROR_INVALID_CREDENTIALS},
-    user::{AuthSessionID, MFAMethodType, SessionID, UserHandle},
-    utils::u128_to_32_char_hex_string,
+    logger::error::{
+        ERROR_INVALID_ACTION, ERROR_INVALID_CREDENTIALS, ERROR_INVALID_MFA_CODE,
+        ERROR_INVALID_RECOVERY_CODE, ERROR_INVALID_WEBAUTHN_ASSERTION, Error,
+    },
+    user::{
+        AuthSessionID, MFAMethodType, SessionID, UserHandle,
+        webauthn::{WebAuthnAssertion, WebAuthnData},
+    },
+    utils::{revision, u128_to_32_char_hex_string},
 };
 
 use super::{Request, Response};
@@ -34,6 +41,8 @@ pub fn handle_auth(req: Request) -> Response {
         "is_auth" => is_auth(req),
         "logout" => logout(req),
         "add_mfa" => add_mfa(req),
+        "register_webauthn" => register_webauthn(req),
+        "set_passkey_master_key" => set_passkey_master_key(req),
         "enable_mfa" => enable_mfa(req),
         "disable_mfa" => disable_mfa(req),
         "list_mfa" => list_mfa(req),
@@ -50,51 +59,101 @@ pub fn handle_auth(req: Request) -> Response {
 }
 
 pub fn auth(req: Request) -> Response {
-    match req.get_user() {
-        Ok(mut user) => {
-            if user.check_password_for_auth_session(req.auth_session(), &req.response()) {
+    if req.data.get("passkey").is_some() {
+        return auth_passkey(req);
+    }
+    if req.data.get("srp_a").is_some() {
+        return auth_srp(req);
+    }
+    req.with_login_attempt(|user| {
+        if user.check_password_for_auth_session(req.auth_session(), &req.response()) {
+            user.save();
+            req.get_auth_completed_response(user)
+        } else {
+            Response::error(ERROR_INVALID_CREDENTIALS)
+        }
+    })
+}
+
+/// Signs in with the SRP-6a client ephemeral A and proof M1. The response carries the server
+/// proof M2, which the client checks before trusting it.
+fn auth_srp(req: Request) -> Response {
+    req.with_login_attempt(|user| {
+        let server_proof = user.check_srp_for_auth_session(
+            req.auth_session(),
+            req.get_str("srp_a"),
+            req.get_str("srp_m1"),
+        );
+        user.save();
+        match server_proof {
+            Some(server_proof) => {
+                let mut response = req.get_auth_completed_response(user);
+                if let Ok(data) = &mut response.0 {
+                    data["srp_m2"] = json!(server_proof);
+                }
+                response
+            }
+            None => Response::error(ERROR_INVALID_CREDENTIALS),
+        }
+    })
+}
+
+/// Signs in with a passkey assertion instead of the password response. The response also
+/// carries the master key wrapped with the passkey's PRF output.
+fn auth_passkey(req: Request) -> Response {
+    req.with_login_attempt(|user| {
+        let result = serde_json::from_value::<WebAuthnAssertion>(req.data["passkey"].clone())
+            .map_err(|_| Error::new(ERROR_INVALID_WEBAUTHN_ASSERTION))
+            .and_then(|assertion| {
+                user.check_passkey_for_auth_session(req.auth_session(), &assertion)
+            });
+        match result {
+            Ok(master_key) => {
                 user.save();
-                req.get_auth_completed_response()
-            } else {
+                let mut response = req.get_auth_completed_response(user);
+                if let Ok(data) = &mut response.0 {
+                    data["passkey_master_key"] = json!(master_key);
+                }
+                response
+            }
+            Err(err) => {
+                warn!("Passkey login of {} failed: {}", user.userhandle, err);
                 Response::error(ERROR_INVALID_CREDENTIALS)
             }
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn prepare(req: Request) -> Response {
-    match req.get_user() {
-        Ok(mut user) => {
-            let auth_session_id = user.create_auth_session();
-            match user.get_auth_session_by_id(auth_session_id) {
-                Ok(auth_session) => {
-                    user.save();
-                    Response::success(json!({
-                        "auth_session": auth_session_id,
-                        "challenge": u128_to_32_char_hex_string(auth_session.challenge),
-                        "salt": user.auth.salt.to_string()
-                    }))
-                }
-                Err(err) => Response::error(err.to_string().as_str()),
+    req.with_user(|user| {
+        let auth_session_id = user.create_auth_session();
+        match user.get_auth_session_by_id(auth_session_id) {
+            Ok(auth_session) => {
+                user.save();
+                Response::success(json!({
+                    "auth_session": String::from(auth_session_id),
+                    "challenge": u128_to_32_char_hex_string(auth_session.challenge),
+                    "salt": user.auth.salt.to_string(),
+                    "srp_identity": user.srp_identity(),
+                    "scheme": if user.auth.verifier.is_empty() { "legacy" } else { "srp6a" },
+                    "srp_b": user.srp_server_public(auth_session_id),
+                    "passkey": user.passkey_request_options(auth_session_id, req.data["rp_id"].as_str())
+                }))
             }
+            Err(err) => Response::error(err.to_string().as_str()),
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn logout(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            user.delete_session_by_id(req.session());
-            if user.save() {
-                Response::success(json!({}))
-            } else {
-                Response::error("Failed to logout")
-            }
+    req.with_auth_user(|user| {
+        user.delete_session_by_id(req.session());
+        if user.save() {
+            Response::success(json!({}))
+        } else {
+            Response::error("Failed to logout")
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn is_auth(req: Request) -> Response {
@@ -107,41 +166,43 @@ pub fn is_auth(req: Request) -> Response {
 }
 
 pub fn foreign_keyring(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            user.foreign_keyring = req.data["foreign_keyring"]
-                .as_str()
-                .unwrap_or("")
-                .to_string();
-            if user.save() {
-                Response::success(json!({}))
-            } else {
-                Response::error("Failed to save foreign key.")
-            }
+    req.with_auth_user(|user| {
+        if let Err(conflict) = req.check_revision("foreign_keyring", &user.foreign_keyring) {
+            return conflict;
         }
-        Err(err) => err,
-    }
+        user.foreign_keyring = req.data["foreign_keyring"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        if user.save() {
+            Response::success(json!({ "revision": revision(&user.foreign_keyring) }))
+        } else {
+            Response::error("Failed to save foreign key.")
+        }
+    })
 }
 
 pub fn set_master_key(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            if let Some(master_key) = req.data["master_key"].as_str() {
-                user.auth.encrypted.master_key = master_key.to_string();
-                user.save();
-                Response::success(json!({}))
-            } else {
-                Response::error("No master key provided")
+    req.with_auth_user(|user| {
+        if let Some(master_key) = req.data["master_key"].as_str() {
+            if let Err(conflict) = req.check_revision("master_key", &user.auth.encrypted.master_key)
+            {
+                return conflict;
             }
+            user.auth.encrypted.master_key = master_key.to_string();
+            user.save();
+            Response::success(json!({ "revision": revision(master_key) }))
+        } else {
+            Response::error("No master key provided")
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn get_master_key(req: Request) -> Response {
     match req.get_auth_user() {
         Ok(user) => Response::success(json!({
-            "master_key": user.auth.encrypted.master_key
+            "master_key": user.auth.encrypted.master_key,
+            "revision": revision(&user.auth.encrypted.master_key),
         })),
         Err(err) => err,
     }
@@ -150,119 +211,199 @@ pub fn get_master_key(req: Request) -> Response {
 pub fn get_keyring(req: Request) -> Response {
     match req.get_auth_user() {
         Ok(user) => Response::success(json!({
-            "keyring": user.auth.encrypted.keyring
+            "keyring": user.auth.encrypted.keyring,
+            "revision": revision(&user.auth.encrypted.keyring),
         })),
         Err(err) => err,
     }
 }
 
 pub fn set_keyring(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            if let Some(keyring) = req.data["keyring"].as_str() {
-                user.auth.encrypted.keyring = keyring.to_string();
-                user.save();
-                Response::success(json!({}))
-            } else {
-                Response::error("No keyring provided")
+    req.with_auth_user(|user| {
+        if let Some(keyring) = req.data["keyring"].as_str() {
+            if let Err(conflict) = req.check_revision("keyring", &user.auth.encrypted.keyring) {
+                return conflict;
             }
+            user.auth.encrypted.keyring = keyring.to_string();
+            user.save();
+            Response::success(json!({ "revision": revision(keyring) }))
+        } else {
+            Response::error("No keyring provided")
         }
-        Err(err) => err,
-    }
+    })
 }
 
+/// Replaces the password of the signed in user, who proves the current one first. The new
+/// verifier is computed with the SRP identity from `prepare`.
 pub fn change_password(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            let old_password = req.data["old_password"].as_str().unwrap_or_default();
-            let new_password = req.data["password"].as_str().unwrap_or_default();
-            let salt = req.data["salt"].as_str().unwrap_or_default();
-            let master_key = req.data["master_key"].as_str().unwrap_or_default();
-            if old_password == user.auth.hash {
-                return Response::error(ERROR_INVALID_CREDENTIALS);
-            }
+    req.with_auth_user(|user| {
+        let new_password = req.data["password"].as_str().unwrap_or_default();
+        let verifier = req.data["verifier"].as_str().unwrap_or_default();
+        let salt = req.data["salt"].as_str().unwrap_or_default();
+        let master_key = req.data["master_key"].as_str().unwrap_or_default();
+        if new_password.is_empty() && verifier.is_empty() {
+            return Response::error("No password or verifier provided");
+        }
+        if let Err(err) = req.check_password_proof(user) {
+            return err;
+        }
 
-            user.auth.hash = new_password.to_string();
-            user.auth.salt = salt.to_string();
-            user.auth.encrypted.master_key = master_key.to_string();
+        user.auth.hash = new_password.to_string();
+        user.auth.verifier = verifier.to_string();
+        user.auth.salt = salt.to_string();
+        user.auth.encrypted.master_key = master_key.to_string();
+        user.upgrade_legacy_password();
 
-            user.save();
-            Response::success(json!({}))
-        }
-        Err(err) => err,
-    }
+        user.save();
+        Response::success(json!({}))
+    })
 }
 
 pub fn auth_mfa(req: Request) -> Response {
-    match req.get_user() {
-        Ok(mut user) => {
-            if user.auth.mfa.enabled {
-                if req.data.get("mfa_id").is_some() && req.data.get("mfa_code").is_some() {
-                    if user.check_mfa(
+    req.with_login_attempt(|user| {
+        if user.auth.mfa.enabled {
+            if req.data.get("mfa_id").is_some() && req.data.get("mfa_code").is_some() {
+                if user.check_mfa(
+                    req.data["mfa_id"].as_u64().unwrap_or(0) as u8,
+                    req.data["mfa_code"].as_str().unwrap_or_default(),
+                ) {
+                    user.auth_session_add_completed_mfa(
+                        req.auth_session(),
                         req.data["mfa_id"].as_u64().unwrap_or(0) as u8,
-                        req.data["mfa_code"].as_str().unwrap_or_default(),
-                    ) {
-                        user.auth_session_add_completed_mfa(
-                            req.auth_session(),
-                            req.data["mfa_id"].as_u64().unwrap_or(0) as u8,
-                        );
-                        if user.save() {
-                            req.get_auth_completed_response()
-                        } else {
-                            Response::error("Failed to add MFA ID to session")
-                        }
+                    );
+                    if user.save() {
+                        req.get_auth_completed_response(user)
                     } else {
-                        Response::error("Invalid MFA code")
+                        Response::error("Failed to add MFA ID to session")
                     }
-                } else if req.data.get("mfa_recovery_code").is_some() {
-                    let code = req.data["mfa_recovery_code"].as_str().unwrap_or_default();
-                    if code.len() != 8 {
-                        return Response::error("Invalid recovery code format");
-                    }
-                    if user.check_mfa_recovery_code(code) {
-                        user.auth_session_add_completed_mfa(req.auth_session(), 255);
+                } else {
+                    Response::error(ERROR_INVALID_MFA_CODE)
+                }
+            } else if req.data.get("mfa_id").is_some() && req.data.get("assertion").is_some() {
+                let mfa_id = req.data["mfa_id"].as_u64().unwrap_or(0) as u8;
+                let result =
+                    serde_json::from_value::<WebAuthnAssertion>(req.data["assertion"].clone())
+                        .map_err(|_| Error::new(ERROR_INVALID_WEBAUTHN_ASSERTION))
+                        .and_then(|assertion| {
+                            user.check_webauthn_assertion(req.auth_session(), mfa_id, &assertion)
+                        });
+                match result {
+                    Ok(()) => {
+                        user.auth_session_add_completed_mfa(req.auth_session(), mfa_id);
                         if user.save() {
-                            req.get_auth_completed_response()
+                            req.get_auth_completed_response(user)
                         } else {
                             Response::error("Failed to add MFA ID to session")
                         }
+                    }
+                    Err(err) => {
+                        warn!("WebAuthn login of {} failed: {}", user.userhandle, err);
+                        Response::error(ERROR_INVALID_WEBAUTHN_ASSERTION)
+                    }
+                }
+            } else if req.data.get("mfa_recovery_code").is_some() {
+                let code = req.data["mfa_recovery_code"].as_str().unwrap_or_default();
+                if code.len() != 8 {
+                    return Response::error("Invalid recovery code format");
+                }
+                if user.check_mfa_recovery_code(code) {
+                    user.auth_session_add_completed_mfa(req.auth_session(), 255);
+                    if user.save() {
+                        req.get_auth_completed_response(user)
                     } else {
-                        Response::error("Invalid MFA recovery code")
+                        Response::error("Failed to add MFA ID to session")
                     }
                 } else {
-                    return Response::error("Missing MFA ID or code");
+                    Response::error(ERROR_INVALID_RECOVERY_CODE)
                 }
             } else {
-                Response::error("MFA is not enabled")
+                Response::error("Missing MFA ID or code")
             }
+        } else {
+            Response::error("MFA is not enabled")
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn add_mfa(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            let mfa_type = req.data["type"].as_str().unwrap_or_default();
-            let mfa_name = req.data["name"].as_str().unwrap_or_default();
-            if mfa_type == "totp" {
-                if let Some(method) = user.create_mfa(MFAMethodType::TOTP, mfa_name.to_string()) {
-                    if user.save() {
-                        Response::success(serde_json::json!({
-                            "method": method
-                        }))
-                    } else {
-                        Response::error("Failed to save user with new MFA method")
-                    }
+    req.with_auth_user(|user| {
+        let mfa_type = req.data["type"].as_str().unwrap_or_default();
+        let mfa_name = req.data["name"].as_str().unwrap_or_default();
+        if mfa_type == "totp" {
+            if let Some(method) = user.create_mfa(MFAMethodType::TOTP, mfa_name.to_string()) {
+                if user.save() {
+                    Response::success(serde_json::json!({
+                        "method": method
+                    }))
                 } else {
-                    Response::error("Failed to create TOTP MFA method")
+                    Response::error("Failed to save user with new MFA method")
                 }
             } else {
-                Response::error("Invalid Method")
+                Response::error("Failed to create TOTP MFA method")
+            }
+        } else if mfa_type == "webauthn" || mfa_type == "u2f" || mfa_type == "passkey" {
+            let method = if mfa_type == "passkey" {
+                user.create_passkey(mfa_name.to_string())
+            } else {
+                user.create_mfa(MFAMethodType::WebAuthn, mfa_name.to_string())
+            };
+            let Some(method) = method else {
+                return Response::error("Failed to create WebAuthn MFA method");
+            };
+            let options = user.webauthn_creation_options(method.id, req.data["rp_id"].as_str());
+            if user.save() {
+                Response::success(serde_json::json!({
+                    "method": method,
+                    "options": options
+                }))
+            } else {
+                Response::error("Failed to save user with new MFA method")
             }
+        } else {
+            Response::error("Invalid Method")
         }
-        Err(err) => err,
-    }
+    })
+}
+
+/// Finishes registering a WebAuthn method with the response of `navigator.credentials.create`.
+/// Passkeys may send their wrapped master key along.
+pub fn register_webauthn(req: Request) -> Response {
+    req.with_auth_user(|user| {
+        let mfa_id = req.data["mfa_id"].as_u64().unwrap_or(0) as u8;
+        let result = user
+            .finish_webauthn_registration(
+                mfa_id,
+                req.get_str("client_data_json"),
+                req.get_str("attestation_object"),
+            )
+            .and_then(|_| match req.data["master_key"].as_str() {
+                Some(master_key) => user.set_passkey_master_key(mfa_id, master_key),
+                None => Ok(()),
+            });
+        if let Err(err) = result {
+            return Response::error(err.to_string().as_str());
+        }
+        if user.save() {
+            Response::success(serde_json::json!({}))
+        } else {
+            Response::error("Failed to save user with new MFA method")
+        }
+    })
+}
+
+/// Replaces the master key wrapped with a passkey's PRF output.
+pub fn set_passkey_master_key(req: Request) -> Response {
+    req.with_auth_user(|user| {
+        let mfa_id = req.data["mfa_id"].as_u64().unwrap_or(0) as u8;
+        if let Err(err) = user.set_passkey_master_key(mfa_id, req.get_str("master_key")) {
+            return Response::error(err.to_string().as_str());
+        }
+        if user.save() {
+            Response::success(serde_json::json!({}))
+        } else {
+            Response::error("Failed to save passkey master key")
+        }
+    })
 }
 
 pub fn list_mfa(req: Request) -> Response {
@@ -279,6 +420,9 @@ pub fn list_mfa(req: Request) -> Response {
                         "name": m.name,
                         "type": m.r#type,
                         "enabled": m.enabled,
+                        "passkey": WebAuthnData::from_method(m)
+                            .credential
+                            .is_some_and(|c| c.passkey.is_some()),
                     })
                 })
                 .collect();
@@ -291,64 +435,50 @@ pub fn list_mfa(req: Request) -> Response {
 }
 
 pub fn remove_mfa(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            let mfa_id = req.data["mfa_id"].as_u64().unwrap_or(0) as u8;
-            if let Some(pos) = user.auth.mfa.methods.iter().position(|m| m.id == mfa_id) {
-                user.auth.mfa.methods.remove(pos);
-                if user.save() {
-                    Response::success(serde_json::json!({}))
-                } else {
-                    Response::error("Failed to save user after removing MFA method")
-                }
+    req.with_auth_user(|user| {
+        let mfa_id = req.data["mfa_id"].as_u64().unwrap_or(0) as u8;
+        if let Some(pos) = user.auth.mfa.methods.iter().position(|m| m.id == mfa_id) {
+            user.auth.mfa.methods.remove(pos);
+            if user.save() {
+                Response::success(serde_json::json!({}))
             } else {
-                Response::error("MFA method not found")
+                Response::error("Failed to save user after removing MFA method")
             }
+        } else {
+            Response::error("MFA method not found")
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn enable_mfa(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            user.auth.mfa.enabled = true;
-            if user.save() {
-                Response::success(serde_json::json!({}))
-            } else {
-                Response::error("Failed to enable MFA")
-            }
+    req.with_auth_user(|user| {
+        user.auth.mfa.enabled = true;
+        if user.save() {
+            Response::success(serde_json::json!({}))
+        } else {
+            Response::error("Failed to enable MFA")
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn disable_mfa(req: Request) -> Response {
-    match req.get_auth_user() {
-        Ok(mut user) => {
-            user.auth.mfa.enabled = false;
-            if user.save() {
-                Response::success(serde_json::json!({}))
-            } else {
-                Response::error("Failed to disable MFA")
-            }
+    req.with_auth_user(|user| {
+        user.auth.mfa.enabled = false;
+        if user.save() {
+            Response::success(serde_json::json!({}))
+        } else {
+            Response::error("Failed to disable MFA")
         }
-        Err(err) => err,
-    }
+    })
 }
 
 pub fn new_recovery_codes(_req: Request) -> Response {
-    match _req.get_auth_user() {
-        Ok(mut user) => {
-            user.generate_recovery_codes();
-            if user.save() {
-                Response::success(
-                    serde_json::json!({ "recovery_codes": user.auth.mfa.recovery_codes }),
-                )
-            } else {
-                Response::error("Failed to generate new recovery codes")
-            }
+    _req.with_auth_user(|user| {
+        user.generate_recovery_codes();
+        if user.save() {
+            Response::success(serde_json::json!({ "recovery_codes": user.auth.mfa.recovery_codes }))
+        } else {
+            Response::error("Failed to generate new recovery codes")
         }
-        Err(err) => err,
-    }
+    })
 }
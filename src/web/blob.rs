@@ -1,9 +1,36 @@
+use std::io::Write;
+
 use super::{Request, Response};
 use crate::{
-    logger::error::ERROR_INVALID_ACTION,
-    user::blob::{Base64, BlobHash, BlobID},
+    config::get_config,
+    logger::error::{
+        ERROR_BLOB_HASH_NOT_MATCH, ERROR_BLOB_HASH_REQUIRED, ERROR_BLOB_NOT_FOUND,
+        ERROR_BLOB_WRITE_FAILED, ERROR_INVALID_ACTION, ERROR_QUOTA_EXCEEDED, ERROR_RATE_LIMITED,
+        ERROR_REGISTRATION_DISABLED, ERROR_UNAUTHORIZED, ERROR_UPLOAD_INCOMPLETE,
+        ERROR_USER_ALREADY_EXISTS, ERROR_USER_NOT_FOUND,
+    },
+    storage::{
+        backend,
+        file::{create_dir, dir_exists, remove_file},
+    },
+    user::{
+        blob::{Base64, BlobHash, BlobID, IfMatch, ShareID},
+        metadata::BlobMetadata,
+    },
+    utils::{random_u128, revision, u128_to_32_char_hex_string},
+};
+use actix_web::{
+    HttpRequest, HttpResponse,
+    body::SizedStream,
+    http::{StatusCode, header::ETAG},
+    web::{self, Bytes},
 };
+use futures_util::{StreamExt, stream};
 use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// Entries returned by `list` and `changes_since` when the request sets no limit.
+const DEFAULT_LIST_LIMIT: usize = 100;
 
 impl Request {
     pub fn content(&self) -> Base64 {
@@ -17,35 +44,56 @@ impl Request {
     pub fn blob_hash(&self) -> BlobHash {
         self.get_string("blob_hash").into()
     }
+
+    pub fn version_id(&self) -> BlobID {
+        self.get_string("version").into()
+    }
+
+    pub fn upload_id(&self) -> &str {
+        self.get_str("upload_id")
+    }
 }
 
 /// Handles blob-related actions such as create, read, update, delete, etc.
 pub fn handle_blob(req: Request) -> Response {
-    // Authenticate user
-    let mut user = match req.get_auth_user() {
-        Ok(user) => user,
-        Err(err) => return err,
-    };
-
-    // Dispatch action
-    match req.action() {
-        "create" => handle_create_blob(&user, &req),
-        "read" => handle_read_blob(&user, &req),
-        "update" => handle_update_blob(&user, &req),
-        "delete" => handle_delete_blob(&user, &req),
-        "hash" => handle_blob_hash(&user, &req),
-        "set_blob_map" => handle_set_blob_map(&mut user, &req),
-        "get_blob_map" => handle_get_blob_map(&user),
-        "get_quota" => handle_get_quota(&user),
-        _ => Response::error(ERROR_INVALID_ACTION),
-    }
+    // Authenticate user and hold its lock while the action runs
+    req.with_auth_user(|user| {
+        // Dispatch action
+        match req.action() {
+            "create" => handle_create_blob(user, &req),
+            "read" => handle_read_blob(user, &req),
+            "update" => handle_update_blob(user, &req),
+            "delete" => handle_delete_blob(user, &req),
+            "copy" => handle_copy_blob(user, &req),
+            "hash" => handle_blob_hash(user, &req),
+            "list" => handle_list_blobs(user, &req),
+            "changes_since" => handle_changes_since(user, &req),
+            "metadata" => handle_blob_metadata(user, &req),
+            "set_tags" => handle_set_blob_tags(user, &req),
+            "list_trash" => handle_list_trash(user),
+            "restore" => handle_restore_blob(user, &req),
+            "empty_trash" => handle_empty_trash(user),
+            "list_versions" => handle_list_versions(user, &req),
+            "read_version" => handle_read_version(user, &req),
+            "restore_version" => handle_restore_version(user, &req),
+            "begin_upload" => handle_begin_upload(user, &req),
+            "upload_chunk" => handle_upload_chunk(user, &req),
+            "get_upload" => handle_get_upload(user, &req),
+            "commit_upload" => handle_commit_upload(user, &req),
+            "abort_upload" => handle_abort_upload(user, &req),
+            "set_blob_map" => handle_set_blob_map(user, &req),
+            "get_blob_map" => handle_get_blob_map(user),
+            "get_quota" => handle_get_quota(user),
+            _ => Response::error(ERROR_INVALID_ACTION),
+        }
+    })
 }
 
 /// Handles the creation of a new blob.
 fn handle_create_blob(user: &crate::user::User, req: &super::Request) -> Response {
     match user.create_blob(req.content()) {
         Ok(blob) => Response::success(json!({
-            "id": blob.0,
+            "id": String::from(blob.0),
             "hash": blob.1
         })),
         Err(e) => Response::error(e.to_string().as_str()),
@@ -71,6 +119,20 @@ fn handle_update_blob(user: &crate::user::User, req: &super::Request) -> Respons
     }
 }
 
+/// Handles copying a blob, optionally into a share.
+fn handle_copy_blob(user: &crate::user::User, req: &super::Request) -> Response {
+    let share_id = req.data["share_id"]
+        .as_str()
+        .map(|id| ShareID::from(id.to_string()));
+    match user.copy_blob(req.blob_id(), share_id) {
+        Ok(blob) => Response::success(json!({
+            "id": String::from(blob.0),
+            "hash": blob.1
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
 /// Handles deleting an existing blob.
 fn handle_delete_blob(user: &crate::user::User, req: &super::Request) -> Response {
     let success = user.delete_blob(req.blob_id());
@@ -83,8 +145,220 @@ fn handle_delete_blob(user: &crate::user::User, req: &super::Request) -> Respons
 
 /// Retrieves the hash of a blob.
 fn handle_blob_hash(user: &crate::user::User, req: &super::Request) -> Response {
-    match user.read_blob(req.blob_id()) {
-        Ok(blob) => Response::success(json!({ "hash": blob.1 })),
+    match user.get_blob_metadata(req.blob_id()) {
+        Ok(metadata) => Response::success(json!({ "hash": metadata.hash })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+fn metadata_json(id: BlobID, metadata: BlobMetadata) -> serde_json::Value {
+    json!({
+        "id": String::from(id),
+        "size": metadata.size,
+        "hash": metadata.hash,
+        "created_at": metadata.created_at,
+        "updated_at": metadata.updated_at,
+        "tags": metadata.tags,
+    })
+}
+
+/// Lists blobs with their metadata, one page at a time.
+fn handle_list_blobs(user: &crate::user::User, req: &super::Request) -> Response {
+    let cursor = req.data["cursor"]
+        .as_str()
+        .map(|id| BlobID::from(id.to_string()));
+    let limit = match req.get_u64("limit") {
+        0 => DEFAULT_LIST_LIMIT,
+        limit => limit as usize,
+    };
+    match user.list_blobs(cursor, limit) {
+        Ok((blobs, next)) => Response::success(json!({
+            "blobs": blobs
+                .into_iter()
+                .map(|(id, metadata)| match metadata {
+                    Some(metadata) => metadata_json(id, metadata),
+                    None => json!({ "id": String::from(id) }),
+                })
+                .collect::<Vec<_>>(),
+            "next_cursor": next.map(String::from),
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Returns the changes after the given cursor from the change journal.
+fn handle_changes_since(user: &crate::user::User, req: &super::Request) -> Response {
+    let limit = match req.get_u64("limit") {
+        0 => DEFAULT_LIST_LIMIT,
+        limit => limit as usize,
+    };
+    let page = user.changes_since(req.get_u64("cursor"), limit);
+    Response::success(json!({
+        "changes": page
+            .changes
+            .into_iter()
+            .map(|change| json!({
+                "seq": change.seq,
+                "kind": change.kind,
+                "blob_id": change.blob.map(String::from),
+                "share_id": change.share.map(String::from),
+                "hash": change.hash,
+                "at": change.at,
+            }))
+            .collect::<Vec<_>>(),
+        "cursor": page.cursor,
+        "more": page.more,
+        "reset": page.reset,
+    }))
+}
+
+/// Retrieves the metadata of a blob.
+fn handle_blob_metadata(user: &crate::user::User, req: &super::Request) -> Response {
+    let id = req.blob_id();
+    match user.get_blob_metadata(id) {
+        Ok(metadata) => Response::success(metadata_json(id, metadata)),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Sets or clears the client tags of a blob.
+fn handle_set_blob_tags(user: &crate::user::User, req: &super::Request) -> Response {
+    let tags = req.data["tags"].as_str().map(str::to_string);
+    match user.set_blob_tags(req.blob_id(), tags) {
+        Ok(()) => Response::success(json!({})),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Lists the blobs in the trash.
+fn handle_list_trash(user: &crate::user::User) -> Response {
+    Response::success(json!({
+        "trash": user
+            .list_trash()
+            .into_iter()
+            .map(|entry| json!({
+                "id": String::from(entry.id),
+                "size": entry.size,
+                "deleted_at": entry.deleted_at,
+                "shares": entry.shares.into_iter().map(String::from).collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+/// Restores a blob from the trash, optionally back into its shares.
+fn handle_restore_blob(user: &crate::user::User, req: &super::Request) -> Response {
+    let restore_shares = req.data["restore_shares"].as_bool().unwrap_or(false);
+    match user.restore_from_trash(req.blob_id(), restore_shares) {
+        Ok(()) => Response::success(json!({})),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Permanently deletes everything in the trash.
+fn handle_empty_trash(user: &crate::user::User) -> Response {
+    match user.empty_trash() {
+        Ok(purged) => Response::success(json!({ "purged": purged })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Lists the previous versions of a blob.
+fn handle_list_versions(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.list_versions(req.blob_id()) {
+        Ok(versions) => Response::success(json!({
+            "versions": versions
+                .into_iter()
+                .map(|version| json!({
+                    "version": String::from(version.id),
+                    "hash": version.hash,
+                    "size": version.size,
+                    "created_at": version.created_at,
+                }))
+                .collect::<Vec<_>>(),
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Reads a previous version of a blob.
+fn handle_read_version(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.read_version(req.blob_id(), req.version_id()) {
+        Ok(version) => Response::success(json!({
+            "content": version.0,
+            "hash": version.1,
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Restores a previous version of a blob.
+fn handle_restore_version(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.restore_version(req.blob_id(), req.version_id()) {
+        Ok(hash) => Response::success(json!({ "hash": hash })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Starts a chunked upload. Passing `blob_id` and `blob_hash` replaces an existing blob on commit.
+fn handle_begin_upload(user: &crate::user::User, req: &super::Request) -> Response {
+    let blob = if req.get_str("blob_id").is_empty() {
+        None
+    } else {
+        Some((req.blob_id(), req.blob_hash()))
+    };
+    match user.begin_upload(
+        req.get_u64("size"),
+        req.get_string("hash").into(),
+        req.get_u64("chunk_size"),
+        blob,
+    ) {
+        Ok(session) => Response::success(json!({
+            "upload_id": session.id,
+            "chunks": session.chunks(),
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Stores one numbered chunk of an upload.
+fn handle_upload_chunk(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.upload_chunk(req.upload_id(), req.get_u64("index"), req.content()) {
+        Ok(received) => Response::success(json!({ "received": received })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Reports which chunks of an upload have been received, so an interrupted upload can resume.
+fn handle_get_upload(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.get_upload(req.upload_id()) {
+        Ok((session, received)) => Response::success(json!({
+            "upload_id": session.id,
+            "size": session.size,
+            "hash": session.hash,
+            "chunk_size": session.chunk_size,
+            "chunks": session.chunks(),
+            "received": received,
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Completes an upload and stores it as a blob.
+fn handle_commit_upload(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.commit_upload(req.upload_id()) {
+        Ok(blob) => Response::success(json!({
+            "id": String::from(blob.0),
+            "hash": blob.1
+        })),
+        Err(e) => Response::error(e.to_string().as_str()),
+    }
+}
+
+/// Cancels an upload.
+fn handle_abort_upload(user: &crate::user::User, req: &super::Request) -> Response {
+    match user.abort_upload(req.upload_id()) {
+        Ok(()) => Response::success(json!({})),
         Err(e) => Response::error(e.to_string().as_str()),
     }
 }
@@ -94,9 +368,13 @@ fn handle_set_blob_map(user: &mut crate::user::User, req: &super::Request) -> Re
     let blob_map = req.data["blob_map"].as_str();
     match blob_map {
         Some(map) => {
+            if let Err(conflict) = req.check_revision("blob_map", &user.auth.encrypted.blob_map) {
+                return conflict;
+            }
             user.auth.encrypted.blob_map = map.to_string();
             if user.save() {
-                Response::success(json!({}))
+                user.record_blob_map_change();
+                Response::success(json!({ "revision": revision(map) }))
             } else {
                 Response::error("Failed to save blob map")
             }
@@ -109,6 +387,7 @@ fn handle_set_blob_map(user: &mut crate::user::User, req: &super::Request) -> Re
 fn handle_get_blob_map(user: &crate::user::User) -> Response {
     Response::success(json!({
         "blob_map": user.auth.encrypted.blob_map,
+        "revision": revision(&user.auth.encrypted.blob_map),
     }))
 }
 
@@ -121,3 +400,248 @@ fn handle_get_quota(user: &crate::user::User) -> Response {
         "total": total,
     }))
 }
+
+/// Size of the pieces raw blob reads are streamed in.
+pub(super) const STREAM_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Maps an error of the raw endpoints to its HTTP status code.
+fn error_status(error: &str) -> StatusCode {
+    match error {
+        ERROR_UNAUTHORIZED => StatusCode::UNAUTHORIZED,
+        ERROR_BLOB_NOT_FOUND | ERROR_USER_NOT_FOUND => StatusCode::NOT_FOUND,
+        ERROR_BLOB_HASH_NOT_MATCH => StatusCode::PRECONDITION_FAILED,
+        ERROR_BLOB_HASH_REQUIRED => StatusCode::PRECONDITION_REQUIRED,
+        ERROR_QUOTA_EXCEEDED => StatusCode::PAYLOAD_TOO_LARGE,
+        ERROR_USER_ALREADY_EXISTS => StatusCode::CONFLICT,
+        ERROR_REGISTRATION_DISABLED => StatusCode::FORBIDDEN,
+        ERROR_BLOB_WRITE_FAILED => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+pub(super) fn raw_error(error: &str) -> HttpResponse {
+    Response::error(error).send_with_status(error_status(error))
+}
+
+pub(super) fn send_raw(response: Response) -> HttpResponse {
+    match &response.0 {
+        // Rate limited requests keep their Retry-After header
+        Err(error) if error != ERROR_RATE_LIMITED => raw_error(error),
+        _ => response.send(),
+    }
+}
+
+/// Reads a header as a string, ignoring values that are not valid ASCII.
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Checks an `If-None-Match` list of entity tags against the ETag of a blob, using the weak
+/// comparison, so `W/"…"` matches the same tag.
+pub fn etag_matches(value: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    value.trim() == "*" || value.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// Parses an `If-Match` list of entity tags. Replacing content needs the strong comparison, so
+/// a weak `W/"…"` tag is rejected rather than left to never match.
+pub fn parse_if_match(value: Option<&str>) -> Result<IfMatch, &'static str> {
+    let Some(value) = value else {
+        return Ok(IfMatch::Missing);
+    };
+    if value.trim() == "*" {
+        return Ok(IfMatch::Any);
+    }
+    value
+        .split(',')
+        .map(|tag| match tag.trim() {
+            tag if tag.starts_with("W/") => Err(ERROR_BLOB_HASH_NOT_MATCH),
+            tag => Ok(BlobHash::from(tag.trim_matches('"').to_string())),
+        })
+        .collect::<Result<_, _>>()
+        .map(IfMatch::Hashes)
+}
+
+/// Parses a single `bytes=` range into an inclusive start and end.
+/// Returns `Ok(None)` for a missing or unsupported header and `Err` if it cannot be satisfied.
+pub fn parse_range(range: Option<&str>, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.and_then(|range| range.strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (start, end) => {
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (
+                start.parse().map_err(|_| ())?,
+                end.min(size.saturating_sub(1)),
+            )
+        }
+    };
+    if start >= size || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Streams the raw content of a blob, honouring `Range` and `If-None-Match`.
+pub fn handle_raw_read(req: Request, http: &HttpRequest, id: BlobID) -> HttpResponse {
+    let user = match req.get_auth_user() {
+        Ok(user) => user,
+        Err(response) => return send_raw(response),
+    };
+    let (size, hash) = match user.get_blob_info(id) {
+        Ok(info) => info,
+        Err(e) => return raw_error(e.to_string().as_str()),
+    };
+    let etag = format!("\"{}\"", String::from(hash));
+    if header(http, "If-None-Match").is_some_and(|value| etag_matches(value, &etag)) {
+        return HttpResponse::NotModified()
+            .append_header(("Access-Control-Allow-Origin", "*"))
+            .append_header(("ETag", etag))
+            .finish();
+    }
+
+    let (mut builder, start, end) = match parse_range(header(http, "Range"), size) {
+        Err(()) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .append_header(("Access-Control-Allow-Origin", "*"))
+                .append_header(("Content-Range", format!("bytes */{}", size)))
+                .finish();
+        }
+        Ok(None) => (HttpResponse::Ok(), 0, size),
+        Ok(Some((start, end))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.append_header(("Content-Range", format!("bytes {}-{}/{}", start, end, size)));
+            (response, start, end + 1)
+        }
+    };
+
+    let userhandle = user.userhandle.to_owned();
+    let body = stream::unfold(start, move |offset| {
+        let userhandle = userhandle.to_owned();
+        async move {
+            if offset >= end {
+                return None;
+            }
+            let length = STREAM_CHUNK_SIZE.min(end - offset);
+            match backend().read_blob_range(&userhandle, id, offset, length) {
+                Ok(chunk) if !chunk.is_empty() => {
+                    let next = offset + chunk.len() as u64;
+                    Some((Ok::<_, std::io::Error>(Bytes::from(chunk)), next))
+                }
+                Ok(_) => None,
+                Err(e) => Some((Err(std::io::Error::other(e.to_string())), end)),
+            }
+        }
+    });
+    builder
+        .append_header(("Access-Control-Allow-Origin", "*"))
+        .append_header((
+            "Access-Control-Expose-Headers",
+            "ETag, Content-Range, Accept-Ranges",
+        ))
+        .append_header(("Accept-Ranges", "bytes"))
+        .append_header(("ETag", etag))
+        .content_type("application/octet-stream")
+        .body(SizedStream::new(end - start, body))
+}
+
+/// Stores a raw request body as the content of a blob. Replacing an existing blob requires
+/// its current hash in `If-Match`.
+pub async fn handle_raw_write(
+    req: Request,
+    http: &HttpRequest,
+    id: BlobID,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let user = match req.get_auth_user() {
+        Ok(user) => user,
+        Err(response) => return send_raw(response),
+    };
+    let if_match = match parse_if_match(header(http, "If-Match")) {
+        Ok(if_match) => if_match,
+        Err(error) => return raw_error(error),
+    };
+
+    let temp_dir = get_config().storage.temp_dir;
+    if !dir_exists(&temp_dir) && !create_dir(&temp_dir) {
+        return raw_error(ERROR_BLOB_WRITE_FAILED);
+    }
+    let path = format!(
+        "{}/.put.{}.tmp",
+        temp_dir,
+        u128_to_32_char_hex_string(random_u128())
+    );
+    // The body can not take more than the quota left, which is checked exactly once it is in
+    let limit = user.get_available_quota();
+    let received = async {
+        let mut file = std::fs::File::create(&path).map_err(|_| ERROR_BLOB_WRITE_FAILED)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|_| ERROR_UPLOAD_INCOMPLETE)?;
+            size += chunk.len() as u64;
+            if size > limit {
+                return Err(ERROR_QUOTA_EXCEEDED);
+            }
+            hasher.update(&chunk);
+            file.write_all(&chunk)
+                .map_err(|_| ERROR_BLOB_WRITE_FAILED)?;
+        }
+        file.sync_all().map_err(|_| ERROR_BLOB_WRITE_FAILED)?;
+        Ok((size, BlobHash::from(hex::encode(hasher.finalize()))))
+    }
+    .await;
+
+    let response = match received {
+        Ok((size, hash)) => {
+            let mut created = false;
+            // The session is checked again after the upload, but the request was already
+            // charged against the user's rate limit
+            let result = req.with_user(|user| {
+                if !user.check_auth_by_id(req.session()) {
+                    return Response::error(ERROR_UNAUTHORIZED);
+                }
+                match user.put_blob_file(id, &path, size, &hash, if_match) {
+                    Ok(is_new) => {
+                        created = is_new;
+                        Response::success(json!({ "id": String::from(id), "hash": hash }))
+                    }
+                    Err(e) => Response::error(e.to_string().as_str()),
+                }
+            });
+            match &result.0 {
+                Ok(_) => {
+                    let status = if created {
+                        StatusCode::CREATED
+                    } else {
+                        StatusCode::OK
+                    };
+                    let mut response = result.send_with_status(status);
+                    if let Ok(etag) = format!("\"{}\"", String::from(hash)).parse() {
+                        response.headers_mut().insert(ETAG, etag);
+                    }
+                    response
+                }
+                Err(error) => raw_error(error),
+            }
+        }
+        Err(error) => raw_error(error),
+    };
+    let _ = remove_file(&path);
+    response
+}
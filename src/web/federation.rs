@@ -7,8 +7,8 @@ use crate::{
     {
         config::get_config,
         user::{
-            blob::{BlobID, Share, ShareID, ShareSecret},
             User, UserHandle,
+            blob::{BlobID, Share, ShareID, ShareSecret},
         },
     },
 };
@@ -98,9 +98,9 @@ fn build_proxy_request_body(
     req: &Request,
 ) -> serde_json::Value {
     let mut data = json!({
-        "id": req.share_id(),
+        "id": String::from(req.share_id()),
         "share_user": share_user,
-        "secret": req.share_secret(),
+        "secret": String::from(req.share_secret()),
     });
 
     if matches!(action, "update" | "create") {
@@ -122,7 +122,7 @@ fn build_proxy_request_body(
 fn handle_blobs_action(req: &Request) -> Response {
     match validate_user_and_share(req) {
         Ok(share) => Response::success(json!({
-            "blobs": share.1.blobs,
+            "blobs": share.1.blobs.into_iter().map(String::from).collect::<Vec<_>>(),
             "write_access": share.1.write,
         })),
         Err(response) => response,
@@ -183,7 +183,9 @@ fn handle_create_action(req: &Request) -> Response {
             }
             match share.0.create_blob(req.content()) {
                 Ok((blob_id, hash)) => match share.0.add_blob_to_share(share.1.id, blob_id) {
-                    Ok(_) => Response::success(json!({ "id": blob_id, "hash": hash })),
+                    Ok(_) => {
+                        Response::success(json!({ "id": String::from(blob_id), "hash": hash }))
+                    }
                     Err(_) => Response::error("message"),
                 },
                 Err(e) => Response::error(e.to_string().as_str()),
@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+};
+
+use log::warn;
+use serde_json::json;
+
+use super::{Request, Response};
+use crate::{
+    config::get_config,
+    logger::error::{
+        ERROR_INVALID_CREDENTIALS, ERROR_INVALID_MFA_CODE, ERROR_INVALID_RECOVERY_CODE,
+        ERROR_INVALID_WEBAUTHN_ASSERTION, ERROR_TOO_MANY_ATTEMPTS,
+    },
+    user::{User, lockout::Throttle},
+    utils::current_time,
+};
+
+/// Failed logins per client IP. They are kept in memory only, so a restart forgives them.
+static IP_THROTTLES: LazyLock<Mutex<HashMap<IpAddr, Throttle>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Errors telling the client that its password, passkey or MFA code was wrong. Only these
+/// count as failed logins.
+const WRONG_CREDENTIALS: [&str; 4] = [
+    ERROR_INVALID_CREDENTIALS,
+    ERROR_INVALID_MFA_CODE,
+    ERROR_INVALID_RECOVERY_CODE,
+    ERROR_INVALID_WEBAUTHN_ASSERTION,
+];
+
+fn too_many_attempts(until: u64, locked: bool) -> Response {
+    Response::error_with(
+        ERROR_TOO_MANY_ATTEMPTS,
+        json!({
+            "retry_after": until.saturating_sub(current_time()),
+            "locked": locked,
+        }),
+    )
+}
+
+fn ip_throttles() -> std::sync::MutexGuard<'static, HashMap<IpAddr, Throttle>> {
+    IP_THROTTLES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn ip_blocked_until(ip: IpAddr) -> Option<(u64, bool)> {
+    let throttles = ip_throttles();
+    let throttle = throttles.get(&ip)?;
+    throttle
+        .blocked_until(&get_config().auth)
+        .map(|until| (until, throttle.is_locked()))
+}
+
+fn record_ip_failure(ip: IpAddr) {
+    let config = get_config().auth;
+    let mut throttles = ip_throttles();
+    // Forgotten failures would be reset on the next one anyway
+    throttles.retain(|_, throttle| {
+        throttle.is_locked()
+            || current_time().saturating_sub(throttle.last_failure) <= config.lockout_duration
+    });
+    if throttles
+        .entry(ip)
+        .or_default()
+        .record_failure(&config, config.max_ip_attempts)
+    {
+        warn!(
+            "Locked out IP {} for {} seconds after {} failed logins",
+            ip, config.lockout_duration, config.max_ip_attempts
+        );
+    }
+}
+
+impl Request {
+    /// Runs a login attempt on an auth session of the user unless the client IP or the user
+    /// have to wait before trying again. Wrong credentials count as a failure against the IP,
+    /// the user and the auth session.
+    pub fn with_login_attempt(&self, handler: impl FnOnce(&mut User) -> Response) -> Response {
+        if let Some((until, locked)) = self.ip.and_then(ip_blocked_until) {
+            return too_many_attempts(until, locked);
+        }
+        let mut failed = false;
+        let response = self.with_user(|user| {
+            if let Some(until) = user.login_blocked_until() {
+                return too_many_attempts(until, user.auth.lockout.throttle.is_locked());
+            }
+            if let Err(err) = user.get_auth_session_by_id(self.auth_session()) {
+                return Response::error(err.to_string().as_str());
+            }
+            let response = handler(user);
+            failed = response
+                .0
+                .as_ref()
+                .is_err_and(|err| WRONG_CREDENTIALS.contains(&err.as_str()));
+            if failed {
+                user.record_failed_login(self.auth_session());
+                user.save();
+            }
+            response
+        });
+        if failed && let Some(ip) = self.ip {
+            record_ip_failure(ip);
+        }
+        response
+    }
+
+    /// Checks that the client knows the current password of an already signed in user, with
+    /// a fresh SRP proof or a legacy response on an auth session from `prepare`. The auth
+    /// session is used up, and failures count like failed logins.
+    pub fn check_password_proof(&self, user: &mut User) -> Result<(), Response> {
+        if let Some((until, locked)) = self.ip.and_then(ip_blocked_until) {
+            return Err(too_many_attempts(until, locked));
+        }
+        if let Some(until) = user.login_blocked_until() {
+            return Err(too_many_attempts(
+                until,
+                user.auth.lockout.throttle.is_locked(),
+            ));
+        }
+        let proven = if self.data.get("srp_a").is_some() {
+            user.check_srp_for_auth_session(
+                self.auth_session(),
+                self.get_str("srp_a"),
+                self.get_str("srp_m1"),
+            )
+            .is_some()
+        } else {
+            user.check_password_for_auth_session(self.auth_session(), &self.response())
+        };
+        if proven {
+            user.delete_auth_session_by_id(self.auth_session());
+            return Ok(());
+        }
+        user.record_failed_login(self.auth_session());
+        user.save();
+        if let Some(ip) = self.ip {
+            record_ip_failure(ip);
+        }
+        Err(Response::error(ERROR_INVALID_CREDENTIALS))
+    }
+}
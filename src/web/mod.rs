@@ -1,25 +1,41 @@
-mod auth;
-mod blob;
+mod account;
+pub mod auth;
+pub mod blob;
 mod federation;
-mod registration;
+mod lockout;
+pub mod rate_limit;
+pub mod registration;
 
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::net::IpAddr;
 
 use crate::{
-    logger::error::{ERROR_UNAUTHORIZED, ERROR_USER_NOT_FOUND},
-    utils::{as_str, current_time},
+    logger::error::{
+        ERROR_RATE_LIMITED, ERROR_REVISION_MISMATCH, ERROR_TOO_MANY_ATTEMPTS, ERROR_UNAUTHORIZED,
+        ERROR_USER_NOT_FOUND,
+    },
+    utils::{as_str, current_time, revision},
     {
         config::CONFIG,
         user::{MFAMethodPublic, User},
     },
 };
-use actix_web::{get, post, routes, web::PayloadConfig, App, HttpResponse, HttpServer, Responder};
+use account::{handle_export, handle_import};
+use actix_web::{
+    App, HttpRequest, HttpResponse, HttpServer, Responder, get,
+    http::{Method, StatusCode},
+    middleware::from_fn,
+    options, post, put, routes,
+    web::{self, PayloadConfig},
+};
 use auth::handle_auth;
-use blob::handle_blob;
+use blob::{handle_blob, handle_raw_read, handle_raw_write};
 use federation::handle_federation;
+use rate_limit::{limit_action, limit_user, rate_limit};
 use registration::handle_registration;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::{Value, json};
 
 #[get("/")]
 async fn redirect() -> impl Responder {
@@ -27,23 +43,73 @@ async fn redirect() -> impl Responder {
 }
 
 #[post("/synxit/auth")]
-async fn auth_request(body: String) -> impl Responder {
-    handle_auth(Request::parse(body)).send()
+async fn auth_request(req: HttpRequest, body: String) -> impl Responder {
+    let req = Request::parse(body).with_ip(&req);
+    match limit_action("auth", &req) {
+        Ok(()) => handle_auth(req),
+        Err(response) => response,
+    }
+    .send()
 }
 
 #[post("/synxit/registration")]
-async fn registration_request(body: String) -> impl Responder {
-    handle_registration(Request::parse(body)).send()
+async fn registration_request(req: HttpRequest, body: String) -> impl Responder {
+    let req = Request::parse(body).with_ip(&req);
+    match limit_action("registration", &req) {
+        Ok(()) => handle_registration(req),
+        Err(response) => response,
+    }
+    .send()
 }
 
 #[post("/synxit/blob")]
-async fn blob_request(body: String) -> impl Responder {
-    handle_blob(Request::parse(body)).send()
+async fn blob_request(req: HttpRequest, body: String) -> impl Responder {
+    let req = Request::parse(body).with_ip(&req);
+    match limit_action("blob", &req) {
+        Ok(()) => handle_blob(req),
+        Err(response) => response,
+    }
+    .send()
+}
+
+#[get("/synxit/blob/{id}")]
+async fn raw_blob_read(req: HttpRequest, id: web::Path<String>) -> impl Responder {
+    handle_raw_read(Request::from_http(&req), &req, id.into_inner().into())
+}
+
+#[put("/synxit/blob/{id}")]
+async fn raw_blob_write(
+    req: HttpRequest,
+    id: web::Path<String>,
+    payload: web::Payload,
+) -> impl Responder {
+    handle_raw_write(
+        Request::from_http(&req),
+        &req,
+        id.into_inner().into(),
+        payload,
+    )
+    .await
+}
+
+#[get("/synxit/account/export")]
+async fn account_export(req: HttpRequest) -> impl Responder {
+    handle_export(Request::from_http(&req))
+}
+
+#[post("/synxit/account/import")]
+async fn account_import(req: HttpRequest, payload: web::Payload) -> impl Responder {
+    handle_import(Request::from_http(&req), payload).await
 }
 
 #[post("/synxit/federation")]
-async fn federation_request(body: String) -> impl Responder {
-    handle_federation(Request::parse(body)).await.send()
+async fn federation_request(req: HttpRequest, body: String) -> impl Responder {
+    let req = Request::parse(body).with_ip(&req);
+    match limit_action("federation", &req) {
+        Ok(()) => handle_federation(req).await,
+        Err(response) => response,
+    }
+    .send()
 }
 
 #[get("/synxit/status")]
@@ -69,15 +135,53 @@ async fn options_request() -> impl Responder {
         .finish()
 }
 
+#[options("/synxit/blob/{id}")]
+async fn raw_blob_options() -> impl Responder {
+    HttpResponse::Ok()
+        .append_header(("Access-Control-Allow-Origin", "*"))
+        .append_header(("Access-Control-Allow-Methods", "GET, PUT, OPTIONS"))
+        .append_header((
+            "Access-Control-Allow-Headers",
+            "Content-Type, Range, If-Match, If-None-Match, X-Synxit-Userhandle, X-Synxit-Session",
+        ))
+        .append_header((
+            "Access-Control-Expose-Headers",
+            "ETag, Content-Range, Accept-Ranges",
+        ))
+        .finish()
+}
+
+#[routes]
+#[options("/synxit/account/export")]
+#[options("/synxit/account/import")]
+async fn account_options() -> impl Responder {
+    HttpResponse::Ok()
+        .append_header(("Access-Control-Allow-Origin", "*"))
+        .append_header(("Access-Control-Allow-Methods", "GET, POST, OPTIONS"))
+        .append_header((
+            "Access-Control-Allow-Headers",
+            "Content-Type, X-Synxit-Userhandle, X-Synxit-Session",
+        ))
+        .append_header(("Access-Control-Expose-Headers", "Content-Disposition"))
+        .finish()
+}
+
 pub async fn start_server() {
     let config = CONFIG.get().unwrap();
     match HttpServer::new(|| {
         App::new()
             .app_data(PayloadConfig::new(1024 * 1024 * 1024 * 4))
+            .wrap(from_fn(rate_limit))
             .service(redirect)
             .service(auth_request)
             .service(registration_request)
             .service(blob_request)
+            .service(raw_blob_read)
+            .service(raw_blob_write)
+            .service(raw_blob_options)
+            .service(account_export)
+            .service(account_import)
+            .service(account_options)
             .service(options_request)
             .service(federation_request)
             .service(status)
@@ -97,29 +201,35 @@ pub async fn start_server() {
 }
 
 #[derive(Serialize, Deserialize)]
-struct Request {
+pub struct Request {
     action: String,
     data: Value,
+    /// Address of the client, if the route asked for it
+    #[serde(skip)]
+    ip: Option<IpAddr>,
 }
 
+/// Result of a request, with additional fields sent alongside an error.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Response(Result<Value, String>);
+pub struct Response(Result<Value, String>, Value);
 
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.0 {
             Ok(data) => write!(f, "{}", json!({ "success": true, "data": data })),
-            Err(err) => write!(
-                f,
-                "{}",
-                json!({ "success": false, "data": { "error": err} })
-            ),
+            Err(err) => {
+                let mut data = json!({ "error": err });
+                if let (Some(data), Some(details)) = (data.as_object_mut(), self.1.as_object()) {
+                    data.extend(details.to_owned());
+                }
+                write!(f, "{}", json!({ "success": false, "data": data }))
+            }
         }
     }
 }
 
 impl Response {
-    pub fn send(&self) -> impl Responder {
+    pub fn send(&self) -> HttpResponse {
         match &self.0 {
             Ok(_) => HttpResponse::Ok()
                 .append_header(("Access-Control-Allow-Origin", "*"))
@@ -129,6 +239,13 @@ impl Response {
                 .append_header(("Access-Control-Allow-Origin", "*"))
                 .append_header(("Content-Type", "application/json"))
                 .body(self.to_string()),
+            Err(err) if err == ERROR_TOO_MANY_ATTEMPTS || err == ERROR_RATE_LIMITED => {
+                HttpResponse::TooManyRequests()
+                    .append_header(("Access-Control-Allow-Origin", "*"))
+                    .append_header(("Content-Type", "application/json"))
+                    .append_header(("Retry-After", self.1["retry_after"].to_string()))
+                    .body(self.to_string())
+            }
             Err(_) => HttpResponse::BadRequest()
                 .append_header(("Access-Control-Allow-Origin", "*"))
                 .append_header(("Content-Type", "application/json"))
@@ -136,6 +253,14 @@ impl Response {
         }
     }
 
+    /// Sends the response with the given status code instead of the one derived from its result.
+    pub fn send_with_status(&self, code: StatusCode) -> HttpResponse {
+        HttpResponse::build(code)
+            .append_header(("Access-Control-Allow-Origin", "*"))
+            .append_header(("Content-Type", "application/json"))
+            .body(self.to_string())
+    }
+
     pub fn redirect(location: &str) -> impl Responder {
         HttpResponse::Found()
             .append_header(("Location", location))
@@ -143,11 +268,16 @@ impl Response {
     }
 
     pub fn error(message: &str) -> Self {
-        Response(Err(message.to_string()))
+        Response(Err(message.to_string()), json!({}))
+    }
+
+    /// An error carrying details such as the current state the request conflicted with.
+    pub fn error_with(message: &str, details: serde_json::Value) -> Self {
+        Response(Err(message.to_string()), details)
     }
 
     pub fn success(data: serde_json::Value) -> Self {
-        Response(Ok(data))
+        Response(Ok(data), json!({}))
     }
 }
 
@@ -156,84 +286,133 @@ impl Request {
         serde_json::from_str(req.as_str()).unwrap_or(Request {
             action: "".to_string(),
             data: json!({}),
+            ip: None,
         })
     }
 
-    pub fn get_user(&self) -> Result<User, Response> {
+    /// Sets the client address the request came from.
+    pub fn with_ip(mut self, req: &HttpRequest) -> Self {
+        self.ip = req.peer_addr().map(|addr| addr.ip());
+        self
+    }
+
+    /// Builds a request for the raw endpoints, taking the userhandle and session from the
+    /// `X-Synxit-Userhandle` and `X-Synxit-Session` headers. GET requests may pass them in the
+    /// query string instead, for media elements and download links that cannot set headers,
+    /// at the cost of the session showing up in proxy logs and `Referer` headers.
+    pub fn from_http(req: &HttpRequest) -> Self {
+        let query = if req.method() == Method::GET {
+            web::Query::<HashMap<String, String>>::from_query(req.query_string())
+                .map(web::Query::into_inner)
+                .unwrap_or_default()
+        } else {
+            HashMap::new()
+        };
+        let field = |header: &str, name: &str| {
+            req.headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .or(query.get(name).map(String::as_str))
+                .unwrap_or_default()
+                .to_string()
+        };
+        Request {
+            action: "".to_string(),
+            data: json!({
+                "userhandle": field("X-Synxit-Userhandle", "userhandle"),
+                "session": field("X-Synxit-Session", "session"),
+            }),
+            ip: req.peer_addr().map(|addr| addr.ip()),
+        }
+    }
+
+    /// Loads the user while holding its lock and passes it to the given handler.
+    pub fn with_user(&self, handler: impl FnOnce(&mut User) -> Response) -> Response {
         match self.userhandle() {
-            Err(_) => Err(Response::error(ERROR_USER_NOT_FOUND)),
-            Ok(userhandle) => match User::load(userhandle) {
-                Ok(user) => Ok(user),
-                Err(err) => Err(Response::error(err.to_string().as_str())),
-            },
+            Err(_) => Response::error(ERROR_USER_NOT_FOUND),
+            Ok(userhandle) => User::with_locked(userhandle, handler)
+                .unwrap_or_else(|err| Response::error(err.to_string().as_str())),
+        }
+    }
+
+    /// Checks the session of the request and charges the user's rate limit for it.
+    fn authorize(&self, user: &User) -> Result<(), Response> {
+        if !user.check_auth_by_id(self.session()) {
+            return Err(Response::error(ERROR_UNAUTHORIZED));
         }
+        limit_user(user)
+    }
+
+    /// Like `with_user`, but only calls the handler if the request carries a valid session.
+    pub fn with_auth_user(&self, handler: impl FnOnce(&mut User) -> Response) -> Response {
+        self.with_user(|user| match self.authorize(user) {
+            Ok(()) => handler(user),
+            Err(err) => err,
+        })
     }
 
     pub fn get_auth_user(&self) -> Result<User, Response> {
         match self.userhandle() {
             Err(_) => Err(Response::error(ERROR_USER_NOT_FOUND)),
             Ok(userhandle) => match User::load(userhandle) {
-                Ok(user) => {
-                    if user.check_auth_by_id(self.session()) {
-                        Ok(user)
-                    } else {
-                        Err(Response::error(ERROR_UNAUTHORIZED))
-                    }
-                }
+                Ok(user) => self.authorize(&user).map(|()| user),
                 Err(err) => Err(Response::error(err.to_string().as_str())),
             },
         }
     }
 
-    pub fn get_auth_completed_response(&self) -> Response {
-        match self.get_user() {
-            Ok(mut user) => match user.convert_auth_session_to_session(self.auth_session()) {
-                Ok(session_id) => {
-                    user.save();
+    pub fn get_auth_completed_response(&self, user: &mut User) -> Response {
+        match user.convert_auth_session_to_session(self.auth_session()) {
+            Ok(session_id) => {
+                let lockout = user.take_lockout();
+                user.save();
+                Response::success(json!({
+                    "username": user.userhandle,
+                    "status": "success",
+                    "session": String::from(session_id),
+                    "master_key": user.auth.encrypted.master_key,
+                    "keyring": user.auth.encrypted.keyring,
+                    "blob_map": user.auth.encrypted.blob_map,
+                    "failed_logins": {
+                        "attempts": lockout.failures_since_login,
+                        "lockouts": lockout.lockouts_since_login,
+                        "last_failure": lockout.throttle.last_failure,
+                    }
+                }))
+            }
+            Err(err) => match err {
+                "require_mfa" => {
+                    let mut enabled_methods: Vec<MFAMethodPublic> = vec![];
+
+                    if let Ok(auth_session) = user.get_auth_session_by_id(self.auth_session()) {
+                        for method in &user.auth.mfa.methods {
+                            if method.enabled && !auth_session.completed_mfa.contains(&method.id) {
+                                enabled_methods.push(MFAMethodPublic {
+                                    id: method.id,
+                                    name: method.name.clone(),
+                                    r#type: method.r#type.clone(),
+                                    webauthn: user
+                                        .webauthn_request_options(self.auth_session(), method),
+                                });
+                            }
+                        }
+                    }
+
                     Response::success(json!({
                         "username": user.userhandle,
-                        "status": "success",
-                        "session": session_id,
-                        "master_key": user.auth.encrypted.master_key,
-                        "keyring": user.auth.encrypted.keyring,
-                        "blob_map": user.auth.encrypted.blob_map
+                        "status": "require_mfa",
+                        "methods": enabled_methods
                     }))
                 }
-                Err(err) => match err {
-                    "require_mfa" => {
-                        let mut enabled_methods: Vec<MFAMethodPublic> = vec![];
-
-                        if let Ok(auth_session) = user.get_auth_session_by_id(self.auth_session()) {
-                            for method in &user.auth.mfa.methods {
-                                if method.enabled
-                                    && !auth_session.completed_mfa.contains(&method.id)
-                                {
-                                    enabled_methods.push(MFAMethodPublic {
-                                        id: method.id,
-                                        name: method.name.clone(),
-                                        r#type: method.r#type.clone(),
-                                    });
-                                }
-                            }
-                        }
-
-                        Response::success(json!({
-                            "username": user.userhandle,
-                            "status": "require_mfa",
-                            "methods": enabled_methods
-                        }))
-                    }
-                    "require_password" => {
-                        user.delete_auth_session_by_id(self.auth_session());
-                        user.save();
-                        Response::success(json!({
-                            "status": "require_password"
-                        }))
-                    }
-                    _ => Response::error("Unknown error"),
-                },
+                "require_password" => {
+                    user.delete_auth_session_by_id(self.auth_session());
+                    user.save();
+                    Response::success(json!({
+                        "status": "require_password"
+                    }))
+                }
+                _ => Response::error("Unknown error"),
             },
-            Err(err) => err,
         }
     }
 
@@ -248,4 +427,21 @@ impl Request {
     pub fn get_string(&self, field: &str) -> String {
         self.get_str(field).to_string()
     }
+
+    /// Fails with the current value and its revision if the request expects a `revision` of
+    /// the document that differs from the one of `current`. Requests without a `revision`
+    /// overwrite the document unconditionally.
+    pub fn check_revision(&self, field: &str, current: &str) -> Result<(), Response> {
+        match self.data["revision"].as_str() {
+            Some(expected) if expected != revision(current) => Err(Response::error_with(
+                ERROR_REVISION_MISMATCH,
+                json!({ field: current, "revision": revision(current) }),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_u64(&self, field: &str) -> u64 {
+        self.data[field].as_u64().unwrap_or_default()
+    }
 }
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use actix_web::{
+    Error,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+};
+use log::debug;
+use serde_json::json;
+
+use super::{Request, Response};
+use crate::{
+    config::{Config, Limit, get_config},
+    logger::error::ERROR_RATE_LIMITED,
+    user::User,
+};
+
+/// Number of buckets above which full ones are forgotten.
+const PRUNE_AT: usize = 10_000;
+
+/// Buckets by client, kept in memory only.
+static BUCKETS: LazyLock<Mutex<HashMap<String, TokenBucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Requests a client may still make, refilled continuously up to the limit.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Limit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.requests as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests as f64 / limit.per.max(1) as f64)
+            .min(limit.requests as f64);
+        self.updated = now;
+    }
+
+    /// Takes a token for a request, or returns the seconds until the next one is available.
+    pub fn take(&mut self, limit: Limit, now: Instant) -> Result<(), u64> {
+        if limit.requests == 0 {
+            return Ok(());
+        }
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let seconds_per_token = limit.per.max(1) as f64 / limit.requests as f64;
+            Err(((1.0 - self.tokens) * seconds_per_token).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// The longest period of any limit, after which every idle bucket is full.
+fn longest_period(config: &Config) -> u64 {
+    let rate_limit = &config.rate_limit;
+    [rate_limit.ip, rate_limit.user, rate_limit.federation]
+        .iter()
+        .chain(rate_limit.routes.values())
+        .chain(
+            config
+                .tiers
+                .iter()
+                .filter_map(|tier| tier.rate_limit.as_ref()),
+        )
+        .map(|limit| limit.per)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Takes a token from every bucket of the request, returning the longest wait if any of them
+/// ran out.
+fn take_tokens(config: &Config, limits: &[(String, Limit)]) -> Result<(), u64> {
+    let now = Instant::now();
+    let mut buckets = BUCKETS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if buckets.len() >= PRUNE_AT {
+        let idle = longest_period(config);
+        buckets.retain(|_, bucket| now.duration_since(bucket.updated).as_secs() < idle);
+    }
+    let mut retry_after = None;
+    for (key, limit) in limits {
+        if let Err(seconds) = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(*limit, now))
+            .take(*limit, now)
+        {
+            debug!("Rate limited {} for {} seconds", key, seconds);
+            retry_after = retry_after.max(Some(seconds));
+        }
+    }
+    retry_after.map_or(Ok(()), Err)
+}
+
+/// Name of the route a path is matched by, such as `blob` for `/synxit/blob/{id}` or
+/// `account_import` for `/synxit/account/import`.
+fn route_name(req: &ServiceRequest) -> String {
+    req.match_pattern()
+        .unwrap_or_default()
+        .trim_start_matches("/synxit")
+        .split('/')
+        .filter(|segment| !segment.is_empty() && !segment.starts_with('{'))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+fn rate_limited(retry_after: u64) -> Response {
+    Response::error_with(ERROR_RATE_LIMITED, json!({ "retry_after": retry_after }))
+}
+
+/// Takes a token from the bucket of a signed in user, limited by the user's tier or the
+/// default limit of every user. Called once the session was checked, so nobody can use up
+/// the bucket of another user.
+pub fn limit_user(user: &User) -> Result<(), Response> {
+    let config = get_config();
+    if !config.rate_limit.enabled {
+        return Ok(());
+    }
+    let limit = config
+        .get_tier(user.tier.as_str())
+        .and_then(|tier| tier.rate_limit)
+        .unwrap_or(config.rate_limit.user);
+    take_tokens(&config, &[(format!("user:{}", user.userhandle), limit)]).map_err(rate_limited)
+}
+
+/// Takes a token from the bucket of the client for the action of a JSON request, such as
+/// `auth.prepare`. Called once the route read the body, after the middleware took the
+/// buckets of the client and the route.
+pub fn limit_action(route: &str, req: &Request) -> Result<(), Response> {
+    let config = get_config();
+    if !config.rate_limit.enabled {
+        return Ok(());
+    }
+    let action = format!("{}.{}", route, req.action());
+    let Some(limit) = config.rate_limit.routes.get(&action) else {
+        return Ok(());
+    };
+    let client = req.ip.map_or_else(String::new, |ip| ip.to_string());
+    take_tokens(&config, &[(format!("route:{}:{}", action, client), *limit)]).map_err(rate_limited)
+}
+
+/// Middleware rejecting requests with 429 once a bucket of the client IP or the route ran
+/// out of tokens. Federation requests are limited per client IP, which is the remote server
+/// for requests from other servers. Actions are limited by `limit_action` and users by
+/// `limit_user` once the body was read and the session checked.
+pub async fn rate_limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let config = get_config();
+    if !config.rate_limit.enabled {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+    let ip = req.peer_addr().map(|addr| addr.ip());
+    let client = ip.map_or_else(String::new, |ip| ip.to_string());
+    let mut limits = vec![(format!("ip:{}", client), config.rate_limit.ip)];
+
+    // Preflights carry nothing to limit but the client
+    if req.method() != Method::OPTIONS {
+        let route = route_name(&req);
+        if let Some(limit) = config.rate_limit.routes.get(&route) {
+            limits.push((format!("route:{}:{}", route, client), *limit));
+        }
+        if route == "federation" {
+            limits.push((
+                format!("federation:{}", client),
+                config.rate_limit.federation,
+            ));
+        }
+    }
+
+    match take_tokens(&config, &limits) {
+        Ok(()) => next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body),
+        Err(retry_after) => {
+            let response = rate_limited(retry_after).send();
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
@@ -17,13 +17,19 @@ pub fn handle_registration(req: Request) -> Response {
         Err(_) => return Response::error("Invalid username"),
     };
     let password = req.data["password"].as_str().unwrap_or_default();
+    let verifier = req.data["verifier"].as_str().unwrap_or_default();
     let salt = req.data["salt"].as_str().unwrap_or_default();
     if User::user_exists(userhandle.to_owned()) {
         Response::error("Username already exists")
+    } else if password.is_empty() && verifier.is_empty() {
+        Response::error("No password or verifier provided")
     } else {
-        let user = User::new(userhandle, password, salt);
+        // Clients of the legacy scheme send the password hash, which is never stored
+        let mut user = User::new(userhandle, password, salt);
+        user.auth.verifier = verifier.to_string();
+        user.upgrade_legacy_password();
         if user.save() {
-            info!("New user registered: {}", user.userhandle.to_string());
+            info!("New user registered: {}", user.userhandle);
             Response::success(json!({
                 "username": user.userhandle,
             }))